@@ -1,4 +1,5 @@
 use rudderanalytics::client::RudderAnalytics;
+use rudderanalytics::context::Context;
 use rudderanalytics::message::{
     Alias, Batch, BatchMessage, Group, Identify, Message, Page, Screen, Track,
 };
@@ -82,9 +83,10 @@ fn main() {
                 ..Default::default()
             }),
         ],
-        context: Some(json!({
-            "foo": "bar",
-        })),
+        context: Some(Context {
+            locale: Some("en-US".to_owned()),
+            ..Default::default()
+        }),
         ..Default::default()
     });
 
@@ -0,0 +1,185 @@
+//! An asynchronous client, for use from within an async runtime such as
+//! tokio.
+
+use crate::client::{
+    self, Attempt, BatchOutcome, BatchValidation, Delivery, Preflight, Prepared, RudderAnalytics,
+};
+use crate::dead_letter::{self, DeadLetter, DeadLetterSink};
+use crate::errors::Error;
+use crate::message::Message;
+use crate::middleware::Middleware;
+use crate::retry::RetryPolicy;
+#[cfg(feature = "tracking-plan")]
+use crate::tracking_plan::TrackingPlan;
+use crate::transport::Response;
+use log::{debug, warn};
+use std::sync::Arc;
+
+/// The async counterpart of [`RudderAnalytics`](crate::client::RudderAnalytics).
+///
+/// Messages go through the same validation, conversion, retries and
+/// compression as with the blocking client, but are sent with a non-blocking
+/// `reqwest::Client`.
+///
+/// ```no_run
+/// use rudderanalytics::async_client::AsyncRudderAnalytics;
+/// use rudderanalytics::message::{Message, Track};
+///
+/// # async fn run() -> Result<(), rudderanalytics::errors::Error> {
+/// let rudder_analytics = AsyncRudderAnalytics::load(
+///     "WRITE-KEY".to_string(),
+///     "DATA-PLANE-URL".to_string(),
+/// );
+///
+/// rudder_analytics
+///     .send(&Message::Track(Track {
+///         user_id: Some("user-1".to_owned()),
+///         event: "Example".to_owned(),
+///         ..Default::default()
+///     }))
+///     .await?;
+/// # Ok(())
+/// # }
+/// ```
+pub struct AsyncRudderAnalytics {
+    pub write_key: String,
+    pub data_plane_url: String,
+    pub client: reqwest::Client,
+    pub retry_policy: RetryPolicy,
+    pub gzip: bool,
+    pub dead_letter: Option<Arc<dyn DeadLetterSink>>,
+    pub batch_validation: BatchValidation,
+    pub middlewares: Vec<Arc<dyn Middleware>>,
+    #[cfg(feature = "tracking-plan")]
+    pub tracking_plan: Option<Arc<TrackingPlan>>,
+}
+
+impl AsyncRudderAnalytics {
+    /// Initialize the client with a write key and data plane url.
+    ///
+    /// Use [`RudderAnalytics::builder`](crate::client::RudderAnalytics::builder)
+    /// and `build_async` to configure the client further.
+    pub fn load(write_key: String, data_plane_url: String) -> AsyncRudderAnalytics {
+        RudderAnalytics::builder(write_key, data_plane_url)
+            .build_async()
+            .expect("failed to build the HTTP client")
+    }
+
+    /// Validate the message, convert it to the RudderStack format and send it
+    /// to the data plane, retrying transient failures as configured by
+    /// `retry_policy`.
+    pub async fn send(&self, msg: &Message) -> Result<Delivery, Error> {
+        // The ids are assigned before the message is converted, so that a
+        // dead letter carries the ids of its payload
+        let mut msg = msg.clone();
+        msg.assign_message_ids();
+        let Prepared {
+            request,
+            message,
+            batch,
+        } = client::prepare(&msg, &self.preflight())?;
+        let (path, rudder_message) = match request {
+            Some(request) => request,
+            None => return Ok(Delivery { attempts: 0, batch }),
+        };
+
+        debug!("rudder_message: {:#?}", rudder_message);
+        let result = match client::encode(&rudder_message, self.gzip) {
+            Ok(body) => self.post(path, body).await,
+            Err(err) => Err(err),
+        };
+        if let (Err(err), Some(sink)) = (&result, &self.dead_letter) {
+            dead_letter::store(sink.as_ref(), &message, &rudder_message, err);
+        }
+        let (attempts, response_body) = result?;
+        Ok(Delivery {
+            attempts,
+            batch: batch.map(|batch| BatchOutcome {
+                response_body,
+                ..batch
+            }),
+        })
+    }
+
+    /// Send dead-lettered messages again.
+    ///
+    /// Returns the messages which failed again, with the new failure. They
+    /// are not passed to the dead-letter sink, so that the caller decides
+    /// what happens to them. The middlewares already ran on dead letters,
+    /// and are not run again.
+    pub async fn redrive(&self, letters: impl IntoIterator<Item = DeadLetter>) -> Vec<DeadLetter> {
+        let mut failed = Vec::new();
+        for letter in letters {
+            let preflight = Preflight {
+                middlewares: &[],
+                ..self.preflight()
+            };
+            let result = match client::prepare(&letter.message, &preflight) {
+                Ok(Prepared {
+                    request: Some((path, rudder_message)),
+                    ..
+                }) => match client::encode(&rudder_message, self.gzip) {
+                    Ok(body) => self.post(path, body).await.map(|_| ()),
+                    Err(err) => Err(err),
+                },
+                Ok(_) => Ok(()),
+                Err(err) => Err(err),
+            };
+            if let Err(err) = result {
+                failed.push(DeadLetter::new(&letter.message, letter.payload, &err));
+            }
+        }
+        failed
+    }
+
+    fn preflight(&self) -> Preflight<'_> {
+        Preflight {
+            batch_validation: self.batch_validation,
+            middlewares: &self.middlewares,
+            #[cfg(feature = "tracking-plan")]
+            tracking_plan: self.tracking_plan.clone(),
+        }
+    }
+
+    async fn post(&self, path: &str, body: Vec<u8>) -> Result<(u32, String), Error> {
+        let headers = client::request_headers(&self.write_key, self.gzip)?;
+        let mut attempts = 0;
+        loop {
+            attempts += 1;
+            let request = self
+                .client
+                .post(format!("{}{}", self.data_plane_url, path))
+                .headers(headers.clone())
+                .body(body.clone());
+            let res = match request.send().await {
+                Ok(res) => {
+                    let status = res.status().as_u16();
+                    let headers = res.headers().clone();
+                    res.text().await.map(|body| Response {
+                        status,
+                        headers,
+                        body,
+                    })
+                }
+                Err(err) => Err(err),
+            };
+
+            match client::check_response(res.map_err(Error::from)) {
+                Attempt::Delivered(body) => return Ok((attempts, body)),
+                Attempt::Failed(err) => return Err(err),
+                Attempt::Retryable(err, retry_after) => {
+                    let delay = match client::next_delay(&self.retry_policy, attempts, retry_after)
+                    {
+                        Some(delay) => delay,
+                        None => return Err(client::retries_exhausted(attempts, err)),
+                    };
+                    warn!(
+                        "attempt {} failed: {}, retrying in {:?}",
+                        attempts, err, delay
+                    );
+                    tokio::time::sleep(delay).await;
+                }
+            }
+        }
+    }
+}
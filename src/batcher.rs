@@ -1,60 +1,70 @@
 //! Utilities for batching up messages.
 
-use crate::errors::Error as AnalyticsError;
+use crate::errors::Error;
 use crate::message::{Batch, BatchMessage, Message};
-use failure::Error;
+use crate::utils;
+use crate::context::Context;
 use serde_json::Value;
 use chrono::prelude::*;
 
-const MAX_MESSAGE_SIZE: usize = 1024 * 32;
-const MAX_BATCH_SIZE: usize = 1024 * 512;
+/// The maximum size of a single serialized message accepted by the data plane.
+pub const MAX_MESSAGE_SIZE: usize = 1024 * 32;
+/// The maximum size of a serialized batch accepted by the data plane.
+pub const MAX_BATCH_SIZE: usize = 1024 * 512;
 
-// / A batcher can accept messages into an internal buffer, and report when
-// / messages must be flushed.
-// /
-// / The recommended usage pattern looks something like this:
-// /
-// / ```
-// / use rudderanalytics::batcher::Batcher;
-// / use rudderanalytics::client::RudderAnalytics;
-// / use rudderanalytics::message::{BatchMessage, Track};
-// / use serde_json::json;
-// /
-// / let mut batcher = Batcher::new(None);
-// / let rudderAnalytics = RudderAnalytics::load("WRITE-KEY","DATA-PLANE-URL");
-// /
-// / for i in 0..100 {
-// /     let msg = BatchMessage::Track(Track {
-// /         user_id: Some(format!("user-{}", i)),
-// /         event: "Example".to_owned(),
-// /         properties: Some(json!({ "foo": "bar" })),
-// /         ..Default::default()
-// /     });
-// /
-// /     // Batcher returns back ownership of a message if the internal buffer
-// /     // would overflow.
-// /     //
-// /     // When this occurs, we flush the batcher, create a new batcher, and add
-// /     // the message into the new batcher.
-// /     if let Some(msg) = batcher.push(msg).unwrap() {
-// /         rudderAnalytics.send(&batcher.into_message()).unwrap();
-// /         batcher = Batcher::new(None);
-// /         batcher.push(msg).unwrap();
-// /     }
-// / }
+/// A batcher can accept messages into an internal buffer, and report when
+/// messages must be flushed.
+///
+/// The recommended usage pattern looks something like this:
+///
+/// ```no_run
+/// use rudderanalytics::batcher::Batcher;
+/// use rudderanalytics::client::RudderAnalytics;
+/// use rudderanalytics::message::{BatchMessage, Track};
+/// use serde_json::json;
+///
+/// let mut batcher = Batcher::new(None);
+/// let rudder_analytics = RudderAnalytics::load(
+///     "WRITE-KEY".to_string(),
+///     "DATA-PLANE-URL".to_string(),
+/// );
+///
+/// for i in 0..100 {
+///     let msg = BatchMessage::Track(Track {
+///         user_id: Some(format!("user-{}", i)),
+///         event: "Example".to_owned(),
+///         properties: Some(json!({ "foo": "bar" })),
+///         ..Default::default()
+///     });
+///
+///     // Batcher returns back ownership of a message if the internal buffer
+///     // would overflow.
+///     //
+///     // When this occurs, we flush the batcher, create a new batcher, and add
+///     // the message into the new batcher.
+///     if let Some(msg) = batcher.push(msg).unwrap() {
+///         rudder_analytics.send(&batcher.into_message()).unwrap();
+///         batcher = Batcher::new(None);
+///         batcher.push(msg).unwrap();
+///     }
+/// }
 /// ```
 ///
-// / Batcher will attempt to fit messages into maximally-sized batches, thus
-// / reducing the number of round trips required with RudderStack's tracking API.
-// / However, if you produce messages infrequently, this may significantly delay
-// / the sending of messages to RudderStack.
-// /
-// / If this delay is a concern, it is recommended that you periodically flush
-// / the batcher on your own by calling `into_message`.
+/// Batcher will attempt to fit messages into maximally-sized batches, thus
+/// reducing the number of round trips required with RudderStack's tracking API.
+/// However, if you produce messages infrequently, this may significantly delay
+/// the sending of messages to RudderStack.
+///
+/// If this delay is a concern, it is recommended that you periodically flush
+/// the batcher on your own by calling `into_message`.
+///
+/// Sizes are measured on the payload that is actually sent to the data plane,
+/// i.e. after `sentAt`, `channel` and the library context have been added.
 pub struct Batcher {
     buf: Vec<BatchMessage>,
     byte_count: usize,
-    context: Option<Value>,
+    context: Option<Context>,
+    rudder_context: Value,
 }
 
 impl Batcher {
@@ -62,16 +72,27 @@ impl Batcher {
     ///
     /// Optionally, you may specify a `context` that should be set on every
     /// batch returned by `into_message`.
-    pub fn new(context: Option<Value>) -> Self {
+    pub fn new(context: Option<Context>) -> Self {
+        // Start from the size of the empty batch envelope, so that only the
+        // messages themselves have to be accounted for on every push.
+        let envelope = utils::parse_batch(&Batch {
+            context: context.clone(),
+            ..Default::default()
+        });
+        let byte_count = serde_json::to_vec(&envelope).map_or(0, |v| v.len());
+
         Self {
             buf: Vec::new(),
-            byte_count: 0,
+            byte_count,
+            rudder_context: utils::get_context(&context),
             context,
         }
     }
 
     /// Push a message into the batcher.
     ///
+    /// A message id is assigned to the message if it has none.
+    ///
     /// Returns `Ok(None)` if the message was accepted and is now owned by the
     /// batcher.
     ///
@@ -82,24 +103,40 @@ impl Batcher {
     ///
     /// Returns an error if the message is too large to be sent to RudderStack's
     /// API.
-    pub fn push(&mut self, msg: BatchMessage) -> Result<Option<BatchMessage>, Error> {
-        let size = serde_json::to_vec(&msg)?.len();
+    pub fn push(&mut self, mut msg: BatchMessage) -> Result<Option<BatchMessage>, Error> {
+        // Assigned here, so that resending the batch keeps the same ids
+        msg.assign_message_id();
+        let now = Utc::now();
+        let rudder_msg =
+            utils::parse_batch_message(&msg, &self.rudder_context, &None, Some(now), now);
+        let size = serde_json::to_vec(&rudder_msg)?.len();
         if size > MAX_MESSAGE_SIZE {
-            return Err(AnalyticsError::MessageTooLarge(String::from(
-                "status code: 400, message: Message too large",
-            ))
-            .into());
+            return Err(Error::MessageTooLarge {
+                size,
+                limit: MAX_MESSAGE_SIZE,
+            });
         }
 
-        self.byte_count += size + 1; // +1 to account for Serialized data's extra commas
-        if self.byte_count > MAX_BATCH_SIZE {
+        let size = size + 1; // +1 to account for Serialized data's extra commas
+        if self.byte_count + size > MAX_BATCH_SIZE {
             return Ok(Some(msg));
         }
 
+        self.byte_count += size;
         self.buf.push(msg);
         Ok(None)
     }
 
+    /// Returns the number of messages currently held by the batcher.
+    pub fn len(&self) -> usize {
+        self.buf.len()
+    }
+
+    /// Returns `true` if the batcher holds no messages.
+    pub fn is_empty(&self) -> bool {
+        self.buf.is_empty()
+    }
+
     /// Consumes this batcher and converts it into a message that can be sent to
     /// RudderStack.
     pub fn into_message(self) -> Message {
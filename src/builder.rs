@@ -0,0 +1,334 @@
+//! Fluent builders for every event type.
+//!
+//! Each event has a `builder` constructor taking its required fields, e.g.
+//! [`Track::builder`]. `build` checks the same rules as
+//! [`Message::validate`](crate::message::Message::validate), e.g. that every
+//! event but alias has a user id or an anonymous id.
+//!
+//! ```
+//! use rudderanalytics::message::Track;
+//!
+//! let track = Track::builder("Order Completed")
+//!     .user_id("user-1")
+//!     .property("revenue", 42.0)
+//!     .context_ip("10.0.0.1")
+//!     .build()
+//!     .unwrap();
+//!
+//! assert_eq!(track.event, "Order Completed");
+//! assert_eq!(track.properties.unwrap()["revenue"], 42.0);
+//! ```
+
+use crate::context::Context;
+use crate::errors::Error;
+use crate::message::{first_violation, Alias, Group, Identify, Page, Screen, Track, Validate};
+use chrono::{DateTime, Utc};
+use serde_json::{Map, Value};
+
+// Setters shared by all builders
+macro_rules! common_setters {
+    () => {
+        /// Set the time at which the event happened. Defaults to the time
+        /// it is sent at.
+        pub fn original_timestamp(mut self, timestamp: DateTime<Utc>) -> Self {
+            self.msg.original_timestamp = Some(timestamp);
+            self
+        }
+
+        /// Set the context, replacing any context keys set so far.
+        pub fn context(mut self, context: Context) -> Self {
+            self.msg.context = Some(context);
+            self
+        }
+
+        /// Set the user's IP address in the context.
+        pub fn context_ip(mut self, ip: impl Into<String>) -> Self {
+            self.context_mut().ip = Some(ip.into());
+            self
+        }
+
+        /// Set the user's locale in the context, e.g. `en-US`.
+        pub fn context_locale(mut self, locale: impl Into<String>) -> Self {
+            self.context_mut().locale = Some(locale.into());
+            self
+        }
+
+        /// Set the user agent of the user's browser in the context.
+        pub fn context_user_agent(mut self, user_agent: impl Into<String>) -> Self {
+            self.context_mut().user_agent = Some(user_agent.into());
+            self
+        }
+
+        /// Set the integrations to route this event to, replacing any set
+        /// so far.
+        pub fn integrations(mut self, integrations: Value) -> Self {
+            self.msg.integrations = Some(integrations);
+            self
+        }
+
+        /// Enable or disable routing this event to the given integration,
+        /// e.g. `All` or `Amplitude`.
+        pub fn integration(mut self, name: impl Into<String>, enabled: bool) -> Self {
+            insert(
+                &mut self.msg.integrations,
+                name.into(),
+                Value::Bool(enabled),
+            );
+            self
+        }
+
+        /// Set the message id. A UUIDv4 is generated if none is set.
+        pub fn message_id(mut self, message_id: impl Into<String>) -> Self {
+            self.msg.message_id = Some(message_id.into());
+            self
+        }
+
+        fn context_mut(&mut self) -> &mut Context {
+            self.msg.context.get_or_insert_with(Context::default)
+        }
+    };
+}
+
+// Setters for the identity of events other than alias
+macro_rules! identity_setters {
+    () => {
+        /// Set the user id.
+        pub fn user_id(mut self, user_id: impl Into<String>) -> Self {
+            self.msg.user_id = Some(user_id.into());
+            self
+        }
+
+        /// Set the anonymous id.
+        pub fn anonymous_id(mut self, anonymous_id: impl Into<String>) -> Self {
+            self.msg.anonymous_id = Some(anonymous_id.into());
+            self
+        }
+    };
+}
+
+// Setters for the properties of track, page and screen events
+macro_rules! property_setters {
+    () => {
+        /// Set the properties, replacing any set so far.
+        pub fn properties(mut self, properties: Value) -> Self {
+            self.msg.properties = Some(properties);
+            self
+        }
+
+        /// Set a single property.
+        pub fn property(mut self, key: impl Into<String>, value: impl Into<Value>) -> Self {
+            insert(&mut self.msg.properties, key.into(), value.into());
+            self
+        }
+    };
+}
+
+// Setters for the traits of identify, group and alias events
+macro_rules! trait_setters {
+    () => {
+        /// Set the traits, replacing any set so far.
+        pub fn traits(mut self, traits: Value) -> Self {
+            self.msg.traits = Some(traits);
+            self
+        }
+
+        /// Set a single trait.
+        pub fn set_trait(mut self, key: impl Into<String>, value: impl Into<Value>) -> Self {
+            insert(&mut self.msg.traits, key.into(), value.into());
+            self
+        }
+    };
+}
+
+/// Builds an [`Identify`] event.
+#[derive(Debug, Clone, Default)]
+pub struct IdentifyBuilder {
+    msg: Identify,
+}
+
+impl Identify {
+    /// Start building an identify event.
+    pub fn builder() -> IdentifyBuilder {
+        IdentifyBuilder::default()
+    }
+}
+
+impl IdentifyBuilder {
+    identity_setters!();
+    trait_setters!();
+    common_setters!();
+
+    /// Build the event, failing with the first violation found by
+    /// [`Message::validate`](crate::message::Message::validate).
+    pub fn build(self) -> Result<Identify, Error> {
+        first_violation(self.msg.violations())?;
+        Ok(self.msg)
+    }
+}
+
+/// Builds a [`Track`] event.
+#[derive(Debug, Clone)]
+pub struct TrackBuilder {
+    msg: Track,
+}
+
+impl Track {
+    /// Start building a track event with the given event name.
+    pub fn builder(event: impl Into<String>) -> TrackBuilder {
+        TrackBuilder {
+            msg: Track {
+                event: event.into(),
+                ..Default::default()
+            },
+        }
+    }
+}
+
+impl TrackBuilder {
+    identity_setters!();
+    property_setters!();
+    common_setters!();
+
+    /// Build the event, failing with the first violation found by
+    /// [`Message::validate`](crate::message::Message::validate).
+    pub fn build(self) -> Result<Track, Error> {
+        first_violation(self.msg.violations())?;
+        Ok(self.msg)
+    }
+}
+
+/// Builds a [`Page`] event.
+#[derive(Debug, Clone)]
+pub struct PageBuilder {
+    msg: Page,
+}
+
+impl Page {
+    /// Start building a page event with the given page name.
+    pub fn builder(name: impl Into<String>) -> PageBuilder {
+        PageBuilder {
+            msg: Page {
+                name: name.into(),
+                ..Default::default()
+            },
+        }
+    }
+}
+
+impl PageBuilder {
+    identity_setters!();
+    property_setters!();
+    common_setters!();
+
+    /// Build the event, failing with the first violation found by
+    /// [`Message::validate`](crate::message::Message::validate).
+    pub fn build(self) -> Result<Page, Error> {
+        first_violation(self.msg.violations())?;
+        Ok(self.msg)
+    }
+}
+
+/// Builds a [`Screen`] event.
+#[derive(Debug, Clone)]
+pub struct ScreenBuilder {
+    msg: Screen,
+}
+
+impl Screen {
+    /// Start building a screen event with the given screen name.
+    pub fn builder(name: impl Into<String>) -> ScreenBuilder {
+        ScreenBuilder {
+            msg: Screen {
+                name: name.into(),
+                ..Default::default()
+            },
+        }
+    }
+}
+
+impl ScreenBuilder {
+    identity_setters!();
+    property_setters!();
+    common_setters!();
+
+    /// Build the event, failing with the first violation found by
+    /// [`Message::validate`](crate::message::Message::validate).
+    pub fn build(self) -> Result<Screen, Error> {
+        first_violation(self.msg.violations())?;
+        Ok(self.msg)
+    }
+}
+
+/// Builds a [`Group`] event.
+#[derive(Debug, Clone)]
+pub struct GroupBuilder {
+    msg: Group,
+}
+
+impl Group {
+    /// Start building a group event for the given group id.
+    pub fn builder(group_id: impl Into<String>) -> GroupBuilder {
+        GroupBuilder {
+            msg: Group {
+                group_id: group_id.into(),
+                ..Default::default()
+            },
+        }
+    }
+}
+
+impl GroupBuilder {
+    identity_setters!();
+    trait_setters!();
+    common_setters!();
+
+    /// Build the event, failing with the first violation found by
+    /// [`Message::validate`](crate::message::Message::validate).
+    pub fn build(self) -> Result<Group, Error> {
+        first_violation(self.msg.violations())?;
+        Ok(self.msg)
+    }
+}
+
+/// Builds an [`Alias`] event.
+#[derive(Debug, Clone)]
+pub struct AliasBuilder {
+    msg: Alias,
+}
+
+impl Alias {
+    /// Start building an alias event, merging the previous id into the given
+    /// user id.
+    pub fn builder(user_id: impl Into<String>, previous_id: impl Into<String>) -> AliasBuilder {
+        AliasBuilder {
+            msg: Alias {
+                user_id: user_id.into(),
+                previous_id: previous_id.into(),
+                ..Default::default()
+            },
+        }
+    }
+}
+
+impl AliasBuilder {
+    trait_setters!();
+    common_setters!();
+
+    /// Build the event, failing with the first violation found by
+    /// [`Message::validate`](crate::message::Message::validate).
+    pub fn build(self) -> Result<Alias, Error> {
+        first_violation(self.msg.violations())?;
+        Ok(self.msg)
+    }
+}
+
+// Inserts a key into an optional JSON object, replacing a value which is not
+// an object
+fn insert(object: &mut Option<Value>, key: String, value: Value) {
+    if !matches!(object, Some(Value::Object(_))) {
+        *object = Some(Value::Object(Map::new()));
+    }
+    if let Some(Value::Object(map)) = object {
+        map.insert(key, value);
+    }
+}
@@ -1,175 +1,874 @@
-use crate::errors::Error as AnalyticsError;
-use crate::message::Message;
-use failure::Error;
+use crate::batcher::{MAX_BATCH_SIZE, MAX_MESSAGE_SIZE};
+use crate::context::Context;
+use crate::dead_letter::{self, DeadLetter, DeadLetterSink};
+use crate::errors::{Error, InvalidItem};
+use crate::message::{self, Batch, BatchMessage, Message};
+use crate::middleware::{self, Middleware, Verdict};
+use crate::retry::{self, RetryPolicy};
+use crate::ruddermessage::Ruddermessage;
+#[cfg(feature = "tracking-plan")]
+use crate::tracking_plan::TrackingPlan;
+use crate::transport::{ReqwestTransport, Response, Transport};
+use base64::engine::general_purpose::STANDARD as BASE64;
+use base64::Engine;
+use flate2::write::GzEncoder;
+use flate2::Compression;
+#[cfg(feature = "async")]
+use crate::async_client::AsyncRudderAnalytics;
+use reqwest::header::{
+    HeaderMap, HeaderName, HeaderValue, AUTHORIZATION, CONTENT_ENCODING, CONTENT_TYPE,
+};
+use reqwest::{Proxy, StatusCode};
+use std::borrow::Cow;
+use std::fmt;
+use std::io::Write;
+use std::sync::Arc;
+use std::thread;
 use std::time::Duration;
 use crate::utils;
-use log::debug;
+use log::{debug, warn};
 
 // Rudderanalytics client
 pub struct RudderAnalytics {
     pub write_key: String,
-    pub data_plane_url: String,
-    pub client: reqwest::blocking::Client,
+    pub transport: Arc<dyn Transport>,
+    pub retry_policy: RetryPolicy,
+    pub gzip: bool,
+    pub dead_letter: Option<Arc<dyn DeadLetterSink>>,
+    pub batch_validation: BatchValidation,
+    pub middlewares: Vec<Arc<dyn Middleware>>,
+    #[cfg(feature = "tracking-plan")]
+    pub tracking_plan: Option<Arc<TrackingPlan>>,
 }
 
+/// The result of a successful send.
+#[derive(Debug)]
+pub struct Delivery {
+    /// The number of requests made to the data plane, including retries.
+    /// This is 0 if nothing was left to send, e.g. because a middleware or
+    /// the tracking plan dropped the message.
+    pub attempts: u32,
 
-impl RudderAnalytics {
+    /// What happened to the messages of a batch, `None` for other messages.
+    pub batch: Option<BatchOutcome>,
+}
 
-    // Function to initialize the Rudderanalytics client with write-key and data-plane-url
-    pub fn load(write_key: String, data_plane_url: String) -> RudderAnalytics {
-        RudderAnalytics {
+/// The outcome of sending a batch to `/v1/batch`.
+#[derive(Debug)]
+pub struct BatchOutcome {
+    /// The number of messages accepted by the data plane.
+    pub accepted: usize,
+
+    /// The messages which were dropped before sending because they failed
+    /// validation, with [`BatchValidation::DropInvalid`]. Sending them again
+    /// fails the same way.
+    pub rejected: Vec<InvalidItem>,
+
+    /// The indexes of the messages which were dropped by a
+    /// [`Middleware`](crate::middleware::Middleware).
+    pub dropped: Vec<usize>,
+
+    /// The body of the data plane's response.
+    pub response_body: String,
+}
+
+/// What the client does with a batch containing messages which fail
+/// validation.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum BatchValidation {
+    /// Fail the whole batch with [`Error::InvalidBatchItems`], listing every
+    /// invalid message. This is the default.
+    #[default]
+    Reject,
+
+    /// Send the valid messages and report the invalid ones in
+    /// [`BatchOutcome::rejected`]. The batch still fails if none are valid.
+    DropInvalid,
+}
+
+/// A builder for [`RudderAnalytics`] clients.
+///
+/// ```no_run
+/// use rudderanalytics::client::RudderAnalytics;
+/// use std::time::Duration;
+///
+/// let rudder_analytics = RudderAnalytics::builder("WRITE-KEY", "DATA-PLANE-URL")
+///     .connect_timeout(Duration::from_secs(5))
+///     .timeout(Duration::from_secs(30))
+///     .header("X-Gateway-Token", "secret")
+///     .gzip(true)
+///     .build()
+///     .unwrap();
+/// ```
+#[derive(Clone)]
+pub struct RudderAnalyticsBuilder {
+    write_key: String,
+    data_plane_url: String,
+    connect_timeout: Duration,
+    timeout: Option<Duration>,
+    https_proxy: Option<String>,
+    headers: Vec<(String, String)>,
+    user_agent: Option<String>,
+    pool_idle_timeout: Option<Duration>,
+    pool_max_idle_per_host: Option<usize>,
+    retry_policy: RetryPolicy,
+    gzip: bool,
+    dead_letter: Option<Arc<dyn DeadLetterSink>>,
+    transport: Option<Arc<dyn Transport>>,
+    batch_validation: BatchValidation,
+    middlewares: Vec<Arc<dyn Middleware>>,
+    #[cfg(feature = "tracking-plan")]
+    tracking_plan: Option<Arc<TrackingPlan>>,
+}
+
+impl fmt::Debug for RudderAnalyticsBuilder {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let mut f = f.debug_struct("RudderAnalyticsBuilder");
+        f
+            .field("write_key", &self.write_key)
+            .field("data_plane_url", &self.data_plane_url)
+            .field("connect_timeout", &self.connect_timeout)
+            .field("timeout", &self.timeout)
+            .field("https_proxy", &self.https_proxy)
+            .field("headers", &self.headers)
+            .field("user_agent", &self.user_agent)
+            .field("pool_idle_timeout", &self.pool_idle_timeout)
+            .field("pool_max_idle_per_host", &self.pool_max_idle_per_host)
+            .field("retry_policy", &self.retry_policy)
+            .field("gzip", &self.gzip)
+            .field("dead_letter", &self.dead_letter.is_some())
+            .field("transport", &self.transport.is_some())
+            .field("batch_validation", &self.batch_validation)
+            .field("middlewares", &self.middlewares.len());
+        #[cfg(feature = "tracking-plan")]
+        f.field("tracking_plan", &self.tracking_plan.is_some());
+        f.finish()
+    }
+}
+
+impl RudderAnalyticsBuilder {
+    fn new(write_key: String, data_plane_url: String) -> Self {
+        Self {
             write_key,
             data_plane_url,
-            client: reqwest::blocking::Client::builder()
-                .connect_timeout(Duration::new(10, 0))
-                .build()
-                .unwrap(),
+            connect_timeout: Duration::new(10, 0),
+            timeout: None,
+            https_proxy: None,
+            headers: Vec::new(),
+            user_agent: None,
+            pool_idle_timeout: None,
+            pool_max_idle_per_host: None,
+            retry_policy: RetryPolicy::default(),
+            gzip: false,
+            dead_letter: None,
+            transport: None,
+            batch_validation: BatchValidation::default(),
+            middlewares: Vec::new(),
+            #[cfg(feature = "tracking-plan")]
+            tracking_plan: None,
+        }
+    }
+
+    /// Timeout for establishing a connection to the data plane. Defaults to
+    /// 10 seconds.
+    pub fn connect_timeout(mut self, timeout: Duration) -> Self {
+        self.connect_timeout = timeout;
+        self
+    }
+
+    /// Timeout for a whole request, from connecting until the response body
+    /// has been read. Defaults to 30 seconds for the blocking client, and to
+    /// no timeout for the async one.
+    pub fn timeout(mut self, timeout: Duration) -> Self {
+        self.timeout = Some(timeout);
+        self
+    }
+
+    /// Send HTTPS requests through the proxy at the given url.
+    pub fn https_proxy(mut self, url: impl Into<String>) -> Self {
+        self.https_proxy = Some(url.into());
+        self
+    }
+
+    /// Add a header to every request made to the data plane.
+    pub fn header(mut self, name: impl Into<String>, value: impl Into<String>) -> Self {
+        self.headers.push((name.into(), value.into()));
+        self
+    }
+
+    /// The `User-Agent` sent with every request.
+    pub fn user_agent(mut self, user_agent: impl Into<String>) -> Self {
+        self.user_agent = Some(user_agent.into());
+        self
+    }
+
+    /// How long idle connections are kept in the pool.
+    pub fn pool_idle_timeout(mut self, timeout: Duration) -> Self {
+        self.pool_idle_timeout = Some(timeout);
+        self
+    }
+
+    /// The maximum number of idle connections kept per host.
+    pub fn pool_max_idle_per_host(mut self, max: usize) -> Self {
+        self.pool_max_idle_per_host = Some(max);
+        self
+    }
+
+    /// How failed requests are retried.
+    pub fn retry_policy(mut self, retry_policy: RetryPolicy) -> Self {
+        self.retry_policy = retry_policy;
+        self
+    }
+
+    /// Compress request bodies with gzip.
+    pub fn gzip(mut self, gzip: bool) -> Self {
+        self.gzip = gzip;
+        self
+    }
+
+    /// Where messages go which could not be delivered.
+    ///
+    /// See [`dead_letter`](crate::dead_letter) for the available sinks.
+    pub fn dead_letter(mut self, sink: Arc<dyn DeadLetterSink>) -> Self {
+        self.dead_letter = Some(sink);
+        self
+    }
+
+    /// Deliver requests with the given transport instead of sending them to
+    /// the data plane over HTTP. The HTTP settings of this builder are then
+    /// ignored. Transports are blocking, so `build_async` fails if one is
+    /// set.
+    ///
+    /// See [`transport`](crate::transport) for the available transports.
+    pub fn transport(mut self, transport: Arc<dyn Transport>) -> Self {
+        self.transport = Some(transport);
+        self
+    }
+
+    /// What to do with a batch containing invalid messages. Defaults to
+    /// rejecting the whole batch.
+    pub fn batch_validation(mut self, batch_validation: BatchValidation) -> Self {
+        self.batch_validation = batch_validation;
+        self
+    }
+
+    /// Run the given middleware on every message before sending it, after
+    /// the middlewares added before.
+    ///
+    /// See [`middleware`](crate::middleware) for details.
+    pub fn middleware(mut self, middleware: impl Middleware + 'static) -> Self {
+        self.middlewares.push(Arc::new(middleware));
+        self
+    }
+
+    /// Check every message against the given tracking plan before sending
+    /// it.
+    #[cfg(feature = "tracking-plan")]
+    pub fn tracking_plan(mut self, tracking_plan: TrackingPlan) -> Self {
+        self.tracking_plan = Some(Arc::new(tracking_plan));
+        self
+    }
+
+    /// Build a blocking client.
+    pub fn build(self) -> Result<RudderAnalytics, Error> {
+        let transport = match self.transport.clone() {
+            Some(transport) => transport,
+            None => Arc::new(ReqwestTransport::new(
+                self.blocking_client()?,
+                self.data_plane_url.clone(),
+            )),
+        };
+
+        Ok(RudderAnalytics {
+            write_key: self.write_key,
+            transport,
+            retry_policy: self.retry_policy,
+            gzip: self.gzip,
+            dead_letter: self.dead_letter,
+            batch_validation: self.batch_validation,
+            middlewares: self.middlewares,
+            #[cfg(feature = "tracking-plan")]
+            tracking_plan: self.tracking_plan,
+        })
+    }
+
+    fn blocking_client(&self) -> Result<reqwest::blocking::Client, Error> {
+        let mut builder = reqwest::blocking::Client::builder()
+            .connect_timeout(self.connect_timeout)
+            .default_headers(self.header_map()?);
+        // reqwest's own 30 seconds apply unless a timeout was set
+        if let Some(timeout) = self.timeout {
+            builder = builder.timeout(timeout);
         }
+        if let Some(proxy) = &self.https_proxy {
+            builder = builder.proxy(https_proxy(proxy)?);
+        }
+        if let Some(user_agent) = &self.user_agent {
+            builder = builder.user_agent(user_agent);
+        }
+        if let Some(timeout) = self.pool_idle_timeout {
+            builder = builder.pool_idle_timeout(timeout);
+        }
+        if let Some(max) = self.pool_max_idle_per_host {
+            builder = builder.pool_max_idle_per_host(max);
+        }
+        Ok(builder.build()?)
+    }
+
+    /// Build an async client.
+    ///
+    /// Fails with [`Error::InvalidConfig`] if a transport is set, as the
+    /// async client always sends over HTTP.
+    #[cfg(feature = "async")]
+    pub fn build_async(self) -> Result<AsyncRudderAnalytics, Error> {
+        if self.transport.is_some() {
+            return Err(Error::InvalidConfig(
+                "transports are not supported by the async client".to_owned(),
+            ));
+        }
+        let mut builder = reqwest::Client::builder()
+            .connect_timeout(self.connect_timeout)
+            .default_headers(self.header_map()?);
+        if let Some(timeout) = self.timeout {
+            builder = builder.timeout(timeout);
+        }
+        if let Some(proxy) = &self.https_proxy {
+            builder = builder.proxy(https_proxy(proxy)?);
+        }
+        if let Some(user_agent) = &self.user_agent {
+            builder = builder.user_agent(user_agent);
+        }
+        if let Some(timeout) = self.pool_idle_timeout {
+            builder = builder.pool_idle_timeout(timeout);
+        }
+        if let Some(max) = self.pool_max_idle_per_host {
+            builder = builder.pool_max_idle_per_host(max);
+        }
+
+        Ok(AsyncRudderAnalytics {
+            client: builder.build()?,
+            write_key: self.write_key,
+            data_plane_url: self.data_plane_url,
+            retry_policy: self.retry_policy,
+            gzip: self.gzip,
+            dead_letter: self.dead_letter,
+            batch_validation: self.batch_validation,
+            middlewares: self.middlewares,
+            #[cfg(feature = "tracking-plan")]
+            tracking_plan: self.tracking_plan,
+        })
+    }
+
+    fn header_map(&self) -> Result<HeaderMap, Error> {
+        let mut headers = HeaderMap::new();
+        for (name, value) in &self.headers {
+            let name = HeaderName::from_bytes(name.as_bytes())
+                .map_err(|_| Error::InvalidConfig(format!("invalid header name `{}`", name)))?;
+            let value = HeaderValue::from_str(value)
+                .map_err(|_| Error::InvalidConfig(format!("invalid value for header `{}`", name)))?;
+            headers.append(name, value);
+        }
+        Ok(headers)
+    }
+}
+
+fn https_proxy(url: &str) -> Result<Proxy, Error> {
+    Proxy::https(url).map_err(|err| Error::InvalidConfig(format!("invalid proxy `{}`: {}", url, err)))
+}
+
+impl RudderAnalytics {
+
+    // Function to initialize the Rudderanalytics client with write-key and data-plane-url
+    // Use `RudderAnalytics::builder` to configure the client further
+    pub fn load(write_key: String, data_plane_url: String) -> RudderAnalytics {
+        RudderAnalytics::builder(write_key, data_plane_url)
+            .build()
+            .expect("failed to build the HTTP client")
+    }
+
+    // Function to start configuring a Rudderanalytics client
+    pub fn builder(
+        write_key: impl Into<String>,
+        data_plane_url: impl Into<String>,
+    ) -> RudderAnalyticsBuilder {
+        RudderAnalyticsBuilder::new(write_key.into(), data_plane_url.into())
     }
 
     // Function that will receive user event data
     // and after validation
     // modify it to Ruddermessage format and send the event to data plane url
-    pub fn send(&self, msg: &Message) -> Result<(), Error> {
-
-        let id_err_msg = String::from("Either of user_id or anonymous_id is required");
-        let reserve_key_err_msg = String::from("Reserve keyword present in context");
-        let empty_msg = String::from("");
-        let mut error_msg: String = String::from("");
-
-        // match the type of event and fetch the proper API path
-        let path = match msg {
-            Message::Identify(b_) => {
-                // Checking for userId and anonymousId
-                if b_.user_id == Option::None && b_.anonymous_id == Option::None {
-                    error_msg = id_err_msg;
-                } else {
-                    error_msg = empty_msg;
-                    // Checking conflicts with reserved keywords
-                    if b_.context != Option::None && utils::check_reserved_keywords_conflict(b_.context.clone().unwrap()){
-                        error_msg = reserve_key_err_msg;
-                    }
-                }
-                "/v1/identify"
-            },
-            Message::Track(b_) => {
-                // Checking for userId and anonymousId
-                if b_.user_id == Option::None && b_.anonymous_id == Option::None {
-                    error_msg = id_err_msg;
-                } else {
-                    error_msg = empty_msg;
-                    // Checking conflicts with reserved keywords
-                    if b_.context != Option::None && utils::check_reserved_keywords_conflict(b_.context.clone().unwrap()){
-                        error_msg = reserve_key_err_msg;
-                    }
-                }
-                "/v1/track"
-            },
-            Message::Page(b_) => {
-                // Checking for userId and anonymousId
-                if b_.user_id == Option::None && b_.anonymous_id == Option::None {
-                    error_msg = id_err_msg;
-                } else {
-                    error_msg = empty_msg;
-                    // Checking conflicts with reserved keywords
-                    if b_.context != Option::None && utils::check_reserved_keywords_conflict(b_.context.clone().unwrap()){
-                        error_msg = reserve_key_err_msg;
-                    }
-                }
-                "/v1/page"
-            },
-            Message::Screen(b_) => {
-                // Checking for userId and anonymousId
-                if b_.user_id == Option::None && b_.anonymous_id == Option::None {
-                    error_msg = id_err_msg;
-                } else {
-                    error_msg = empty_msg;
-                    // Checking conflicts with reserved keywords
-                    if b_.context != Option::None && utils::check_reserved_keywords_conflict(b_.context.clone().unwrap()){
-                        error_msg = reserve_key_err_msg;
-                    }
-                }
-                "/v1/screen"
-            },
-            Message::Group(b_) => {
-                // Checking for userId and anonymousId
-                if b_.user_id == Option::None && b_.anonymous_id == Option::None {
-                    error_msg = id_err_msg;
-                } else {
-                    error_msg = empty_msg;
-                    // Checking conflicts with reserved keywords
-                    if b_.context != Option::None && utils::check_reserved_keywords_conflict(b_.context.clone().unwrap()){
-                        error_msg = reserve_key_err_msg;
+    pub fn send(&self, msg: &Message) -> Result<Delivery, Error> {
+        self.send_with(msg, self.batch_validation, false)
+    }
+
+    // Send with the given handling of invalid batch messages. With
+    // `requeued`, the caller sends the message again after a retryable
+    // failure, so only permanent failures are dead-lettered.
+    pub(crate) fn send_with(
+        &self,
+        msg: &Message,
+        batch_validation: BatchValidation,
+        requeued: bool,
+    ) -> Result<Delivery, Error> {
+        // The ids are assigned before the message is converted, so that a
+        // dead letter carries the ids of its payload
+        let mut msg = msg.clone();
+        msg.assign_message_ids();
+        let Prepared {
+            request,
+            message,
+            batch,
+        } = prepare(&msg, &self.preflight(batch_validation))?;
+        let (path, rudder_message) = match request {
+            Some(request) => request,
+            None => return Ok(Delivery { attempts: 0, batch }),
+        };
+
+        // final payload
+        debug!("rudder_message: {:#?}", rudder_message);
+        // Serialized once, so that every attempt sends the same body. A
+        // payload over the size limits is dead-lettered like a rejected one.
+        let result = encode(&rudder_message, self.gzip).and_then(|body| self.post(path, body));
+        if let (Err(err), Some(sink)) = (&result, &self.dead_letter) {
+            if !(requeued && err.is_retryable()) {
+                // Stored as sent, e.g. once personal data was redacted
+                dead_letter::store(sink.as_ref(), &message, &rudder_message, err);
+            }
+        }
+        let (attempts, response_body) = result?;
+        Ok(Delivery {
+            attempts,
+            batch: batch.map(|batch| BatchOutcome {
+                response_body,
+                ..batch
+            }),
+        })
+    }
+
+    /// Send dead-lettered messages again.
+    ///
+    /// Returns the messages which failed again, with the new failure. They
+    /// are not passed to the dead-letter sink, so that the caller decides
+    /// what happens to them. The middlewares already ran on dead letters,
+    /// and are not run again.
+    pub fn redrive(&self, letters: impl IntoIterator<Item = DeadLetter>) -> Vec<DeadLetter> {
+        letters
+            .into_iter()
+            .filter_map(|letter| {
+                let preflight = Preflight {
+                    middlewares: &[],
+                    ..self.preflight(self.batch_validation)
+                };
+                let result = prepare(&letter.message, &preflight).and_then(|prepared| {
+                    match prepared.request {
+                        Some((path, rudder_message)) => {
+                            self.post(path, encode(&rudder_message, self.gzip)?)?;
+                        }
+                        None => debug!("dead letter dropped, nothing to send"),
                     }
+                    Ok(())
+                });
+                match result {
+                    Ok(_) => None,
+                    Err(err) => Some(DeadLetter::new(&letter.message, letter.payload, &err)),
                 }
-                "/v1/group"
-            },
-            Message::Alias(b_) => {
-                // Checking conflicts with reserved keywords
-                if b_.context != Option::None && utils::check_reserved_keywords_conflict(b_.context.clone().unwrap()){
-                    error_msg = reserve_key_err_msg;
-                }
-                "/v1/alias"
-            },
-            Message::Batch(b_) => {
-                // Checking conflicts with reserved keywords
-                if b_.context != Option::None && utils::check_reserved_keywords_conflict(b_.context.clone().unwrap()){
-                    error_msg = reserve_key_err_msg;
+            })
+            .collect()
+    }
+
+    fn preflight(&self, batch_validation: BatchValidation) -> Preflight<'_> {
+        Preflight {
+            batch_validation,
+            middlewares: &self.middlewares,
+            #[cfg(feature = "tracking-plan")]
+            tracking_plan: self.tracking_plan.clone(),
+        }
+    }
+
+    // Send the payload to the data plane url, retrying as configured.
+    // Returns the number of attempts and the response body.
+    fn post(&self, path: &str, body: Vec<u8>) -> Result<(u32, String), Error> {
+        let headers = request_headers(&self.write_key, self.gzip)?;
+        let mut attempts = 0;
+        loop {
+            attempts += 1;
+            let res = self.transport.send(path, &headers, &body);
+
+            // handle error and send response
+            match check_response(res) {
+                Attempt::Delivered(body) => return Ok((attempts, body)),
+                Attempt::Failed(err) => return Err(err),
+                Attempt::Retryable(err, retry_after) => {
+                    let delay = match next_delay(&self.retry_policy, attempts, retry_after) {
+                        Some(delay) => delay,
+                        None => return Err(retries_exhausted(attempts, err)),
+                    };
+                    warn!("attempt {} failed: {}, retrying in {:?}", attempts, err, delay);
+                    thread::sleep(delay);
                 }
-                "/v1/batch"
-            },
+            }
+        }
+    }
+}
+
+// What the checks before sending do with a message
+pub(crate) enum Enforced<'a, T: Clone> {
+    // send it, possibly modified
+    Keep(Cow<'a, T>),
+    // leave it out
+    #[cfg(feature = "tracking-plan")]
+    Drop(Error),
+    // fail it
+    #[cfg(feature = "tracking-plan")]
+    Block(Error),
+}
+
+impl<T: Clone> Enforced<'_, T> {
+    // Detaches the result from the message it was computed for
+    fn into_owned(self) -> Enforced<'static, T> {
+        match self {
+            Enforced::Keep(msg) => Enforced::Keep(Cow::Owned(msg.into_owned())),
+            #[cfg(feature = "tracking-plan")]
+            Enforced::Drop(err) => Enforced::Drop(err),
+            #[cfg(feature = "tracking-plan")]
+            Enforced::Block(err) => Enforced::Block(err),
+        }
+    }
+}
+
+// The checks run on every message before it is converted, on top of its own
+// validation
+pub(crate) struct Preflight<'a> {
+    pub(crate) batch_validation: BatchValidation,
+    pub(crate) middlewares: &'a [Arc<dyn Middleware>],
+    #[cfg(feature = "tracking-plan")]
+    pub(crate) tracking_plan: Option<Arc<TrackingPlan>>,
+}
+
+impl Preflight<'_> {
+    // Runs the middlewares, None if the message is dropped
+    fn intercept<'a>(&self, msg: &'a Message) -> Option<Cow<'a, Message>> {
+        if self.middlewares.is_empty() || matches!(msg, Message::Batch(_)) {
+            return Some(Cow::Borrowed(msg));
+        }
+        middleware::run_single(self.middlewares, msg).map(Cow::Owned)
+    }
+
+    fn intercept_item<'a>(&self, msg: &'a BatchMessage) -> Option<Cow<'a, BatchMessage>> {
+        if self.middlewares.is_empty() {
+            return Some(Cow::Borrowed(msg));
+        }
+        let mut msg = msg.clone();
+        match middleware::run(self.middlewares, &mut msg) {
+            Verdict::Keep => Some(Cow::Owned(msg)),
+            Verdict::Drop => None,
+        }
+    }
+
+    fn intercept_batch_context<'a>(
+        &self,
+        context: &'a Option<Context>,
+    ) -> Cow<'a, Option<Context>> {
+        if self.middlewares.is_empty() {
+            return Cow::Borrowed(context);
+        }
+        let mut context = context.clone();
+        for middleware in self.middlewares {
+            middleware.process_batch_context(&mut context);
+        }
+        Cow::Owned(context)
+    }
+
+    fn enforce<'a>(&self, msg: &'a Message) -> Enforced<'a, Message> {
+        #[cfg(feature = "tracking-plan")]
+        if let Some(plan) = &self.tracking_plan {
+            return plan.enforce(msg);
+        }
+        Enforced::Keep(Cow::Borrowed(msg))
+    }
+
+    fn enforce_item<'a>(&self, msg: &'a BatchMessage) -> Enforced<'a, BatchMessage> {
+        #[cfg(feature = "tracking-plan")]
+        if let Some(plan) = &self.tracking_plan {
+            return plan.enforce_item(msg);
+        }
+        Enforced::Keep(Cow::Borrowed(msg))
+    }
+}
+
+// A message which passed the checks
+pub(crate) struct Prepared<'a> {
+    // the API path and the payload, None if nothing is left to send
+    pub(crate) request: Option<(&'static str, Ruddermessage)>,
+    // the message the payload is made of, after the middlewares
+    pub(crate) message: Cow<'a, Message>,
+    // for a batch, its outcome so far
+    pub(crate) batch: Option<BatchOutcome>,
+}
+
+// Runs the middlewares, validates the user event data and modifies it to
+// Ruddermessage format
+// returns the API path the payload has to be sent to along with the payload
+pub(crate) fn prepare<'a>(msg: &'a Message, preflight: &Preflight) -> Result<Prepared<'a>, Error> {
+    // The messages of a batch are checked one by one, after the middlewares
+    if let Message::Batch(batch) = msg {
+        return prepare_batch(msg, batch, preflight);
+    }
+
+    let nothing = Prepared {
+        request: None,
+        message: Cow::Borrowed(msg),
+        batch: None,
+    };
+    let msg = match preflight.intercept(msg) {
+        Some(msg) => msg,
+        None => return Ok(nothing),
+    };
+    msg.validate().or_else(message::first_violation)?;
+    // a message rewritten by the middlewares is owned by this function
+    let enforced = match msg {
+        Cow::Borrowed(msg) => preflight.enforce(msg),
+        Cow::Owned(msg) => preflight.enforce(&msg).into_owned(),
+    };
+    // Only the tracking plan leaves out or fails messages
+    #[cfg_attr(
+        not(feature = "tracking-plan"),
+        allow(clippy::infallible_destructuring_match)
+    )]
+    let msg = match enforced {
+        Enforced::Keep(msg) => msg,
+        #[cfg(feature = "tracking-plan")]
+        Enforced::Block(err) => return Err(err),
+        #[cfg(feature = "tracking-plan")]
+        Enforced::Drop(err) => {
+            debug!("dropping message: {}", err);
+            return Ok(nothing);
+        }
+    };
+    let request = match msg.as_ref() {
+        Message::Identify(b_) => ("/v1/identify", utils::parse_identify(b_)),
+        Message::Track(b_) => ("/v1/track", utils::parse_track(b_)),
+        Message::Page(b_) => ("/v1/page", utils::parse_page(b_)),
+        Message::Screen(b_) => ("/v1/screen", utils::parse_screen(b_)),
+        Message::Group(b_) => ("/v1/group", utils::parse_group(b_)),
+        Message::Alias(b_) => ("/v1/alias", utils::parse_alias(b_)),
+        Message::Batch(_) => unreachable!("batches are prepared on their own"),
+    };
+    Ok(Prepared {
+        request: Some(request),
+        message: msg,
+        batch: None,
+    })
+}
+
+// Checks every message of the batch, leaving out the ones which are dropped
+// `msg` is the batch as a message
+fn prepare_batch<'a>(
+    msg: &'a Message,
+    batch: &Batch,
+    preflight: &Preflight,
+) -> Result<Prepared<'a>, Error> {
+    let context = preflight.intercept_batch_context(&batch.context);
+    message::first_violation(message::check_context(&context, Vec::new()))?;
+
+    let mut kept = Vec::with_capacity(batch.batch.len());
+    let mut invalid = Vec::new();
+    let mut rejected = Vec::new();
+    let mut dropped = Vec::new();
+    for (index, msg) in batch.batch.iter().enumerate() {
+        let msg = match preflight.intercept_item(msg) {
+            Some(msg) => msg,
+            None => {
+                dropped.push(index);
+                continue;
+            }
+        };
+        if let Err(errors) = msg.validate() {
+            invalid.push(InvalidItem { index, errors });
+            continue;
+        }
+        // a message rewritten by the middlewares is owned by this loop
+        let enforced = match msg {
+            Cow::Borrowed(msg) => preflight.enforce_item(msg),
+            Cow::Owned(msg) => preflight.enforce_item(&msg).into_owned(),
         };
+        match enforced {
+            Enforced::Keep(msg) => kept.push(msg),
+            #[cfg(feature = "tracking-plan")]
+            Enforced::Block(err) => invalid.push(InvalidItem {
+                index,
+                errors: vec![err],
+            }),
+            #[cfg(feature = "tracking-plan")]
+            Enforced::Drop(err) => rejected.push(InvalidItem {
+                index,
+                errors: vec![err],
+            }),
+        }
+    }
 
-        
+    if !invalid.is_empty() {
+        if preflight.batch_validation == BatchValidation::Reject || kept.is_empty() {
+            return Err(Error::InvalidBatchItems(invalid));
+        }
+        warn!("dropping {} invalid messages from batch", invalid.len());
+        rejected.append(&mut invalid);
+        rejected.sort_by_key(|item| item.index);
+    }
 
-        if error_msg == String::from("") {
-            // match the type of event and manipulate the payload to rudder format
-            let rudder_message = match msg {
-                Message::Identify(b_) => {
-                    utils::parse_identify(b_)    
-                }
-                Message::Track(b_) => {
-                    utils::parse_track(b_)
-                }
-                Message::Page(b_) => {
-                    utils::parse_page(b_)
-                }
-                Message::Screen(b_) => {
-                    utils::parse_screen(b_)
-                }
-                Message::Group(b_) => {
-                    utils::parse_group(b_)
-                }
-                Message::Alias(b_) => {
-                    utils::parse_alias(b_)
-                }
-                Message::Batch(b_) => {
-                    utils::parse_batch(b_)
+    let unchanged = rejected.is_empty()
+        && dropped.is_empty()
+        && matches!(context, Cow::Borrowed(_))
+        && kept.iter().all(|msg| matches!(msg, Cow::Borrowed(_)));
+    let outcome = BatchOutcome {
+        accepted: kept.len(),
+        rejected,
+        dropped,
+        response_body: String::new(),
+    };
+    let (request, message) = if kept.is_empty() {
+        (None, Cow::Borrowed(msg))
+    } else if unchanged {
+        (Some(("/v1/batch", utils::parse_batch(batch))), Cow::Borrowed(msg))
+    } else {
+        let batch = Batch {
+            batch: kept.into_iter().map(Cow::into_owned).collect(),
+            context: context.into_owned(),
+            integrations: batch.integrations.clone(),
+            original_timestamp: batch.original_timestamp,
+        };
+        let request = ("/v1/batch", utils::parse_batch(&batch));
+        (Some(request), Cow::Owned(Message::Batch(batch)))
+    };
+    Ok(Prepared {
+        request,
+        message,
+        batch: Some(outcome),
+    })
+}
+
+// Serializes the payload and checks it against the data plane's size limits.
+// The limits apply to the uncompressed payload, which is gzipped afterwards
+// if requested.
+pub(crate) fn encode(rudder_message: &Ruddermessage, gzip: bool) -> Result<Vec<u8>, Error> {
+    let body = serde_json::to_vec(rudder_message)?;
+    match rudder_message {
+        Ruddermessage::Batch(batch) => {
+            if body.len() > MAX_BATCH_SIZE {
+                return Err(Error::BatchTooLarge {
+                    size: body.len(),
+                    limit: MAX_BATCH_SIZE,
+                });
+            }
+            for msg in &batch.batch {
+                let size = serde_json::to_vec(msg)?.len();
+                if size > MAX_MESSAGE_SIZE {
+                    return Err(Error::MessageTooLarge {
+                        size,
+                        limit: MAX_MESSAGE_SIZE,
+                    });
                 }
-            };
-        
-            // final payload 
-            debug!("rudder_message: {:#?}", rudder_message);
-            // Send the payload to the data plane url
-            let res = self
-                .client
-                .post(&format!("{}{}", self.data_plane_url, path))
-                .basic_auth(self.write_key.to_string(), Some(""))
-                .json(&rudder_message)
-                .send()?;
+            }
+        }
+        _ => {
+            if body.len() > MAX_MESSAGE_SIZE {
+                return Err(Error::MessageTooLarge {
+                    size: body.len(),
+                    limit: MAX_MESSAGE_SIZE,
+                });
+            }
+        }
+    }
 
-            // handle error and send response
-            if res.status() == 200 {
-                return Ok(());
+    if !gzip {
+        return Ok(body);
+    }
+    let mut encoder = GzEncoder::new(Vec::new(), Compression::default());
+    encoder.write_all(&body)?;
+    Ok(encoder.finish()?)
+}
+
+// The headers of every request to the data plane: the write key as basic
+// auth, and the body's content type and encoding
+pub(crate) fn request_headers(write_key: &str, gzip: bool) -> Result<HeaderMap, Error> {
+    let credentials = BASE64.encode(format!("{}:", write_key));
+    let authorization = HeaderValue::from_str(&format!("Basic {}", credentials))
+        .map_err(|_| Error::InvalidConfig("invalid write key".to_owned()))?;
+
+    let mut headers = HeaderMap::new();
+    headers.insert(AUTHORIZATION, authorization);
+    headers.insert(CONTENT_TYPE, HeaderValue::from_static("application/json"));
+    if gzip {
+        headers.insert(CONTENT_ENCODING, HeaderValue::from_static("gzip"));
+    }
+    Ok(headers)
+}
+
+// The outcome of a single request to the data plane
+pub(crate) enum Attempt {
+    // with the response body
+    Delivered(String),
+    Retryable(Error, Option<Duration>),
+    Failed(Error),
+}
+
+// Maps the data plane's response, or the transport error, to the outcome of
+// an attempt. 429, and 503 with a Retry-After header, mean that the client is
+// rate limited.
+pub(crate) fn check_response(res: Result<Response, Error>) -> Attempt {
+    let (err, retry_after) = match res {
+        Ok(res) if res.status == StatusCode::OK => return Attempt::Delivered(res.body),
+        Ok(res) => {
+            let retry_after = retry::retry_after(&res.headers);
+            if res.status == StatusCode::TOO_MANY_REQUESTS
+                || (res.status == StatusCode::SERVICE_UNAVAILABLE && retry_after.is_some())
+            {
+                (Error::RateLimited { retry_after }, retry_after)
             } else {
-                return Err(AnalyticsError::InvalidRequest(String::from(format!(
-                    "status code: {}, message: Invalid request",
-                    res.status()
-                )))
-                .into());
+                (
+                    Error::HttpStatus {
+                        status: res.status,
+                        body: res.body,
+                    },
+                    None,
+                )
             }
-        } else {
-            return Err(AnalyticsError::InvalidRequest(error_msg).into());
         }
+        Err(err) => (err, None),
+    };
+
+    if err.is_retryable() {
+        Attempt::Retryable(err, retry_after)
+    } else {
+        Attempt::Failed(err)
+    }
+}
+
+// Returns how long to wait before the next attempt, or None if the retry
+// policy gives up after the given attempt
+pub(crate) fn next_delay(
+    policy: &RetryPolicy,
+    attempts: u32,
+    retry_after: Option<Duration>,
+) -> Option<Duration> {
+    if attempts >= policy.max_attempts {
+        return None;
+    }
+    match retry_after {
+        Some(retry_after) if retry_after > policy.max_delay => None,
+        Some(retry_after) => Some(retry_after.max(policy.delay(attempts))),
+        None => Some(policy.delay(attempts)),
+    }
+}
+
+// The error returned once the retry policy gives up. Rate limiting is
+// reported as is, so that callers can back off for the requested time.
+pub(crate) fn retries_exhausted(attempts: u32, err: Error) -> Error {
+    if attempts == 1 || matches!(err, Error::RateLimited { .. }) {
+        return err;
+    }
+    Error::RetriesExhausted {
+        attempts,
+        last_error: Box::new(err),
     }
 }
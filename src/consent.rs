@@ -0,0 +1,128 @@
+//! Consent, sent to destinations in `context.consentManagement`.
+//!
+//! Destinations only receive a message if the user consented to the
+//! categories they are configured with. The consent of a single message can
+//! be set in its [`Context::consent_management`]. A [`Consent`] middleware
+//! keeps the consent of each user instead, and adds it to their messages:
+//!
+//! ```no_run
+//! use rudderanalytics::client::RudderAnalytics;
+//! use rudderanalytics::consent::Consent;
+//! use rudderanalytics::context::ConsentManagement;
+//!
+//! let consent = Consent::new().suppress_when_denied("analytics");
+//! let rudder_analytics = RudderAnalytics::builder("WRITE-KEY", "DATA-PLANE-URL")
+//!     .middleware(consent.clone())
+//!     .build()
+//!     .unwrap();
+//!
+//! // Later, once the user made their choice
+//! consent.set(
+//!     "user-1",
+//!     ConsentManagement {
+//!         provider: Some("custom".to_owned()),
+//!         allowed_consent_ids: vec!["marketing".to_owned()],
+//!         denied_consent_ids: vec!["analytics".to_owned()],
+//!         ..Default::default()
+//!     },
+//! );
+//! ```
+
+use crate::context::{ConsentManagement, Context};
+use crate::message::BatchMessage;
+use crate::middleware::{Middleware, Verdict};
+use log::debug;
+use std::collections::HashMap;
+use std::sync::{Arc, PoisonError, RwLock};
+
+/// Keeps the consent of each user, and adds it to their messages.
+///
+/// Clones share the consent of the users, so that a clone can be kept to
+/// update it after the middleware has been registered.
+#[derive(Debug, Clone, Default)]
+pub struct Consent {
+    users: Arc<RwLock<HashMap<String, ConsentManagement>>>,
+    suppressed: Vec<String>,
+}
+
+impl Consent {
+    /// Create a middleware without any consent.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Do not send the messages of users who denied the given consent
+    /// category at all, rather than leaving it to the destinations.
+    pub fn suppress_when_denied(mut self, category: impl Into<String>) -> Self {
+        self.suppressed.push(category.into());
+        self
+    }
+
+    /// Set the consent of a user, by user id or anonymous id.
+    pub fn set(&self, user: impl Into<String>, consent: ConsentManagement) {
+        self.users
+            .write()
+            .unwrap_or_else(PoisonError::into_inner)
+            .insert(user.into(), consent);
+    }
+
+    /// Forget the consent of a user.
+    pub fn remove(&self, user: &str) {
+        self.users
+            .write()
+            .unwrap_or_else(PoisonError::into_inner)
+            .remove(user);
+    }
+
+    /// The consent of a user, if it was set.
+    pub fn get(&self, user: &str) -> Option<ConsentManagement> {
+        self.users
+            .read()
+            .unwrap_or_else(PoisonError::into_inner)
+            .get(user)
+            .cloned()
+    }
+
+    // The consent of the user a message is about, by user id first
+    fn lookup(&self, msg: &BatchMessage) -> Option<ConsentManagement> {
+        let (user_id, anonymous_id) = msg.identity();
+        let users = self.users.read().unwrap_or_else(PoisonError::into_inner);
+        [user_id, anonymous_id]
+            .iter()
+            .flatten()
+            .find_map(|user| users.get(*user))
+            .cloned()
+    }
+}
+
+// Adds the consent of the user to the message, unless it already has its own,
+// and drops it if the user denied a suppressed category
+impl Middleware for Consent {
+    fn process(&self, msg: &mut BatchMessage) -> Verdict {
+        let stored = self.lookup(msg);
+        let context = msg.context_mut();
+        if let Some(consent) = stored {
+            let context = context.get_or_insert_with(Context::default);
+            if context.consent_management.is_none() {
+                context.consent_management = Some(consent);
+            }
+        }
+
+        let denied = context
+            .as_ref()
+            .and_then(|context| context.consent_management.as_ref())
+            .and_then(|consent| {
+                consent
+                    .denied_consent_ids
+                    .iter()
+                    .find(|id| self.suppressed.contains(id))
+            });
+        match denied {
+            Some(category) => {
+                debug!("message dropped, consent to `{}` was denied", category);
+                Verdict::Drop
+            }
+            None => Verdict::Keep,
+        }
+    }
+}
@@ -0,0 +1,279 @@
+//! The context sent along with every message.
+//!
+//! [`Context`] has a field for each of RudderStack's standard context keys.
+//! Any other key can be set through its `extra` map, and is serialized next
+//! to the standard ones, so that a `Context` serializes to the same JSON
+//! object as before.
+
+use crate::errors::Error;
+use serde::{Deserialize, Serialize};
+use serde_json::{Map, Value};
+use std::convert::TryFrom;
+
+/// Extra information about a message, like the device or the page it was
+/// sent from.
+///
+/// ```
+/// use rudderanalytics::context::{Context, Os};
+/// use serde_json::json;
+///
+/// let mut context = Context {
+///     ip: Some("10.0.0.1".to_owned()),
+///     os: Some(Os {
+///         name: Some("Linux".to_owned()),
+///         ..Default::default()
+///     }),
+///     ..Default::default()
+/// };
+/// context.extra.insert("tenant".to_owned(), json!("acme"));
+///
+/// assert_eq!(
+///     serde_json::to_value(&context).unwrap(),
+///     json!({ "ip": "10.0.0.1", "os": { "name": "Linux" }, "tenant": "acme" })
+/// );
+/// ```
+#[derive(PartialEq, Debug, Clone, Serialize, Deserialize, Default)]
+pub struct Context {
+    /// The application sending the message.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub app: Option<App>,
+
+    /// The marketing campaign the user came from.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub campaign: Option<Campaign>,
+
+    /// The user's consent, honored by destinations.
+    #[serde(rename = "consentManagement", skip_serializing_if = "Option::is_none")]
+    pub consent_management: Option<ConsentManagement>,
+
+    /// The user's device.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub device: Option<Device>,
+
+    /// The user's IP address.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub ip: Option<String>,
+
+    /// The library sending the message. This is set by the SDK, and
+    /// reserved.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub library: Option<Library>,
+
+    /// The user's locale, e.g. `en-US`.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub locale: Option<String>,
+
+    /// The user's network connection.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub network: Option<Network>,
+
+    /// The user's operating system.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub os: Option<Os>,
+
+    /// The web page the message was sent from.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub page: Option<Page>,
+
+    /// The referrer of the user.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub referrer: Option<Referrer>,
+
+    /// The user's screen.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub screen: Option<Screen>,
+
+    /// The user's timezone, e.g. `Europe/Berlin`.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub timezone: Option<String>,
+
+    /// Traits of the user, for messages other than identify.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub traits: Option<Value>,
+
+    /// The user agent of the user's browser.
+    #[serde(rename = "userAgent", skip_serializing_if = "Option::is_none")]
+    pub user_agent: Option<String>,
+
+    /// Any other keys.
+    #[serde(flatten)]
+    pub extra: Map<String, Value>,
+}
+
+/// Fails if the value is not a JSON object, or one of the standard keys has a
+/// value of the wrong type.
+impl TryFrom<Value> for Context {
+    type Error = Error;
+
+    fn try_from(value: Value) -> Result<Self, Error> {
+        Ok(serde_json::from_value(value)?)
+    }
+}
+
+/// The application sending the message.
+#[derive(PartialEq, Debug, Clone, Serialize, Deserialize, Default)]
+pub struct App {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub name: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub version: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub build: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub namespace: Option<String>,
+    /// Any other keys.
+    #[serde(flatten)]
+    pub extra: Map<String, Value>,
+}
+
+/// The marketing campaign the user came from, as in the `utm_*` parameters.
+#[derive(PartialEq, Debug, Clone, Serialize, Deserialize, Default)]
+pub struct Campaign {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub name: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub source: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub medium: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub term: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub content: Option<String>,
+    /// Any other keys.
+    #[serde(flatten)]
+    pub extra: Map<String, Value>,
+}
+
+/// The consent categories the user allowed and denied, as known to the
+/// consent management platform.
+#[derive(PartialEq, Debug, Clone, Serialize, Deserialize, Default)]
+pub struct ConsentManagement {
+    /// The consent management platform, e.g. `oneTrust` or `custom`.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub provider: Option<String>,
+    #[serde(rename = "allowedConsentIds", default, skip_serializing_if = "Vec::is_empty")]
+    pub allowed_consent_ids: Vec<String>,
+    #[serde(rename = "deniedConsentIds", default, skip_serializing_if = "Vec::is_empty")]
+    pub denied_consent_ids: Vec<String>,
+    /// Whether a destination needs all (`and`) or any (`or`) of its consent
+    /// categories to be allowed.
+    #[serde(rename = "resolutionStrategy", skip_serializing_if = "Option::is_none")]
+    pub resolution_strategy: Option<String>,
+    /// Any other keys.
+    #[serde(flatten)]
+    pub extra: Map<String, Value>,
+}
+
+/// The user's device.
+#[derive(PartialEq, Debug, Clone, Serialize, Deserialize, Default)]
+pub struct Device {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub id: Option<String>,
+    #[serde(rename = "advertisingId", skip_serializing_if = "Option::is_none")]
+    pub advertising_id: Option<String>,
+    #[serde(rename = "adTrackingEnabled", skip_serializing_if = "Option::is_none")]
+    pub ad_tracking_enabled: Option<bool>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub manufacturer: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub model: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub name: Option<String>,
+    #[serde(rename = "type", skip_serializing_if = "Option::is_none")]
+    pub r#type: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub token: Option<String>,
+    /// Any other keys.
+    #[serde(flatten)]
+    pub extra: Map<String, Value>,
+}
+
+/// The library sending the message.
+#[derive(PartialEq, Debug, Clone, Serialize, Deserialize, Default)]
+pub struct Library {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub name: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub version: Option<String>,
+    /// Any other keys.
+    #[serde(flatten)]
+    pub extra: Map<String, Value>,
+}
+
+/// The user's network connection.
+#[derive(PartialEq, Debug, Clone, Serialize, Deserialize, Default)]
+pub struct Network {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub bluetooth: Option<bool>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub carrier: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub cellular: Option<bool>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub wifi: Option<bool>,
+    /// Any other keys.
+    #[serde(flatten)]
+    pub extra: Map<String, Value>,
+}
+
+/// The user's operating system.
+#[derive(PartialEq, Debug, Clone, Serialize, Deserialize, Default)]
+pub struct Os {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub name: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub version: Option<String>,
+    /// Any other keys.
+    #[serde(flatten)]
+    pub extra: Map<String, Value>,
+}
+
+/// The web page the message was sent from.
+#[derive(PartialEq, Debug, Clone, Serialize, Deserialize, Default)]
+pub struct Page {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub path: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub referrer: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub search: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub title: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub url: Option<String>,
+    /// Any other keys.
+    #[serde(flatten)]
+    pub extra: Map<String, Value>,
+}
+
+/// The referrer of the user.
+#[derive(PartialEq, Debug, Clone, Serialize, Deserialize, Default)]
+pub struct Referrer {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub id: Option<String>,
+    #[serde(rename = "type", skip_serializing_if = "Option::is_none")]
+    pub r#type: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub name: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub url: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub link: Option<String>,
+    /// Any other keys.
+    #[serde(flatten)]
+    pub extra: Map<String, Value>,
+}
+
+/// The user's screen.
+#[derive(PartialEq, Debug, Clone, Serialize, Deserialize, Default)]
+pub struct Screen {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub density: Option<f64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub width: Option<u32>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub height: Option<u32>,
+    /// Any other keys.
+    #[serde(flatten)]
+    pub extra: Map<String, Value>,
+}
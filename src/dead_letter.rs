@@ -0,0 +1,276 @@
+//! Dead-lettering of messages which could not be delivered.
+//!
+//! When a send fails for good, either because the data plane rejected the
+//! message, because the retry policy gave up or because the payload exceeds
+//! the size limits, the client hands a [`DeadLetter`] to its configured
+//! [`DeadLetterSink`]. This includes rate
+//! limiting which outlasts the retry policy. The queued client only
+//! dead-letters permanent failures, as it sends the message again otherwise.
+//!
+//! Dead letters hold messages after the middlewares ran on them, so that
+//! e.g. redacted personal data is not stored. They can be sent again later
+//! with [`RudderAnalytics::redrive`](crate::client::RudderAnalytics::redrive).
+
+use crate::errors::Error;
+use crate::message::{Alias, Batch, Group, Identify, Message, Page, Screen, Track};
+use crate::ruddermessage::Ruddermessage;
+use chrono::{DateTime, Utc};
+use log::error;
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use std::collections::VecDeque;
+use std::fs::{File, OpenOptions};
+use std::io::{BufRead, BufReader, Write};
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+
+/// A message which could not be delivered, along with the reason.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct DeadLetter {
+    /// The message as it was sent, after the middlewares, with the message
+    /// ids of the payload.
+    #[serde(with = "tagged_message")]
+    pub message: Message,
+
+    /// The payload sent to the data plane.
+    pub payload: Value,
+
+    /// The status code of the last response, if any was received.
+    pub status: Option<u16>,
+
+    /// The body of the last response, if any was received.
+    pub response_body: Option<String>,
+
+    /// The error returned to the caller.
+    pub error: String,
+
+    /// When the message was given up on.
+    pub timestamp: DateTime<Utc>,
+}
+
+impl DeadLetter {
+    pub(crate) fn new(message: &Message, payload: Value, err: &Error) -> Self {
+        let (status, response_body) = match last_error(err) {
+            Error::HttpStatus { status, body } => (Some(*status), Some(body.clone())),
+            _ => (None, None),
+        };
+        Self {
+            message: message.clone(),
+            payload,
+            status,
+            response_body,
+            error: err.to_string(),
+            timestamp: Utc::now(),
+        }
+    }
+}
+
+fn last_error(err: &Error) -> &Error {
+    match err {
+        Error::RetriesExhausted { last_error, .. } => last_error,
+        _ => err,
+    }
+}
+
+// Hands a message which failed to send to the sink. Failures of the sink are
+// logged, as the caller already gets the send error.
+pub(crate) fn store(
+    sink: &dyn DeadLetterSink,
+    message: &Message,
+    payload: &Ruddermessage,
+    err: &Error,
+) {
+    let payload = serde_json::to_value(payload).unwrap_or_default();
+    if let Err(err) = sink.store(&DeadLetter::new(message, payload, err)) {
+        error!("failed to dead-letter a message: {}", err);
+    }
+}
+
+/// A destination for dead letters.
+pub trait DeadLetterSink: Send + Sync {
+    /// Store a dead letter.
+    fn store(&self, letter: &DeadLetter) -> Result<(), Error>;
+}
+
+/// Appends dead letters to a file, one JSON object per line.
+#[derive(Debug)]
+pub struct JsonlSink {
+    path: PathBuf,
+    file: Mutex<File>,
+}
+
+impl JsonlSink {
+    /// Open the given file for appending, creating it if needed.
+    pub fn open(path: impl Into<PathBuf>) -> Result<Self, Error> {
+        let path = path.into();
+        let file = OpenOptions::new().create(true).append(true).open(&path)?;
+        Ok(Self {
+            path,
+            file: Mutex::new(file),
+        })
+    }
+
+    /// Read all dead letters from a file written by a `JsonlSink`.
+    pub fn read(path: impl AsRef<Path>) -> Result<Vec<DeadLetter>, Error> {
+        let reader = BufReader::new(File::open(path)?);
+        let mut letters = Vec::new();
+        for line in reader.lines() {
+            let line = line?;
+            if !line.trim().is_empty() {
+                letters.push(serde_json::from_str(&line)?);
+            }
+        }
+        Ok(letters)
+    }
+
+    /// Read and remove all dead letters stored so far.
+    pub fn drain(&self) -> Result<Vec<DeadLetter>, Error> {
+        let file = self.file.lock().map_err(|_| poisoned())?;
+        let letters = Self::read(&self.path)?;
+        file.set_len(0)?;
+        Ok(letters)
+    }
+}
+
+impl DeadLetterSink for JsonlSink {
+    fn store(&self, letter: &DeadLetter) -> Result<(), Error> {
+        let mut line = serde_json::to_vec(letter)?;
+        line.push(b'\n');
+        let mut file = self.file.lock().map_err(|_| poisoned())?;
+        file.write_all(&line)?;
+        Ok(())
+    }
+}
+
+/// Passes dead letters to a function.
+pub struct CallbackSink<F> {
+    callback: F,
+}
+
+impl<F> CallbackSink<F>
+where
+    F: Fn(&DeadLetter) + Send + Sync,
+{
+    /// Call the given function with every dead letter.
+    pub fn new(callback: F) -> Self {
+        Self { callback }
+    }
+}
+
+impl<F> DeadLetterSink for CallbackSink<F>
+where
+    F: Fn(&DeadLetter) + Send + Sync,
+{
+    fn store(&self, letter: &DeadLetter) -> Result<(), Error> {
+        (self.callback)(letter);
+        Ok(())
+    }
+}
+
+/// Keeps the most recent dead letters in memory.
+#[derive(Debug)]
+pub struct MemorySink {
+    capacity: usize,
+    letters: Mutex<VecDeque<DeadLetter>>,
+}
+
+impl MemorySink {
+    /// Keep up to `capacity` dead letters, dropping the oldest ones first.
+    pub fn new(capacity: usize) -> Self {
+        Self {
+            capacity,
+            letters: Mutex::new(VecDeque::with_capacity(capacity)),
+        }
+    }
+
+    /// A copy of the dead letters stored so far, oldest first.
+    pub fn letters(&self) -> Vec<DeadLetter> {
+        match self.letters.lock() {
+            Ok(letters) => letters.iter().cloned().collect(),
+            Err(_) => Vec::new(),
+        }
+    }
+
+    /// Remove and return the dead letters stored so far, oldest first.
+    pub fn drain(&self) -> Vec<DeadLetter> {
+        match self.letters.lock() {
+            Ok(mut letters) => letters.drain(..).collect(),
+            Err(_) => Vec::new(),
+        }
+    }
+}
+
+impl DeadLetterSink for MemorySink {
+    fn store(&self, letter: &DeadLetter) -> Result<(), Error> {
+        if self.capacity == 0 {
+            return Ok(());
+        }
+        let mut letters = self.letters.lock().map_err(|_| poisoned())?;
+        if letters.len() == self.capacity {
+            letters.pop_front();
+        }
+        letters.push_back(letter.clone());
+        Ok(())
+    }
+}
+
+fn poisoned() -> Error {
+    Error::InvalidConfig("dead-letter sink poisoned by a panic".to_owned())
+}
+
+// `Message` is untagged, so that a track event cannot be told apart from an
+// identify event once serialized. Dead letters store the kind of message
+// along with it.
+mod tagged_message {
+    use super::*;
+    use serde::{Deserializer, Serializer};
+
+    #[derive(Serialize)]
+    #[serde(tag = "type", rename_all = "lowercase")]
+    enum TaggedRef<'a> {
+        Identify(&'a Identify),
+        Track(&'a Track),
+        Page(&'a Page),
+        Screen(&'a Screen),
+        Group(&'a Group),
+        Alias(&'a Alias),
+        Batch(&'a Batch),
+    }
+
+    #[derive(Deserialize)]
+    #[serde(tag = "type", rename_all = "lowercase")]
+    enum Tagged {
+        Identify(Identify),
+        Track(Track),
+        Page(Page),
+        Screen(Screen),
+        Group(Group),
+        Alias(Alias),
+        Batch(Batch),
+    }
+
+    pub fn serialize<S: Serializer>(msg: &Message, serializer: S) -> Result<S::Ok, S::Error> {
+        let tagged = match msg {
+            Message::Identify(m) => TaggedRef::Identify(m),
+            Message::Track(m) => TaggedRef::Track(m),
+            Message::Page(m) => TaggedRef::Page(m),
+            Message::Screen(m) => TaggedRef::Screen(m),
+            Message::Group(m) => TaggedRef::Group(m),
+            Message::Alias(m) => TaggedRef::Alias(m),
+            Message::Batch(m) => TaggedRef::Batch(m),
+        };
+        tagged.serialize(serializer)
+    }
+
+    pub fn deserialize<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Message, D::Error> {
+        Ok(match Tagged::deserialize(deserializer)? {
+            Tagged::Identify(m) => Message::Identify(m),
+            Tagged::Track(m) => Message::Track(m),
+            Tagged::Page(m) => Message::Page(m),
+            Tagged::Screen(m) => Message::Screen(m),
+            Tagged::Group(m) => Message::Group(m),
+            Tagged::Alias(m) => Message::Alias(m),
+            Tagged::Batch(m) => Message::Batch(m),
+        })
+    }
+}
@@ -0,0 +1,341 @@
+//! On-disk persistence for the queued client.
+//!
+//! Messages are appended to a log of segment files, one JSON record per line,
+//! before the queue accepts them. A cursor file records how far the log has
+//! been acknowledged, i.e. delivered or given up on. When a queue is started
+//! on the same directory again, the unacknowledged records are replayed.
+
+use crate::errors::Error;
+use crate::message::BatchMessage;
+use log::warn;
+use std::collections::VecDeque;
+use std::fs::{self, File, OpenOptions};
+use std::io::{BufRead, BufReader, Write};
+use std::path::{Path, PathBuf};
+use std::time::{Duration, Instant};
+
+const SEGMENT_EXTENSION: &str = "seg";
+const CURSOR_FILE: &str = "cursor";
+
+/// Where and how a [`QueuedRudderAnalytics`](crate::queue::QueuedRudderAnalytics)
+/// persists its messages.
+///
+/// The directory must not be used by more than one queue at a time.
+#[derive(Debug, Clone)]
+pub struct PersistenceConfig {
+    /// The directory holding the segment files. It is created if missing.
+    pub directory: PathBuf,
+
+    /// The maximum size of all segment files together. Once reached, the
+    /// queue reports itself as full.
+    pub max_bytes: u64,
+
+    /// The size after which a new segment file is started. Segments are
+    /// deleted once all their records have been acknowledged.
+    pub segment_bytes: u64,
+
+    /// When appended records are flushed to the disk.
+    pub fsync: FsyncPolicy,
+}
+
+impl PersistenceConfig {
+    /// Persist to the given directory, with a 64 MiB cap, 4 MiB segments and
+    /// a sync once per second.
+    pub fn new(directory: impl Into<PathBuf>) -> Self {
+        Self {
+            directory: directory.into(),
+            max_bytes: 64 * 1024 * 1024,
+            segment_bytes: 4 * 1024 * 1024,
+            fsync: FsyncPolicy::Interval(Duration::from_secs(1)),
+        }
+    }
+}
+
+/// When records are synced to the disk.
+///
+/// Records survive the process being killed under any policy, as soon as they
+/// have been written. The policy only matters for crashes of the operating
+/// system or power losses.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum FsyncPolicy {
+    /// Sync every record before it is accepted.
+    Always,
+    /// Sync at most once per interval.
+    Interval(Duration),
+    /// Leave syncing to the operating system.
+    Never,
+}
+
+// The position right after a record
+#[derive(Debug, Clone, Copy, PartialEq)]
+struct Position {
+    segment: u64,
+    offset: u64,
+}
+
+// An append-only log of messages, read back on start
+pub(crate) struct DiskQueue {
+    config: PersistenceConfig,
+    file: File,
+    position: Position,
+    // the end of every record which has not been acknowledged yet, in order
+    unacked: VecDeque<Position>,
+    // the start of the last appended record, until it is unappended
+    last_append: Option<Position>,
+    first_segment: u64,
+    size: u64,
+    last_sync: Instant,
+}
+
+impl DiskQueue {
+    // Open the log in the configured directory, and return it along with the
+    // records which were not acknowledged before.
+    pub(crate) fn open(config: PersistenceConfig) -> Result<(Self, Vec<BatchMessage>), Error> {
+        fs::create_dir_all(&config.directory)?;
+
+        let cursor = read_cursor(&config.directory)?;
+        let mut segments = list_segments(&config.directory)?;
+        for segment in segments.iter().filter(|segment| **segment < cursor.segment) {
+            fs::remove_file(segment_path(&config.directory, *segment))?;
+        }
+        segments.retain(|segment| *segment >= cursor.segment);
+
+        let mut replay = Vec::new();
+        let mut unacked = VecDeque::new();
+        let mut size = 0;
+        let mut position = Position {
+            segment: segments.first().copied().unwrap_or(cursor.segment),
+            offset: 0,
+        };
+        for segment in &segments {
+            let start = if *segment == cursor.segment {
+                cursor.offset
+            } else {
+                0
+            };
+            let end = read_segment(&config.directory, *segment, start, |end, msg| {
+                unacked.push_back(Position {
+                    segment: *segment,
+                    offset: end,
+                });
+                replay.push(msg);
+            })?;
+            size += end;
+            position = Position {
+                segment: *segment,
+                offset: end,
+            };
+        }
+
+        let file = open_segment(&config.directory, position.segment)?;
+        let queue = Self {
+            first_segment: segments.first().copied().unwrap_or(position.segment),
+            config,
+            file,
+            position,
+            unacked,
+            last_append: None,
+            size,
+            last_sync: Instant::now(),
+        };
+        Ok((queue, replay))
+    }
+
+    // Append a message to the log
+    pub(crate) fn append(&mut self, msg: &BatchMessage) -> Result<(), Error> {
+        let mut record = serde_json::to_vec(msg)?;
+        record.push(b'\n');
+        let len = record.len() as u64;
+        if self.size + len > self.config.max_bytes {
+            return Err(Error::QueueFull);
+        }
+
+        if self.position.offset > 0 && self.position.offset + len > self.config.segment_bytes {
+            self.rotate()?;
+        }
+        if let Err(err) = self.file.write_all(&record) {
+            // Do not leave a partial record behind
+            self.file.set_len(self.position.offset)?;
+            return Err(err.into());
+        }
+
+        self.last_append = Some(self.position);
+        self.position.offset += len;
+        self.size += len;
+        self.unacked.push_back(self.position);
+        self.sync(false)?;
+        Ok(())
+    }
+
+    // Remove the last appended record, when the queue did not accept it after
+    // all. Records before it may have been acknowledged already, so the
+    // segment is cut exactly where the record started.
+    pub(crate) fn unappend(&mut self) -> Result<(), Error> {
+        if let Some(start) = self.last_append.take() {
+            self.unacked.pop_back();
+            self.file.set_len(start.offset)?;
+            self.size -= self.position.offset - start.offset;
+            self.position = start;
+        }
+        Ok(())
+    }
+
+    // Acknowledge the given number of the oldest records.
+    pub(crate) fn ack(&mut self, count: usize) -> Result<(), Error> {
+        let count = count.min(self.unacked.len());
+        let cursor = match self.unacked.drain(..count).next_back() {
+            Some(cursor) => cursor,
+            None => return Ok(()),
+        };
+        write_cursor(&self.config.directory, cursor, self.config.fsync)?;
+
+        while self.first_segment < cursor.segment {
+            let path = segment_path(&self.config.directory, self.first_segment);
+            if let Ok(metadata) = fs::metadata(&path) {
+                self.size -= metadata.len();
+                fs::remove_file(path)?;
+            }
+            self.first_segment += 1;
+        }
+        Ok(())
+    }
+
+    fn rotate(&mut self) -> Result<(), Error> {
+        self.sync(true)?;
+        self.position = Position {
+            segment: self.position.segment + 1,
+            offset: 0,
+        };
+        self.file = open_segment(&self.config.directory, self.position.segment)?;
+        Ok(())
+    }
+
+    fn sync(&mut self, force: bool) -> Result<(), Error> {
+        let due = match self.config.fsync {
+            FsyncPolicy::Always => true,
+            FsyncPolicy::Interval(interval) => force || self.last_sync.elapsed() >= interval,
+            FsyncPolicy::Never => false,
+        };
+        if due {
+            self.file.sync_data()?;
+            self.last_sync = Instant::now();
+        }
+        Ok(())
+    }
+}
+
+impl Drop for DiskQueue {
+    fn drop(&mut self) {
+        if let Err(err) = self.sync(true) {
+            warn!("failed to sync the queue to disk: {}", err);
+        }
+    }
+}
+
+fn segment_path(directory: &Path, segment: u64) -> PathBuf {
+    directory.join(format!("{:020}.{}", segment, SEGMENT_EXTENSION))
+}
+
+fn open_segment(directory: &Path, segment: u64) -> Result<File, Error> {
+    Ok(OpenOptions::new()
+        .create(true)
+        .append(true)
+        .open(segment_path(directory, segment))?)
+}
+
+// The ids of all segments in the directory, in order
+fn list_segments(directory: &Path) -> Result<Vec<u64>, Error> {
+    let mut segments = Vec::new();
+    for entry in fs::read_dir(directory)? {
+        let path = entry?.path();
+        if path.extension().and_then(|ext| ext.to_str()) != Some(SEGMENT_EXTENSION) {
+            continue;
+        }
+        if let Some(segment) = path
+            .file_stem()
+            .and_then(|stem| stem.to_str())
+            .and_then(|stem| stem.parse().ok())
+        {
+            segments.push(segment);
+        }
+    }
+    segments.sort_unstable();
+    Ok(segments)
+}
+
+// Read the records of a segment from the given offset, and return the
+// segment's length. A partially written last record is truncated.
+fn read_segment(
+    directory: &Path,
+    segment: u64,
+    start: u64,
+    mut record: impl FnMut(u64, BatchMessage),
+) -> Result<u64, Error> {
+    let path = segment_path(directory, segment);
+    let mut reader = BufReader::new(File::open(&path)?);
+    let mut offset = 0;
+    let mut line = Vec::new();
+    loop {
+        line.clear();
+        let len = reader.read_until(b'\n', &mut line)? as u64;
+        if len == 0 {
+            break;
+        }
+        if line.last() != Some(&b'\n') {
+            warn!("truncating a partial record in {}", path.display());
+            OpenOptions::new()
+                .write(true)
+                .open(&path)?
+                .set_len(offset)?;
+            break;
+        }
+
+        offset += len;
+        if offset <= start {
+            continue;
+        }
+        match serde_json::from_slice(&line) {
+            Ok(msg) => record(offset, msg),
+            Err(err) => warn!(
+                "skipping an unreadable record in {}: {}",
+                path.display(),
+                err
+            ),
+        }
+    }
+    Ok(offset)
+}
+
+fn read_cursor(directory: &Path) -> Result<Position, Error> {
+    let content = match fs::read_to_string(directory.join(CURSOR_FILE)) {
+        Ok(content) => content,
+        Err(err) if err.kind() == std::io::ErrorKind::NotFound => {
+            return Ok(Position {
+                segment: 0,
+                offset: 0,
+            })
+        }
+        Err(err) => return Err(err.into()),
+    };
+
+    let mut parts = content.split_whitespace().map(str::parse);
+    match (parts.next(), parts.next()) {
+        (Some(Ok(segment)), Some(Ok(offset))) => Ok(Position { segment, offset }),
+        _ => Err(Error::InvalidConfig(format!(
+            "invalid queue cursor in {}",
+            directory.display()
+        ))),
+    }
+}
+
+// Replace the cursor file, so that it is never seen half written
+fn write_cursor(directory: &Path, cursor: Position, fsync: FsyncPolicy) -> Result<(), Error> {
+    let tmp = directory.join(format!("{}.tmp", CURSOR_FILE));
+    let mut file = File::create(&tmp)?;
+    writeln!(file, "{} {}", cursor.segment, cursor.offset)?;
+    if fsync != FsyncPolicy::Never {
+        file.sync_data()?;
+    }
+    fs::rename(tmp, directory.join(CURSOR_FILE))?;
+    Ok(())
+}
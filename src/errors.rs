@@ -1,15 +1,112 @@
 //! Errors which may arise from this crate.
 
-use failure::Fail;
+use std::time::Duration;
+use thiserror::Error;
 
-/// An enum of errors this crate may produce. These are compatible with
-/// `failure` errors.
-#[derive(Debug, Fail)]
+/// An enum of errors this crate may produce. It implements
+/// `std::error::Error`, so it composes with `thiserror`, `anyhow` and `?`.
+#[derive(Debug, Error)]
 pub enum Error {
+    /// The message has neither a user id nor an anonymous id.
+    #[error("either of user_id or anonymous_id is required")]
+    MissingIdentity,
+
+    /// The message's context contains a key which is reserved by the SDK.
+    #[error("reserved keyword `{0}` present in context")]
+    ReservedKeyword(String),
+
+    /// A required field of the message, like a track event's name, is empty.
+    #[error("`{0}` must not be empty")]
+    EmptyField(&'static str),
+
+    /// An alias message has the same `previous_id` as `user_id`.
+    #[error("previous_id must differ from user_id")]
+    AliasToSelf,
+
+    /// Messages of a batch failed validation, see
+    /// [`BatchValidation`](crate::client::BatchValidation).
+    #[error("invalid messages in batch at indices {:?}", .0.iter().map(|item| item.index).collect::<Vec<_>>())]
+    InvalidBatchItems(Vec<InvalidItem>),
+
+    /// The message breaks the client's tracking plan.
+    #[cfg(feature = "tracking-plan")]
+    #[error("tracking plan violated: {}", .0.iter().map(ToString::to_string).collect::<Vec<_>>().join(", "))]
+    TrackingPlanViolation(Vec<crate::tracking_plan::Violation>),
+
     /// The given message is too large to be sent to RudderStack's API.
-    #[fail(display = "message too large")]
-    MessageTooLarge(String),
+    #[error("message too large: {size} bytes, the limit is {limit} bytes")]
+    MessageTooLarge { size: usize, limit: usize },
+
+    /// The given batch is too large to be sent to RudderStack's API.
+    #[error("batch too large: {size} bytes, the limit is {limit} bytes")]
+    BatchTooLarge { size: usize, limit: usize },
+
+    /// The data plane answered with an unexpected status code.
+    #[error("status code: {status}, body: {body}")]
+    HttpStatus { status: u16, body: String },
+
+    /// The request could not be sent, or its response could not be read.
+    #[error("transport error: {0}")]
+    Transport(#[from] reqwest::Error),
+
+    /// The message could not be serialized.
+    #[error("serialization error: {0}")]
+    Serialization(#[from] serde_json::Error),
+
+    /// An I/O error, e.g. while compressing a request body.
+    #[error("I/O error: {0}")]
+    Io(#[from] std::io::Error),
+
+    /// The client was configured with invalid settings.
+    #[error("invalid configuration: {0}")]
+    InvalidConfig(String),
+
+    /// The data plane is rate limiting this client. `retry_after` is the
+    /// delay it asked for, if any.
+    #[error("rate limited by the data plane")]
+    RateLimited { retry_after: Option<Duration> },
+
+    /// The message could not be delivered within the retry policy's attempts.
+    #[error("giving up after {attempts} attempts: {last_error}")]
+    RetriesExhausted {
+        attempts: u32,
+        #[source]
+        last_error: Box<Error>,
+    },
+
+    /// The queue has reached its capacity and cannot accept more messages.
+    #[error("queue is full")]
+    QueueFull,
+
+    /// The queue's worker thread has stopped.
+    #[error("queue is closed")]
+    QueueClosed,
+}
+
+/// A message of a batch which failed validation.
+#[derive(Debug)]
+pub struct InvalidItem {
+    /// The index of the message in the batch.
+    pub index: usize,
+
+    /// Everything wrong with the message, as returned by
+    /// [`BatchMessage::validate`](crate::message::BatchMessage::validate).
+    pub errors: Vec<Error>,
+}
 
-    #[fail(display = "Invalid request")]
-    InvalidRequest(String),
+impl Error {
+    /// Returns `true` if sending the same message again may succeed.
+    ///
+    /// This is the case for network errors, rate limiting, `5xx` responses
+    /// and a full queue. Invalid messages, `4xx` responses and configuration
+    /// errors are permanent.
+    pub fn is_retryable(&self) -> bool {
+        match self {
+            Error::HttpStatus { status, .. } => *status == 429 || *status >= 500,
+            Error::Transport(err) => !err.is_builder(),
+            Error::RateLimited { .. } | Error::QueueFull => true,
+            Error::RetriesExhausted { last_error, .. } => last_error.is_retryable(),
+            _ => false,
+        }
+    }
 }
@@ -1,7 +1,27 @@
 // public modules
+#[cfg(feature = "async")]
+pub mod async_client;
+pub mod batcher;
+pub mod builder;
 pub mod client;
+pub mod consent;
+pub mod context;
+pub mod dead_letter;
+pub mod disk_queue;
 pub mod errors;
 pub mod message;
+pub mod middleware;
+pub mod queue;
+#[cfg(feature = "redaction")]
+pub mod redaction;
+pub mod retry;
+pub mod sampling;
+pub mod suppression;
+#[cfg(feature = "test-server")]
+pub mod test_server;
+#[cfg(feature = "tracking-plan")]
+pub mod tracking_plan;
+pub mod transport;
 // private modules
 mod ruddermessage;
 mod utils;
@@ -1,11 +1,11 @@
 use clap::{App, AppSettings, Arg, SubCommand};
-use failure::Error;
+use std::error::Error;
 use log::debug;
 use rudderanalytics::client::RudderAnalytics;
 use rudderanalytics::message::Message;
 use std::io;
 
-fn main() -> Result<(), Error> {
+fn main() -> Result<(), Box<dyn Error>> {
     env_logger::init();
     let matches = App::new("Rudderanalytics")
         .version("0.1")
@@ -63,5 +63,6 @@ fn main() -> Result<(), Error> {
         None => panic!("subcommand is required"),
     };
 
-    rudderanalytics.send(&message)
+    rudderanalytics.send(&message)?;
+    Ok(())
 }
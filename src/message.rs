@@ -1,6 +1,10 @@
+use crate::context::Context;
+use crate::errors::{Error, InvalidItem};
+use crate::utils;
 use chrono::{DateTime, Utc};
 use serde::{Deserialize, Serialize};
 use serde_json::Value;
+use uuid::Uuid;
 
 /// An enum containing all values which may be sent to RudderStack's API.
 #[derive(PartialEq, Debug, Clone, Serialize, Deserialize)]
@@ -36,11 +40,16 @@ pub struct Identify {
 
     /// Context associated with this message.
     #[serde(skip_serializing_if = "Option::is_none")]
-    pub context: Option<Value>,
+    pub context: Option<Context>,
 
     /// Integrations to route this message to.
     #[serde(skip_serializing_if = "Option::is_none")]
     pub integrations: Option<Value>,
+
+    /// The unique id of this message, which lets the data plane deduplicate
+    /// retried deliveries. A UUIDv4 is generated if none is set.
+    #[serde(rename = "messageId", skip_serializing_if = "Option::is_none")]
+    pub message_id: Option<String>,
 }
 
 /// A track event.
@@ -67,11 +76,16 @@ pub struct Track {
 
     /// Context associated with this message.
     #[serde(skip_serializing_if = "Option::is_none")]
-    pub context: Option<Value>,
+    pub context: Option<Context>,
 
     /// Integrations to route this message to.
     #[serde(skip_serializing_if = "Option::is_none")]
     pub integrations: Option<Value>,
+
+    /// The unique id of this message, which lets the data plane deduplicate
+    /// retried deliveries. A UUIDv4 is generated if none is set.
+    #[serde(rename = "messageId", skip_serializing_if = "Option::is_none")]
+    pub message_id: Option<String>,
 }
 
 /// A page event.
@@ -98,11 +112,16 @@ pub struct Page {
 
     /// Context associated with this message.
     #[serde(skip_serializing_if = "Option::is_none")]
-    pub context: Option<Value>,
+    pub context: Option<Context>,
 
     /// Integrations to route this message to.
     #[serde(skip_serializing_if = "Option::is_none")]
     pub integrations: Option<Value>,
+
+    /// The unique id of this message, which lets the data plane deduplicate
+    /// retried deliveries. A UUIDv4 is generated if none is set.
+    #[serde(rename = "messageId", skip_serializing_if = "Option::is_none")]
+    pub message_id: Option<String>,
 }
 
 /// A screen event.
@@ -129,11 +148,16 @@ pub struct Screen {
 
     /// Context associated with this message.
     #[serde(skip_serializing_if = "Option::is_none")]
-    pub context: Option<Value>,
+    pub context: Option<Context>,
 
     /// Integrations to route this message to.
     #[serde(skip_serializing_if = "Option::is_none")]
     pub integrations: Option<Value>,
+
+    /// The unique id of this message, which lets the data plane deduplicate
+    /// retried deliveries. A UUIDv4 is generated if none is set.
+    #[serde(rename = "messageId", skip_serializing_if = "Option::is_none")]
+    pub message_id: Option<String>,
 }
 
 /// A group event.
@@ -161,11 +185,16 @@ pub struct Group {
 
     /// Context associated with this message.
     #[serde(skip_serializing_if = "Option::is_none")]
-    pub context: Option<Value>,
+    pub context: Option<Context>,
 
     /// Integrations to route this message to.
     #[serde(skip_serializing_if = "Option::is_none")]
     pub integrations: Option<Value>,
+
+    /// The unique id of this message, which lets the data plane deduplicate
+    /// retried deliveries. A UUIDv4 is generated if none is set.
+    #[serde(rename = "messageId", skip_serializing_if = "Option::is_none")]
+    pub message_id: Option<String>,
 }
 
 /// An alias event.
@@ -189,28 +218,37 @@ pub struct Alias {
 
     /// Context associated with this message.
     #[serde(skip_serializing_if = "Option::is_none")]
-    pub context: Option<Value>,
+    pub context: Option<Context>,
 
     /// Integrations to route this message to.
     #[serde(skip_serializing_if = "Option::is_none")]
     pub integrations: Option<Value>,
+
+    /// The unique id of this message, which lets the data plane deduplicate
+    /// retried deliveries. A UUIDv4 is generated if none is set.
+    #[serde(rename = "messageId", skip_serializing_if = "Option::is_none")]
+    pub message_id: Option<String>,
 }
 
 /// A batch of events.
+///
+/// The batch's `context` and `integrations` apply to every message in it,
+/// and each message's own values are merged on top of them. Likewise, the
+/// batch's `original_timestamp` is only used for messages without one.
 #[derive(PartialEq, Debug, Clone, Serialize, Deserialize, Default)]
 pub struct Batch {
     /// The batch of messages to send.
     pub batch: Vec<BatchMessage>,
 
-    /// Context associated with this message.
+    /// Context shared by all messages of the batch.
     #[serde(skip_serializing_if = "Option::is_none")]
-    pub context: Option<Value>,
+    pub context: Option<Context>,
 
-    /// Integrations to route this message to.
+    /// Integrations shared by all messages of the batch.
     #[serde(skip_serializing_if = "Option::is_none")]
     pub integrations: Option<Value>,
 
-    /// The timestamp associated with this message.
+    /// The timestamp of messages which do not have their own.
     #[serde(rename="originalTimestamp", skip_serializing_if = "Option::is_none")]
     pub original_timestamp: Option<DateTime<Utc>>,
 }
@@ -232,3 +270,264 @@ pub enum BatchMessage {
     #[serde(rename = "alias")]
     Alias(Alias),
 }
+
+impl Message {
+    /// Check the message without sending it, and return all violations.
+    ///
+    /// Every message but alias needs a user id or an anonymous id, and no
+    /// context may contain a key reserved by the SDK. Event, page and screen
+    /// names and group ids must not be empty, and an alias must not point to
+    /// itself. `send` rejects a message failing any of these checks with the
+    /// first violation.
+    ///
+    /// For a batch, the batch's own context is checked, followed by an
+    /// [`Error::InvalidBatchItems`] listing the violations of every invalid
+    /// message. `send` handles invalid messages as configured by
+    /// [`BatchValidation`](crate::client::BatchValidation).
+    pub fn validate(&self) -> Result<(), Vec<Error>> {
+        let violations = match self {
+            Message::Identify(m) => m.violations(),
+            Message::Track(m) => m.violations(),
+            Message::Page(m) => m.violations(),
+            Message::Screen(m) => m.violations(),
+            Message::Group(m) => m.violations(),
+            Message::Alias(m) => m.violations(),
+            Message::Batch(b) => b.violations(),
+        };
+        into_result(violations)
+    }
+
+    /// Assign a freshly generated message id to this message, or to every
+    /// message of a batch, unless it already has one.
+    ///
+    /// Ids are otherwise generated while sending. Call this once before
+    /// sending if you retry a failed send on your own, so that the retries
+    /// reuse the same ids.
+    pub fn assign_message_ids(&mut self) {
+        match self.message_id_mut() {
+            Some(message_id) => {
+                message_id.get_or_insert_with(generate_message_id);
+            }
+            None => {
+                if let Message::Batch(b) = self {
+                    b.batch.iter_mut().for_each(BatchMessage::assign_message_id);
+                }
+            }
+        }
+    }
+
+    // The context of a single message, None for a batch
+    #[cfg(feature = "tracking-plan")]
+    pub(crate) fn context_mut(&mut self) -> Option<&mut Option<Context>> {
+        match self {
+            Message::Identify(m) => Some(&mut m.context),
+            Message::Track(m) => Some(&mut m.context),
+            Message::Page(m) => Some(&mut m.context),
+            Message::Screen(m) => Some(&mut m.context),
+            Message::Group(m) => Some(&mut m.context),
+            Message::Alias(m) => Some(&mut m.context),
+            Message::Batch(_) => None,
+        }
+    }
+
+    // The message id of a single message, None for a batch
+    pub(crate) fn message_id_mut(&mut self) -> Option<&mut Option<String>> {
+        match self {
+            Message::Identify(m) => Some(&mut m.message_id),
+            Message::Track(m) => Some(&mut m.message_id),
+            Message::Page(m) => Some(&mut m.message_id),
+            Message::Screen(m) => Some(&mut m.message_id),
+            Message::Group(m) => Some(&mut m.message_id),
+            Message::Alias(m) => Some(&mut m.message_id),
+            Message::Batch(_) => None,
+        }
+    }
+}
+
+impl BatchMessage {
+    /// Check the message without sending it, and return all violations.
+    /// See [`Message::validate`] for the rules.
+    pub fn validate(&self) -> Result<(), Vec<Error>> {
+        let violations = match self {
+            BatchMessage::Identify(m) => m.violations(),
+            BatchMessage::Track(m) => m.violations(),
+            BatchMessage::Page(m) => m.violations(),
+            BatchMessage::Screen(m) => m.violations(),
+            BatchMessage::Group(m) => m.violations(),
+            BatchMessage::Alias(m) => m.violations(),
+        };
+        into_result(violations)
+    }
+
+    /// Assign a freshly generated message id to this message, unless it
+    /// already has one.
+    pub fn assign_message_id(&mut self) {
+        self.message_id_mut().get_or_insert_with(generate_message_id);
+    }
+
+    // The user id and the anonymous id of the message, or for an alias, its
+    // user id and its previous id
+    pub(crate) fn identity(&self) -> (Option<&str>, Option<&str>) {
+        match self {
+            BatchMessage::Identify(m) => (m.user_id.as_deref(), m.anonymous_id.as_deref()),
+            BatchMessage::Track(m) => (m.user_id.as_deref(), m.anonymous_id.as_deref()),
+            BatchMessage::Page(m) => (m.user_id.as_deref(), m.anonymous_id.as_deref()),
+            BatchMessage::Screen(m) => (m.user_id.as_deref(), m.anonymous_id.as_deref()),
+            BatchMessage::Group(m) => (m.user_id.as_deref(), m.anonymous_id.as_deref()),
+            BatchMessage::Alias(m) => (Some(&m.user_id), Some(&m.previous_id)),
+        }
+    }
+
+    pub(crate) fn context_mut(&mut self) -> &mut Option<Context> {
+        match self {
+            BatchMessage::Identify(m) => &mut m.context,
+            BatchMessage::Track(m) => &mut m.context,
+            BatchMessage::Page(m) => &mut m.context,
+            BatchMessage::Screen(m) => &mut m.context,
+            BatchMessage::Group(m) => &mut m.context,
+            BatchMessage::Alias(m) => &mut m.context,
+        }
+    }
+
+    pub(crate) fn original_timestamp_mut(&mut self) -> &mut Option<DateTime<Utc>> {
+        match self {
+            BatchMessage::Identify(m) => &mut m.original_timestamp,
+            BatchMessage::Track(m) => &mut m.original_timestamp,
+            BatchMessage::Page(m) => &mut m.original_timestamp,
+            BatchMessage::Screen(m) => &mut m.original_timestamp,
+            BatchMessage::Group(m) => &mut m.original_timestamp,
+            BatchMessage::Alias(m) => &mut m.original_timestamp,
+        }
+    }
+
+    pub(crate) fn message_id_mut(&mut self) -> &mut Option<String> {
+        match self {
+            BatchMessage::Identify(m) => &mut m.message_id,
+            BatchMessage::Track(m) => &mut m.message_id,
+            BatchMessage::Page(m) => &mut m.message_id,
+            BatchMessage::Screen(m) => &mut m.message_id,
+            BatchMessage::Group(m) => &mut m.message_id,
+            BatchMessage::Alias(m) => &mut m.message_id,
+        }
+    }
+}
+
+pub(crate) fn generate_message_id() -> String {
+    Uuid::new_v4().to_string()
+}
+
+// The rules checked by `validate`, for every event type
+pub(crate) trait Validate {
+    fn violations(&self) -> Vec<Error>;
+}
+
+impl Validate for Identify {
+    fn violations(&self) -> Vec<Error> {
+        let errors = check_identity(&self.user_id, &self.anonymous_id);
+        check_context(&self.context, errors)
+    }
+}
+
+impl Validate for Track {
+    fn violations(&self) -> Vec<Error> {
+        let mut errors = check_identity(&self.user_id, &self.anonymous_id);
+        check_not_empty(&self.event, "event", &mut errors);
+        check_context(&self.context, errors)
+    }
+}
+
+impl Validate for Page {
+    fn violations(&self) -> Vec<Error> {
+        let mut errors = check_identity(&self.user_id, &self.anonymous_id);
+        check_not_empty(&self.name, "name", &mut errors);
+        check_context(&self.context, errors)
+    }
+}
+
+impl Validate for Screen {
+    fn violations(&self) -> Vec<Error> {
+        let mut errors = check_identity(&self.user_id, &self.anonymous_id);
+        check_not_empty(&self.name, "name", &mut errors);
+        check_context(&self.context, errors)
+    }
+}
+
+impl Validate for Group {
+    fn violations(&self) -> Vec<Error> {
+        let mut errors = check_identity(&self.user_id, &self.anonymous_id);
+        check_not_empty(&self.group_id, "group_id", &mut errors);
+        check_context(&self.context, errors)
+    }
+}
+
+impl Validate for Alias {
+    fn violations(&self) -> Vec<Error> {
+        let mut errors = Vec::new();
+        check_not_empty(&self.user_id, "user_id", &mut errors);
+        check_not_empty(&self.previous_id, "previous_id", &mut errors);
+        if !self.user_id.is_empty() && self.user_id == self.previous_id {
+            errors.push(Error::AliasToSelf);
+        }
+        check_context(&self.context, errors)
+    }
+}
+
+impl Validate for Batch {
+    fn violations(&self) -> Vec<Error> {
+        let mut errors = check_context(&self.context, Vec::new());
+        let invalid: Vec<InvalidItem> = self
+            .batch
+            .iter()
+            .enumerate()
+            .filter_map(|(index, msg)| {
+                msg.validate()
+                    .err()
+                    .map(|errors| InvalidItem { index, errors })
+            })
+            .collect();
+        if !invalid.is_empty() {
+            errors.push(Error::InvalidBatchItems(invalid));
+        }
+        errors
+    }
+}
+
+// Empty ids count as missing
+fn check_identity(user_id: &Option<String>, anonymous_id: &Option<String>) -> Vec<Error> {
+    let missing = |id: &Option<String>| id.as_deref().unwrap_or_default().is_empty();
+    if missing(user_id) && missing(anonymous_id) {
+        vec![Error::MissingIdentity]
+    } else {
+        Vec::new()
+    }
+}
+
+fn check_not_empty(value: &str, field: &'static str, errors: &mut Vec<Error>) {
+    if value.is_empty() {
+        errors.push(Error::EmptyField(field));
+    }
+}
+
+// Checks for conflicts with reserved keywords
+pub(crate) fn check_context(context: &Option<Context>, mut errors: Vec<Error>) -> Vec<Error> {
+    if let Some(keyword) = context.as_ref().and_then(utils::check_reserved_keywords_conflict) {
+        errors.push(Error::ReservedKeyword(keyword.to_owned()));
+    }
+    errors
+}
+
+// The first violation, for callers reporting a single error
+pub(crate) fn first_violation(errors: Vec<Error>) -> Result<(), Error> {
+    match errors.into_iter().next() {
+        Some(err) => Err(err),
+        None => Ok(()),
+    }
+}
+
+fn into_result(errors: Vec<Error>) -> Result<(), Vec<Error>> {
+    if errors.is_empty() {
+        Ok(())
+    } else {
+        Err(errors)
+    }
+}
@@ -0,0 +1,102 @@
+//! Middlewares, enriching, rewriting or dropping messages before they are
+//! sent.
+//!
+//! The middlewares registered with
+//! [`RudderAnalyticsBuilder::middleware`](crate::client::RudderAnalyticsBuilder::middleware)
+//! run in order on every message, before it is validated and converted. A
+//! message sent on its own and each message of a batch are passed as a
+//! [`BatchMessage`]. The context shared by the messages of a batch is passed
+//! to [`Middleware::process_batch_context`].
+//!
+//! A [`QueuedRudderAnalytics`](crate::queue::QueuedRudderAnalytics) runs
+//! them once per message in `enqueue`, and once on its batch context when it
+//! is created.
+//!
+//! ```no_run
+//! use rudderanalytics::client::RudderAnalytics;
+//! use rudderanalytics::message::BatchMessage;
+//! use rudderanalytics::middleware::Verdict;
+//!
+//! let rudder_analytics = RudderAnalytics::builder("WRITE-KEY", "DATA-PLANE-URL")
+//!     .middleware(|msg: &mut BatchMessage| match msg {
+//!         BatchMessage::Track(track) if track.event.starts_with("internal.") => Verdict::Drop,
+//!         _ => Verdict::Keep,
+//!     })
+//!     .build()
+//!     .unwrap();
+//! ```
+
+use crate::context::Context;
+use crate::message::{BatchMessage, Message};
+use log::debug;
+use std::sync::Arc;
+
+/// Processes every message before it is sent.
+pub trait Middleware: Send + Sync {
+    /// Enrich or rewrite the message in place, and tell whether it should
+    /// still be sent.
+    fn process(&self, msg: &mut BatchMessage) -> Verdict;
+
+    /// Enrich or rewrite the context of a batch, which is sent along with
+    /// its messages. Does nothing by default.
+    fn process_batch_context(&self, _context: &mut Option<Context>) {}
+}
+
+impl<F> Middleware for F
+where
+    F: Fn(&mut BatchMessage) -> Verdict + Send + Sync,
+{
+    fn process(&self, msg: &mut BatchMessage) -> Verdict {
+        self(msg)
+    }
+}
+
+/// What happens to a message after a middleware processed it.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Verdict {
+    /// Pass the message on to the next middleware, and then send it.
+    Keep,
+
+    /// Do not send the message. The remaining middlewares are skipped, and
+    /// `send` succeeds without making a request for it. Within a batch, the
+    /// message is reported in
+    /// [`BatchOutcome::dropped`](crate::client::BatchOutcome::dropped).
+    Drop,
+}
+
+// Runs the middlewares on a message of a batch, stopping at the first one
+// which drops it
+pub(crate) fn run(middlewares: &[Arc<dyn Middleware>], msg: &mut BatchMessage) -> Verdict {
+    for middleware in middlewares {
+        if middleware.process(msg) == Verdict::Drop {
+            debug!("message dropped by middleware");
+            return Verdict::Drop;
+        }
+    }
+    Verdict::Keep
+}
+
+// Runs the middlewares on a message sent on its own. Returns None if it was
+// dropped.
+pub(crate) fn run_single(middlewares: &[Arc<dyn Middleware>], msg: &Message) -> Option<Message> {
+    let mut item = match msg.clone() {
+        Message::Identify(m) => BatchMessage::Identify(m),
+        Message::Track(m) => BatchMessage::Track(m),
+        Message::Page(m) => BatchMessage::Page(m),
+        Message::Screen(m) => BatchMessage::Screen(m),
+        Message::Group(m) => BatchMessage::Group(m),
+        Message::Alias(m) => BatchMessage::Alias(m),
+        msg @ Message::Batch(_) => return Some(msg),
+    };
+    if run(middlewares, &mut item) == Verdict::Drop {
+        return None;
+    }
+    Some(match item {
+        BatchMessage::Identify(m) => Message::Identify(m),
+        BatchMessage::Track(m) => Message::Track(m),
+        BatchMessage::Page(m) => Message::Page(m),
+        BatchMessage::Screen(m) => Message::Screen(m),
+        BatchMessage::Group(m) => Message::Group(m),
+        BatchMessage::Alias(m) => Message::Alias(m),
+    })
+}
@@ -0,0 +1,433 @@
+//! A queued client which batches messages and sends them from a background
+//! worker thread.
+
+use crate::batcher::Batcher;
+use crate::client::{BatchValidation, RudderAnalytics};
+use crate::context::Context;
+use crate::disk_queue::{DiskQueue, PersistenceConfig};
+use crate::errors::Error;
+use crate::message::{self, BatchMessage, Message};
+use crate::middleware::{self, Middleware, Verdict};
+use chrono::Utc;
+use log::{debug, error};
+use std::sync::mpsc::{self, Receiver, RecvTimeoutError, SyncSender, TrySendError};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
+use std::thread::{self, JoinHandle};
+use std::time::{Duration, Instant};
+
+/// Settings for a [`QueuedRudderAnalytics`] client.
+#[derive(Debug, Clone)]
+pub struct QueueConfig {
+    /// The maximum number of messages waiting to be picked up by the worker.
+    pub capacity: usize,
+
+    /// The maximum number of messages sent in a single batch.
+    pub batch_size: usize,
+
+    /// How long the worker waits before sending a batch which is not full.
+    pub flush_interval: Duration,
+
+    /// Context set on every batch sent by the worker.
+    pub context: Option<Context>,
+
+    /// Persist queued messages to disk, so that they survive a restart.
+    pub persistence: Option<PersistenceConfig>,
+}
+
+impl Default for QueueConfig {
+    fn default() -> Self {
+        Self {
+            capacity: 10_000,
+            batch_size: 100,
+            flush_interval: Duration::from_secs(10),
+            context: None,
+            persistence: None,
+        }
+    }
+}
+
+enum Command {
+    Message(Box<BatchMessage>),
+    Flush(mpsc::Sender<Result<(), Error>>),
+    Shutdown,
+}
+
+/// A client which hands messages to a background worker instead of sending
+/// them on the caller's thread.
+///
+/// The worker collects messages into a [`Batcher`] and sends them to
+/// `/v1/batch` once the batch is full or `flush_interval` has expired,
+/// whichever comes first. Dropping the client flushes all buffered messages
+/// and waits for the worker to finish.
+///
+/// When the data plane rate limits the client, the batch is kept and the
+/// worker holds off sending until the requested `Retry-After` delay has
+/// passed. Other retryable failures, e.g. network errors or `5xx` responses
+/// which outlast the retry policy, keep the batch as well, and it is sent
+/// again after the policy's `max_delay`. Meanwhile, new messages keep being
+/// buffered; once the buffer is full, the worker waits and `enqueue`
+/// eventually reports a full queue. If the client is shut down while the
+/// worker is still waiting, the messages which did not fit are not sent, and
+/// with persistence, they are sent on the next start.
+///
+/// With `persistence` set, messages are written to disk before `enqueue`
+/// accepts them, and removed once they have been delivered or given up on
+/// after a permanent failure, e.g. handed to the client's dead-letter sink.
+/// Messages left over from a previous run are sent first.
+///
+/// ```no_run
+/// use rudderanalytics::client::RudderAnalytics;
+/// use rudderanalytics::message::{BatchMessage, Track};
+/// use rudderanalytics::queue::QueueConfig;
+///
+/// let rudder_analytics = RudderAnalytics::load(
+///     "WRITE-KEY".to_string(),
+///     "DATA-PLANE-URL".to_string(),
+/// )
+/// .queued(QueueConfig::default())
+/// .unwrap();
+///
+/// rudder_analytics
+///     .enqueue(BatchMessage::Track(Track {
+///         user_id: Some("user-1".to_owned()),
+///         event: "Example".to_owned(),
+///         ..Default::default()
+///     }))
+///     .unwrap();
+///
+/// rudder_analytics.shutdown();
+/// ```
+pub struct QueuedRudderAnalytics {
+    sender: SyncSender<Command>,
+    worker: Option<JoinHandle<()>>,
+    closing: Arc<AtomicBool>,
+    disk: Option<Arc<Mutex<DiskQueue>>>,
+    middlewares: Vec<Arc<dyn Middleware>>,
+}
+
+impl RudderAnalytics {
+    /// Move this client onto a background worker thread and return a queued
+    /// client feeding it.
+    pub fn queued(self, config: QueueConfig) -> Result<QueuedRudderAnalytics, Error> {
+        QueuedRudderAnalytics::new(self, config)
+    }
+}
+
+impl QueuedRudderAnalytics {
+    /// Spawn the worker thread for the given client.
+    ///
+    /// Fails if the persistence directory cannot be opened, or the thread
+    /// cannot be spawned.
+    pub fn new(mut client: RudderAnalytics, mut config: QueueConfig) -> Result<Self, Error> {
+        // The middlewares run in `enqueue`, and the worker's batches share
+        // the same context, so the worker has none left to run
+        let middlewares = std::mem::take(&mut client.middlewares);
+        for middleware in &middlewares {
+            middleware.process_batch_context(&mut config.context);
+        }
+
+        let (disk, replay) = match config.persistence.clone() {
+            Some(persistence) => {
+                let (disk, replay) = DiskQueue::open(persistence)?;
+                (Some(Arc::new(Mutex::new(disk))), replay)
+            }
+            None => (None, Vec::new()),
+        };
+
+        let (sender, receiver) = mpsc::sync_channel(config.capacity);
+        let closing = Arc::new(AtomicBool::new(false));
+        let worker = Worker::new(client, config, disk.clone(), closing.clone());
+        let worker = thread::Builder::new()
+            .name("rudderanalytics-queue".to_owned())
+            .spawn(move || worker.run(replay, receiver))?;
+
+        Ok(Self {
+            sender,
+            worker: Some(worker),
+            closing,
+            disk,
+            middlewares,
+        })
+    }
+
+    /// Queue a message to be sent by the worker.
+    ///
+    /// The client's middlewares run first; a message they drop is neither
+    /// queued nor persisted, and `Ok` is returned for it.
+    ///
+    /// This never blocks on the worker: an error is returned if the message
+    /// is invalid, the queue is full or the worker has stopped. With
+    /// persistence enabled, the message is written to disk first.
+    pub fn enqueue(&self, mut msg: BatchMessage) -> Result<(), Error> {
+        // Run here rather than by the worker, so that rate limits see the
+        // messages as they come in, and batches are sized on what is sent
+        if middleware::run(&self.middlewares, &mut msg) == Verdict::Drop {
+            return Ok(());
+        }
+
+        // Rejected here, as it would fail the whole batch otherwise
+        msg.validate().or_else(message::first_violation)?;
+
+        // Assigned here, so that a replayed message keeps its id, and is
+        // timed when it was enqueued rather than when its batch is sent
+        msg.assign_message_id();
+        msg.original_timestamp_mut().get_or_insert_with(Utc::now);
+
+        // The lock is held until the message has been handed to the worker,
+        // so that the worker sees messages in the order they were written.
+        let mut disk = match &self.disk {
+            Some(disk) => {
+                let mut disk = disk.lock().map_err(|_| Error::QueueClosed)?;
+                disk.append(&msg)?;
+                Some(disk)
+            }
+            None => None,
+        };
+
+        let result = self
+            .sender
+            .try_send(Command::Message(Box::new(msg)))
+            .map_err(|err| match err {
+                TrySendError::Full(_) => Error::QueueFull,
+                TrySendError::Disconnected(_) => Error::QueueClosed,
+            });
+        if let (Err(_), Some(disk)) = (&result, disk.as_mut()) {
+            disk.unappend()?;
+        }
+        result
+    }
+
+    /// Send every message queued so far, and wait until the worker is done.
+    pub fn flush(&self) -> Result<(), Error> {
+        let (reply, result) = mpsc::channel();
+        self.sender
+            .send(Command::Flush(reply))
+            .map_err(|_| Error::QueueClosed)?;
+        result.recv().map_err(|_| Error::QueueClosed)?
+    }
+
+    /// Send every message queued so far and stop the worker.
+    pub fn shutdown(mut self) {
+        self.close();
+    }
+
+    fn close(&mut self) {
+        if let Some(worker) = self.worker.take() {
+            // Tells a worker waiting for the data plane to give up
+            self.closing.store(true, Ordering::SeqCst);
+            // The worker stops on its own once the channel is disconnected,
+            // so a failed send needs no handling.
+            let _ = self.sender.send(Command::Shutdown);
+            if worker.join().is_err() {
+                error!("rudderanalytics queue worker panicked");
+            }
+        }
+    }
+}
+
+impl Drop for QueuedRudderAnalytics {
+    fn drop(&mut self) {
+        self.close();
+    }
+}
+
+// State owned by the worker thread
+struct Worker {
+    client: RudderAnalytics,
+    config: QueueConfig,
+    batcher: Batcher,
+    deadline: Instant,
+    // a batch which failed with a retryable error, waiting to be sent again
+    pending: Option<Message>,
+    paused_until: Option<Instant>,
+    // whether the pause was requested by the data plane
+    rate_limited: bool,
+    disk: Option<Arc<Mutex<DiskQueue>>>,
+    // the number of persisted records making up the pending message and the
+    // current batch, including messages which were dropped
+    pending_records: usize,
+    batch_records: usize,
+    // set when the client is dropped or shut down
+    closing: Arc<AtomicBool>,
+    // whether the worker stopped taking messages while shutting down
+    given_up: bool,
+}
+
+impl Worker {
+    fn new(
+        client: RudderAnalytics,
+        config: QueueConfig,
+        disk: Option<Arc<Mutex<DiskQueue>>>,
+        closing: Arc<AtomicBool>,
+    ) -> Self {
+        Self {
+            batcher: Batcher::new(config.context.clone()),
+            deadline: Instant::now() + config.flush_interval,
+            pending: None,
+            paused_until: None,
+            rate_limited: false,
+            disk,
+            pending_records: 0,
+            batch_records: 0,
+            closing,
+            given_up: false,
+            client,
+            config,
+        }
+    }
+
+    fn run(mut self, replay: Vec<BatchMessage>, receiver: Receiver<Command>) {
+        if !replay.is_empty() {
+            debug!("replaying {} persisted messages", replay.len());
+        }
+        for msg in replay {
+            self.push(msg);
+        }
+
+        loop {
+            let timeout = self.deadline.saturating_duration_since(Instant::now());
+            match receiver.recv_timeout(timeout) {
+                Ok(Command::Message(msg)) => self.push(*msg),
+                Ok(Command::Flush(reply)) => {
+                    // The caller may have given up waiting
+                    let _ = reply.send(self.flush());
+                }
+                Ok(Command::Shutdown) | Err(RecvTimeoutError::Disconnected) => {
+                    self.wait_until_resumed();
+                    self.flush_and_log();
+                    break;
+                }
+                Err(RecvTimeoutError::Timeout) => self.flush_and_log(),
+            }
+        }
+    }
+
+    fn push(&mut self, mut msg: BatchMessage) {
+        // Once a message was left out, later ones are too, so that the
+        // batches are still made of the oldest records on disk
+        if self.given_up {
+            error!("dropping message, the data plane is unavailable at shutdown");
+            return;
+        }
+
+        let mut flushed = false;
+        loop {
+            match self.batcher.push(msg) {
+                Ok(None) => break,
+                Ok(Some(rejected)) => {
+                    // The batch is full, and has to be sent before the
+                    // message fits. This blocks until the data plane accepts
+                    // it, so that new messages wait in the channel, unless
+                    // the client is shutting down.
+                    if flushed && self.closing.load(Ordering::SeqCst) {
+                        error!("dropping message, the data plane is unavailable at shutdown");
+                        self.given_up = true;
+                        return;
+                    }
+                    self.wait_until_resumed();
+                    self.flush_and_log();
+                    flushed = true;
+                    msg = rejected;
+                }
+                Err(err) => {
+                    error!("dropping message: {}", err);
+                    break;
+                }
+            }
+        }
+        self.batch_records += 1;
+
+        if self.batcher.len() >= self.config.batch_size && self.paused_until.is_none() {
+            self.flush_and_log();
+        }
+    }
+
+    fn flush(&mut self) -> Result<(), Error> {
+        if let Some(paused_until) = self.paused_until {
+            let retry_after = paused_until.saturating_duration_since(Instant::now());
+            // Only the data plane's delay is binding, an explicit flush may
+            // try again sooner after other failures
+            if !retry_after.is_zero() && self.rate_limited {
+                return Err(Error::RateLimited {
+                    retry_after: Some(retry_after),
+                });
+            }
+            self.paused_until = None;
+        }
+
+        self.deadline = Instant::now() + self.config.flush_interval;
+        if let Some(msg) = self.pending.take() {
+            let records = std::mem::take(&mut self.pending_records);
+            self.send(msg, records)?;
+        }
+        let records = std::mem::take(&mut self.batch_records);
+        if self.batcher.is_empty() {
+            // Only dropped messages are left
+            self.ack(records);
+            return Ok(());
+        }
+
+        let batcher =
+            std::mem::replace(&mut self.batcher, Batcher::new(self.config.context.clone()));
+        debug!("flushing {} queued messages", batcher.len());
+        self.send(batcher.into_message(), records)
+    }
+
+    fn send(&mut self, msg: Message, records: usize) -> Result<(), Error> {
+        // A few invalid messages, e.g. replayed from an older version, must
+        // not hold back the rest of the batch
+        let result = self
+            .client
+            .send_with(&msg, BatchValidation::DropInvalid, true)
+            .map(|delivery| {
+                for item in delivery.batch.iter().flat_map(|batch| &batch.rejected) {
+                    let errors: Vec<_> = item.errors.iter().map(ToString::to_string).collect();
+                    error!("dropping invalid message: {}", errors.join(", "));
+                }
+            });
+        match &result {
+            Err(err) if err.is_retryable() => {
+                let delay = match err {
+                    Error::RateLimited { retry_after } => {
+                        retry_after.unwrap_or(self.config.flush_interval)
+                    }
+                    _ => self.client.retry_policy.max_delay,
+                };
+                let paused_until = Instant::now() + delay;
+                self.paused_until = Some(paused_until);
+                self.rate_limited = matches!(err, Error::RateLimited { .. });
+                self.deadline = paused_until;
+                self.pending = Some(msg);
+                self.pending_records = records;
+            }
+            // Delivered, or given up on
+            _ => self.ack(records),
+        }
+        result
+    }
+
+    fn ack(&self, records: usize) {
+        if let Some(disk) = &self.disk {
+            let result = match disk.lock() {
+                Ok(mut disk) => disk.ack(records),
+                Err(_) => Err(Error::QueueClosed),
+            };
+            if let Err(err) = result {
+                error!("failed to remove sent messages from disk: {}", err);
+            }
+        }
+    }
+
+    fn wait_until_resumed(&self) {
+        if let Some(paused_until) = self.paused_until {
+            thread::sleep(paused_until.saturating_duration_since(Instant::now()));
+        }
+    }
+
+    fn flush_and_log(&mut self) {
+        if let Err(err) = self.flush() {
+            error!("failed to send queued messages: {}", err);
+        }
+    }
+}
@@ -0,0 +1,538 @@
+//! Redaction of personal data from traits, properties and contexts before
+//! they are sent.
+//!
+//! A [`Redactor`] is a [`Middleware`] applying rules to the values at given
+//! paths, and optionally scanning the strings of traits and properties for
+//! email addresses, phone numbers and card numbers:
+//!
+//! ```no_run
+//! use rudderanalytics::client::RudderAnalytics;
+//! use rudderanalytics::redaction::{Detector, Redaction, Redactor};
+//!
+//! let redactor = Redactor::builder()
+//!     .rule("/traits/email", Redaction::Hash { salt: "SALT".to_owned() })
+//!     .rule("context.ip", Redaction::Drop)
+//!     .rule("properties.**.phone", Redaction::Mask)
+//!     .detect(Detector::Email, Redaction::Mask)
+//!     .build()
+//!     .unwrap();
+//!
+//! let rudder_analytics = RudderAnalytics::builder("WRITE-KEY", "DATA-PLANE-URL")
+//!     .middleware(redactor)
+//!     .build()
+//!     .unwrap();
+//! ```
+//!
+//! Paths are either JSON pointers, e.g. `/properties/user/email`, or dotted
+//! paths, e.g. `properties.user.email`. They start with `traits`,
+//! `properties` or `context`, and every other segment may contain `*`
+//! wildcards, matching object keys and array indexes. A `**` segment matches
+//! any number of levels.
+//!
+//! Middlewares run in the order they were added; add the redactor last so
+//! that it also covers what other middlewares add.
+
+use crate::context::Context;
+use crate::errors::Error;
+use crate::message::BatchMessage;
+use crate::middleware::{Middleware, Verdict};
+use log::warn;
+use regex::Regex;
+use serde::Deserialize;
+use serde_json::{Map, Value};
+use sha2::{Digest, Sha256};
+use std::convert::TryFrom;
+
+// What masked values are replaced with
+const MASK: &str = "***";
+
+/// How a value is redacted.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Redaction {
+    /// Remove the value.
+    Drop,
+
+    /// Replace the value with `***`.
+    Mask,
+
+    /// Replace the value with the hex-encoded SHA-256 hash of the salt
+    /// followed by the value, so that equal values can still be matched
+    /// downstream. Values other than strings are hashed as JSON.
+    Hash { salt: String },
+}
+
+/// Personal data found in free text.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Detector {
+    /// Email addresses.
+    Email,
+
+    /// Phone numbers, in international format or as ten digit numbers.
+    Phone,
+
+    /// Payment card numbers, with a valid Luhn checksum.
+    CardNumber,
+}
+
+impl Detector {
+    fn pattern(self) -> &'static str {
+        match self {
+            Detector::Email => r"[A-Za-z0-9._%+-]+@[A-Za-z0-9.-]+\.[A-Za-z]{2,}",
+            Detector::Phone => {
+                r"\+\d(?:[ .-]?\d){6,14}\b|(?:\(\d{3}\)|\b\d{3})[ .-]?\d{3}[ .-]?\d{4}\b"
+            }
+            Detector::CardNumber => r"\b\d(?:[ -]?\d){11,18}\b",
+        }
+    }
+
+    // Rules out false positives of the pattern
+    fn accepts(self, text: &str) -> bool {
+        match self {
+            Detector::CardNumber => luhn(text),
+            _ => true,
+        }
+    }
+}
+
+/// A builder for [`Redactor`]s.
+#[derive(Debug, Clone, Default)]
+pub struct RedactorBuilder {
+    rules: Vec<(String, Redaction)>,
+    detectors: Vec<(Detector, Redaction)>,
+}
+
+impl RedactorBuilder {
+    /// Redact the values at the given path.
+    pub fn rule(mut self, path: impl Into<String>, redaction: Redaction) -> Self {
+        self.rules.push((path.into(), redaction));
+        self
+    }
+
+    /// Redact what the detector finds in the strings of traits and
+    /// properties. The matches are masked or hashed within the string; with
+    /// [`Redaction::Drop`], the whole string is removed.
+    pub fn detect(mut self, detector: Detector, redaction: Redaction) -> Self {
+        self.detectors.push((detector, redaction));
+        self
+    }
+
+    /// Build the redactor. Fails if a path is invalid.
+    pub fn build(self) -> Result<Redactor, Error> {
+        let rules = self
+            .rules
+            .into_iter()
+            .map(|(path, redaction)| Rule::parse(&path, redaction))
+            .collect::<Result<_, _>>()?;
+        let detectors = self
+            .detectors
+            .into_iter()
+            .map(|(detector, redaction)| {
+                let regex = Regex::new(detector.pattern()).expect("invalid detector pattern");
+                (detector, regex, redaction)
+            })
+            .collect();
+        Ok(Redactor { rules, detectors })
+    }
+}
+
+/// Redacts personal data from messages.
+#[derive(Debug)]
+pub struct Redactor {
+    rules: Vec<Rule>,
+    detectors: Vec<(Detector, Regex, Redaction)>,
+}
+
+impl Redactor {
+    /// Start configuring a redactor.
+    pub fn builder() -> RedactorBuilder {
+        RedactorBuilder::default()
+    }
+
+    /// Redact a message in place.
+    pub fn redact(&self, msg: &mut BatchMessage) {
+        let (traits, properties, context) = match msg {
+            BatchMessage::Identify(m) => (Some(&mut m.traits), None, &mut m.context),
+            BatchMessage::Track(m) => (None, Some(&mut m.properties), &mut m.context),
+            BatchMessage::Page(m) => (None, Some(&mut m.properties), &mut m.context),
+            BatchMessage::Screen(m) => (None, Some(&mut m.properties), &mut m.context),
+            BatchMessage::Group(m) => (Some(&mut m.traits), None, &mut m.context),
+            BatchMessage::Alias(m) => (Some(&mut m.traits), None, &mut m.context),
+        };
+        if let Some(traits) = traits {
+            self.redact_field(Root::Traits, traits);
+        }
+        if let Some(properties) = properties {
+            self.redact_field(Root::Properties, properties);
+        }
+        self.redact_context(context);
+    }
+
+    /// Redact a context in place.
+    ///
+    /// The context is redacted as JSON. A standard key which no longer has
+    /// the expected type afterwards is removed, e.g. `context.screen.width`
+    /// once it was hashed, or `context.page` once it was masked. The context
+    /// is only dropped if it is no longer an object.
+    pub fn redact_context(&self, context: &mut Option<Context>) {
+        let value = match context {
+            Some(value) if self.rules.iter().any(|rule| rule.root == Root::Context) => value,
+            _ => return,
+        };
+        let mut value = serde_json::to_value(&*value).ok();
+        for rule in self.rules.iter().filter(|rule| rule.root == Root::Context) {
+            rule.apply(&mut value);
+        }
+        *context = value.and_then(|mut value| {
+            if Context::deserialize(&value).is_err() {
+                remove_invalid_keys(&mut value);
+            }
+            match Context::try_from(value) {
+                Ok(context) => Some(context),
+                Err(err) => {
+                    warn!(
+                        "dropping the context, it is invalid after redaction: {}",
+                        err
+                    );
+                    None
+                }
+            }
+        });
+    }
+
+    fn redact_field(&self, root: Root, field: &mut Option<Value>) {
+        for rule in self.rules.iter().filter(|rule| rule.root == root) {
+            rule.apply(field);
+        }
+        if self.detectors.is_empty() {
+            return;
+        }
+        if let Some(value) = field {
+            if !self.scan(value) {
+                *field = None;
+            }
+        }
+    }
+
+    // Runs the detectors on the strings within the value, false if it has to
+    // be dropped
+    fn scan(&self, value: &mut Value) -> bool {
+        match value {
+            Value::String(text) => match self.scrub(text) {
+                Scrubbed::Clean => true,
+                Scrubbed::Redacted(scrubbed) => {
+                    *text = scrubbed;
+                    true
+                }
+                Scrubbed::Drop => false,
+            },
+            Value::Object(map) => {
+                map.retain(|_, value| self.scan(value));
+                true
+            }
+            Value::Array(items) => {
+                items.retain_mut(|value| self.scan(value));
+                true
+            }
+            _ => true,
+        }
+    }
+
+    fn scrub(&self, text: &str) -> Scrubbed {
+        let mut matches = Vec::new();
+        for (detector, regex, redaction) in &self.detectors {
+            for m in regex.find_iter(text) {
+                if detector.accepts(m.as_str()) {
+                    matches.push((m.start(), m.end(), redaction));
+                }
+            }
+        }
+        if matches.is_empty() {
+            return Scrubbed::Clean;
+        }
+        // longest match first where detectors overlap
+        matches.sort_by_key(|&(start, end, _)| (start, std::cmp::Reverse(end)));
+
+        let mut scrubbed = String::with_capacity(text.len());
+        let mut last = 0;
+        for (start, end, redaction) in matches {
+            if start < last {
+                continue;
+            }
+            scrubbed.push_str(&text[last..start]);
+            match redaction {
+                Redaction::Drop => return Scrubbed::Drop,
+                Redaction::Mask => scrubbed.push_str(MASK),
+                Redaction::Hash { salt } => scrubbed.push_str(&hash(salt, &text[start..end])),
+            }
+            last = end;
+        }
+        scrubbed.push_str(&text[last..]);
+        Scrubbed::Redacted(scrubbed)
+    }
+}
+
+// What the detectors found in a string
+enum Scrubbed {
+    Clean,
+    Redacted(String),
+    Drop,
+}
+
+impl Middleware for Redactor {
+    fn process(&self, msg: &mut BatchMessage) -> Verdict {
+        self.redact(msg);
+        Verdict::Keep
+    }
+
+    fn process_batch_context(&self, context: &mut Option<Context>) {
+        self.redact_context(context);
+    }
+}
+
+// The part of a message a rule applies to
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Root {
+    Traits,
+    Properties,
+    Context,
+}
+
+#[derive(Debug)]
+enum Segment {
+    Key(String),
+    Pattern(String),
+    AnyDepth,
+}
+
+impl Segment {
+    fn matches(&self, key: &str) -> bool {
+        match self {
+            Segment::Key(k) => k == key,
+            Segment::Pattern(pattern) => glob_match(pattern, key),
+            Segment::AnyDepth => true,
+        }
+    }
+}
+
+#[derive(Debug)]
+struct Rule {
+    root: Root,
+    path: Vec<Segment>,
+    redaction: Redaction,
+}
+
+impl Rule {
+    fn parse(path: &str, redaction: Redaction) -> Result<Self, Error> {
+        let invalid = |reason: &str| {
+            Error::InvalidConfig(format!("invalid redaction path `{}`: {}", path, reason))
+        };
+        let mut segments: Vec<String> = match path.strip_prefix('/') {
+            Some(pointer) => pointer
+                .split('/')
+                .map(|segment| segment.replace("~1", "/").replace("~0", "~"))
+                .collect(),
+            None => path.split('.').map(str::to_owned).collect(),
+        };
+        if segments.iter().any(String::is_empty) {
+            return Err(invalid("empty segment"));
+        }
+        let root = match segments.remove(0).as_str() {
+            "traits" => Root::Traits,
+            "properties" => Root::Properties,
+            "context" => Root::Context,
+            _ => return Err(invalid("must start with traits, properties or context")),
+        };
+        let path = segments
+            .into_iter()
+            .map(|segment| {
+                if segment == "**" {
+                    Segment::AnyDepth
+                } else if segment.contains('*') {
+                    Segment::Pattern(segment)
+                } else {
+                    Segment::Key(segment)
+                }
+            })
+            .collect();
+        Ok(Self {
+            root,
+            path,
+            redaction,
+        })
+    }
+
+    fn apply(&self, field: &mut Option<Value>) {
+        if self.path.is_empty() {
+            match self.redaction {
+                Redaction::Drop => *field = None,
+                _ => {
+                    if let Some(value) = field {
+                        redact_value(value, &self.redaction);
+                    }
+                }
+            }
+        } else if let Some(value) = field {
+            redact_path(value, &self.path, &self.redaction);
+        }
+    }
+}
+
+// Redacts the values below the given one which match the path
+fn redact_path(value: &mut Value, path: &[Segment], redaction: &Redaction) {
+    let (segment, rest) = match path.split_first() {
+        Some(split) => split,
+        None => return,
+    };
+    if let Segment::AnyDepth = segment {
+        redact_path(value, rest, redaction);
+        match value {
+            Value::Object(map) => map
+                .values_mut()
+                .for_each(|value| redact_path(value, path, redaction)),
+            Value::Array(items) => items
+                .iter_mut()
+                .for_each(|value| redact_path(value, path, redaction)),
+            _ => {}
+        }
+        return;
+    }
+
+    let drop = rest.is_empty() && *redaction == Redaction::Drop;
+    match value {
+        Value::Object(map) => {
+            if drop {
+                map.retain(|key, _| !segment.matches(key));
+                return;
+            }
+            for (key, value) in map.iter_mut() {
+                if segment.matches(key) {
+                    redact_child(value, rest, redaction);
+                }
+            }
+        }
+        Value::Array(items) => {
+            if drop {
+                let mut index = 0;
+                items.retain(|_| {
+                    index += 1;
+                    !segment.matches(&(index - 1).to_string())
+                });
+                return;
+            }
+            for (index, value) in items.iter_mut().enumerate() {
+                if segment.matches(&index.to_string()) {
+                    redact_child(value, rest, redaction);
+                }
+            }
+        }
+        _ => {}
+    }
+}
+
+fn redact_child(value: &mut Value, rest: &[Segment], redaction: &Redaction) {
+    if rest.is_empty() {
+        redact_value(value, redaction);
+    } else {
+        redact_path(value, rest, redaction);
+    }
+}
+
+// Removes the keys of a context which do not have the expected type, or the
+// fields of a standard object such as `screen` if only they are invalid
+fn remove_invalid_keys(context: &mut Value) {
+    let context = match context.as_object_mut() {
+        Some(context) => context,
+        None => return,
+    };
+    let keys: Vec<String> = context.keys().cloned().collect();
+    for key in keys {
+        if fits(&key, &context[&key]) {
+            continue;
+        }
+        if let Some(object) = context[&key].as_object_mut() {
+            let fields: Vec<String> = object.keys().cloned().collect();
+            for field in fields {
+                let mut single = Map::new();
+                single.insert(field.clone(), object[&field].clone());
+                if !fits(&key, &Value::Object(single)) {
+                    warn!("removing context.{}.{}, it is invalid after redaction", key, field);
+                    object.remove(&field);
+                }
+            }
+        }
+        if !fits(&key, &context[&key]) {
+            warn!("removing context.{}, it is invalid after redaction", key);
+            context.remove(&key);
+        }
+    }
+}
+
+// Whether a context made of only the given key is valid
+fn fits(key: &str, value: &Value) -> bool {
+    let mut context = Map::new();
+    context.insert(key.to_owned(), value.clone());
+    Context::deserialize(&Value::Object(context)).is_ok()
+}
+
+// Masks or hashes a value, leaving nulls alone
+fn redact_value(value: &mut Value, redaction: &Redaction) {
+    if value.is_null() {
+        return;
+    }
+    match redaction {
+        Redaction::Drop => *value = Value::Null,
+        Redaction::Mask => *value = Value::String(MASK.to_owned()),
+        Redaction::Hash { salt } => {
+            let hashed = match &*value {
+                Value::String(text) => hash(salt, text),
+                value => hash(salt, &value.to_string()),
+            };
+            *value = Value::String(hashed);
+        }
+    }
+}
+
+fn hash(salt: &str, text: &str) -> String {
+    let mut hasher = Sha256::new();
+    hasher.update(salt.as_bytes());
+    hasher.update(text.as_bytes());
+    format!("{:x}", hasher.finalize())
+}
+
+// Matches a key against a pattern where `*` stands for any number of
+// characters
+fn glob_match(pattern: &str, key: &str) -> bool {
+    let mut parts = pattern.split('*');
+    let first = parts.next().unwrap_or_default();
+    let mut rest = match key.strip_prefix(first) {
+        Some(rest) => rest,
+        None => return false,
+    };
+    let parts: Vec<_> = parts.collect();
+    for (i, part) in parts.iter().enumerate() {
+        if i == parts.len() - 1 {
+            return rest.ends_with(part);
+        }
+        match rest.find(part) {
+            Some(at) => rest = &rest[at + part.len()..],
+            None => return false,
+        }
+    }
+    rest.is_empty()
+}
+
+// Checks the Luhn checksum of a card number
+fn luhn(text: &str) -> bool {
+    let digits: Vec<u32> = text.chars().filter_map(|c| c.to_digit(10)).collect();
+    let sum: u32 = digits
+        .iter()
+        .rev()
+        .enumerate()
+        .map(|(i, &digit)| match (i % 2, digit * 2) {
+            (0, _) => digit,
+            (_, doubled) if doubled > 9 => doubled - 9,
+            (_, doubled) => doubled,
+        })
+        .sum();
+    sum.is_multiple_of(10)
+}
@@ -0,0 +1,97 @@
+//! Retrying of transient delivery failures.
+
+use chrono::{DateTime, Utc};
+use reqwest::header::{HeaderMap, RETRY_AFTER};
+use std::time::Duration;
+
+/// Controls how often, and how far apart, a failed send is retried.
+///
+/// Sends are retried on network errors, `429 Too Many Requests` and `5xx`
+/// responses. Any other response, such as a `400` for an invalid payload, is
+/// returned straight away.
+///
+/// The delay before retry `n` is `base_delay * 2^(n - 1)`, capped at
+/// `max_delay`. With a `jitter` of `j`, up to `j` of that delay is removed at
+/// random, so that many clients failing at once do not retry in lockstep.
+///
+/// When the data plane rate limits the client with a `Retry-After` header,
+/// the next attempt waits at least that long. If it asks for more than
+/// `max_delay`, no further attempts are made and
+/// [`Error::RateLimited`](crate::errors::Error::RateLimited) is returned.
+#[derive(Debug, Clone, PartialEq)]
+pub struct RetryPolicy {
+    /// The maximum number of attempts, including the first one.
+    pub max_attempts: u32,
+
+    /// The delay before the first retry.
+    pub base_delay: Duration,
+
+    /// The upper bound for the delay between two attempts.
+    pub max_delay: Duration,
+
+    /// The fraction of the delay, between `0.0` and `1.0`, which is
+    /// randomized.
+    pub jitter: f64,
+}
+
+impl Default for RetryPolicy {
+    fn default() -> Self {
+        Self {
+            max_attempts: 3,
+            base_delay: Duration::from_millis(100),
+            max_delay: Duration::from_secs(5),
+            jitter: 0.5,
+        }
+    }
+}
+
+impl RetryPolicy {
+    /// A policy which sends every message exactly once.
+    pub fn none() -> Self {
+        Self {
+            max_attempts: 1,
+            ..Default::default()
+        }
+    }
+
+    /// Returns the delay to wait after the given failed attempt, counting
+    /// from 1.
+    pub fn delay(&self, attempt: u32) -> Duration {
+        let exponent = attempt.saturating_sub(1).min(31);
+        let delay = self
+            .base_delay
+            .checked_mul(1 << exponent)
+            .unwrap_or(self.max_delay)
+            .min(self.max_delay);
+
+        let jitter = self.jitter.clamp(0.0, 1.0);
+        delay.mul_f64(1.0 - jitter * rand::random::<f64>())
+    }
+}
+
+/// Parses the value of a `Retry-After` header, given either as a number of
+/// seconds or as an HTTP date.
+///
+/// Dates in the past yield a zero duration.
+pub fn parse_retry_after(value: &str) -> Option<Duration> {
+    let value = value.trim();
+    if let Ok(seconds) = value.parse::<u64>() {
+        return Some(Duration::from_secs(seconds));
+    }
+
+    let date = DateTime::parse_from_rfc2822(value).ok()?;
+    Some(
+        date.with_timezone(&Utc)
+            .signed_duration_since(Utc::now())
+            .to_std()
+            .unwrap_or(Duration::ZERO),
+    )
+}
+
+// Reads the Retry-After header of a response
+pub(crate) fn retry_after(headers: &HeaderMap) -> Option<Duration> {
+    headers
+        .get(RETRY_AFTER)
+        .and_then(|value| value.to_str().ok())
+        .and_then(parse_retry_after)
+}
@@ -51,6 +51,10 @@ pub struct Identify {
     /// channel in payload
     pub channel: String,
 
+    /// unique id of the message
+    #[serde(rename = "messageId")]
+    pub message_id: String,
+
 }
 
 /// A track event.
@@ -93,6 +97,10 @@ pub struct Track {
     /// channel in payload
     pub channel: String,
 
+    /// unique id of the message
+    #[serde(rename = "messageId")]
+    pub message_id: String,
+
 }
 
 /// A page event.
@@ -135,6 +143,10 @@ pub struct Page {
     /// channel in payload
     pub channel: String,
 
+    /// unique id of the message
+    #[serde(rename = "messageId")]
+    pub message_id: String,
+
 }
 
 /// A screen event.
@@ -177,6 +189,10 @@ pub struct Screen {
     /// channel in payload
     pub channel: String,
 
+    /// unique id of the message
+    #[serde(rename = "messageId")]
+    pub message_id: String,
+
 }
 
 /// A group event.
@@ -220,6 +236,10 @@ pub struct Group {
     /// channel in payload
     pub channel: String,
 
+    /// unique id of the message
+    #[serde(rename = "messageId")]
+    pub message_id: String,
+
 }
 
 /// An alias event.
@@ -259,6 +279,10 @@ pub struct Alias {
     /// channel in payload
     pub channel: String,
 
+    /// unique id of the message
+    #[serde(rename = "messageId")]
+    pub message_id: String,
+
 }
 
 /// A batch of events.
@@ -289,19 +313,14 @@ pub struct Batch {
 }
 
 /// An enum containing all messages which may be placed inside a batch.
+/// Every message carries its own `type`, so the enum itself is untagged.
 #[derive(PartialEq, Debug, Clone, Serialize, Deserialize)]
-#[serde(tag = "type")]
+#[serde(untagged)]
 pub enum BatchMessage {
-    #[serde(rename = "identify")]
     Identify(Identify),
-    #[serde(rename = "track")]
     Track(Track),
-    #[serde(rename = "page")]
     Page(Page),
-    #[serde(rename = "screen")]
     Screen(Screen),
-    #[serde(rename = "group")]
     Group(Group),
-    #[serde(rename = "alias")]
     Alias(Alias),
 }
@@ -0,0 +1,135 @@
+//! Sampling and rate limiting of high-volume track events.
+//!
+//! A [`Sampler`] keeps a share of the track events with a given name, and
+//! can cap how many of them are sent per second:
+//!
+//! ```no_run
+//! use rudderanalytics::client::RudderAnalytics;
+//! use rudderanalytics::sampling::Sampler;
+//!
+//! let sampler = Sampler::new()
+//!     .sample("Heartbeat", 0.01)
+//!     .rate_limit("Cache Hit", 100.0, 500);
+//!
+//! let rudder_analytics = RudderAnalytics::builder("WRITE-KEY", "DATA-PLANE-URL")
+//!     .middleware(sampler)
+//!     .build()
+//!     .unwrap();
+//! ```
+//!
+//! Sampling is decided by a hash of the user id, or of the anonymous id for
+//! anonymous users, so that a user whose events are kept keeps all of them,
+//! and funnels stay complete. The rate of a sampled event is recorded in its
+//! `context.sampleRate`, so that downstream tools can weigh it.
+
+use crate::context::Context;
+use crate::message::BatchMessage;
+use crate::middleware::{Middleware, Verdict};
+use log::debug;
+use serde_json::json;
+use std::collections::HashMap;
+use std::sync::{Mutex, PoisonError};
+use std::time::Instant;
+
+/// Samples and rate limits track events by name.
+#[derive(Debug, Default)]
+pub struct Sampler {
+    rates: HashMap<String, f64>,
+    limits: HashMap<String, Limit>,
+    buckets: Mutex<HashMap<String, Bucket>>,
+}
+
+#[derive(Debug, Clone, Copy)]
+struct Limit {
+    per_second: f64,
+    burst: u32,
+}
+
+// A token bucket, refilled as time passes
+#[derive(Debug)]
+struct Bucket {
+    tokens: f64,
+    updated: Instant,
+}
+
+impl Sampler {
+    /// Create a sampler which keeps every event.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Keep the given share of the events with this name, between `0.0`
+    /// (none) and `1.0` (all).
+    pub fn sample(mut self, event: impl Into<String>, rate: f64) -> Self {
+        self.rates.insert(event.into(), rate.clamp(0.0, 1.0));
+        self
+    }
+
+    /// Send at most `per_second` events with this name per second on
+    /// average, and at most `burst` at once. The events above the limit are
+    /// dropped.
+    pub fn rate_limit(mut self, event: impl Into<String>, per_second: f64, burst: u32) -> Self {
+        self.limits
+            .insert(event.into(), Limit { per_second, burst });
+        self
+    }
+
+    // Takes a token from the event's bucket, false if there are none left
+    fn acquire(&self, event: &str, limit: Limit) -> bool {
+        let now = Instant::now();
+        let mut buckets = self.buckets.lock().unwrap_or_else(PoisonError::into_inner);
+        let bucket = buckets.entry(event.to_owned()).or_insert(Bucket {
+            tokens: f64::from(limit.burst),
+            updated: now,
+        });
+        let elapsed = now.duration_since(bucket.updated).as_secs_f64();
+        bucket.tokens = (bucket.tokens + elapsed * limit.per_second).min(f64::from(limit.burst));
+        bucket.updated = now;
+        if bucket.tokens < 1.0 {
+            return false;
+        }
+        bucket.tokens -= 1.0;
+        true
+    }
+}
+
+impl Middleware for Sampler {
+    fn process(&self, msg: &mut BatchMessage) -> Verdict {
+        let track = match msg {
+            BatchMessage::Track(track) => track,
+            _ => return Verdict::Keep,
+        };
+
+        if let Some(&rate) = self.rates.get(&track.event) {
+            let user = track.user_id.as_ref().or(track.anonymous_id.as_ref());
+            if let Some(user) = user {
+                if bucket_of(user) >= rate {
+                    debug!("event `{}` dropped by sampling", track.event);
+                    return Verdict::Drop;
+                }
+            }
+            track
+                .context
+                .get_or_insert_with(Context::default)
+                .extra
+                .insert("sampleRate".to_owned(), json!(rate));
+        }
+
+        if let Some(&limit) = self.limits.get(&track.event) {
+            if !self.acquire(&track.event, limit) {
+                debug!("event `{}` dropped by rate limiting", track.event);
+                return Verdict::Drop;
+            }
+        }
+        Verdict::Keep
+    }
+}
+
+// Maps a user to a number in [0, 1), the same on every platform and release,
+// with 64-bit FNV-1a
+fn bucket_of(user: &str) -> f64 {
+    let hash = user.bytes().fold(0xcbf2_9ce4_8422_2325_u64, |hash, byte| {
+        (hash ^ u64::from(byte)).wrapping_mul(0x0100_0000_01b3)
+    });
+    (hash >> 11) as f64 / (1_u64 << 53) as f64
+}
@@ -0,0 +1,181 @@
+//! A kill switch for all messages, and a list of users whose messages are
+//! never sent.
+//!
+//! A [`Suppression`] middleware drops the messages of the users it lists,
+//! matched by user id, anonymous id and, for aliases, previous id. It can
+//! also drop every message, e.g. while a tenant is opted out of tracking:
+//!
+//! ```no_run
+//! use rudderanalytics::client::RudderAnalytics;
+//! use rudderanalytics::suppression::Suppression;
+//!
+//! let suppression = Suppression::from_file("/etc/my-service/suppressed-users.txt").unwrap();
+//! let rudder_analytics = RudderAnalytics::builder("WRITE-KEY", "DATA-PLANE-URL")
+//!     .middleware(suppression.clone())
+//!     .build()
+//!     .unwrap();
+//!
+//! // Pick up the ids added to the file since
+//! suppression.reload().unwrap();
+//! ```
+
+use crate::errors::Error;
+use crate::message::BatchMessage;
+use crate::middleware::{Middleware, Verdict};
+use log::info;
+use std::collections::HashSet;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::{Arc, PoisonError, RwLock, RwLockWriteGuard};
+
+/// Drops the messages of suppressed users, or every message while opted out.
+///
+/// Clones share their state, so that a clone can be kept to update the list
+/// after the middleware has been registered.
+#[derive(Debug, Clone, Default)]
+pub struct Suppression {
+    shared: Arc<Shared>,
+    log: bool,
+}
+
+#[derive(Debug, Default)]
+struct Shared {
+    opted_out: AtomicBool,
+    ids: RwLock<HashSet<String>>,
+    file: Option<PathBuf>,
+    suppressed: AtomicU64,
+}
+
+impl Suppression {
+    /// Create a middleware which does not suppress anything yet.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Load the suppressed ids from a file, with one user id or anonymous
+    /// id per line. Blank lines and lines starting with `#` are ignored.
+    pub fn from_file(path: impl AsRef<Path>) -> Result<Self, Error> {
+        let path = path.as_ref();
+        let shared = Shared {
+            ids: RwLock::new(read_ids(path)?),
+            file: Some(path.to_owned()),
+            ..Default::default()
+        };
+        Ok(Self {
+            shared: Arc::new(shared),
+            log: false,
+        })
+    }
+
+    /// Log every suppressed message, without the id it was suppressed for.
+    pub fn log_suppressed(mut self, log: bool) -> Self {
+        self.log = log;
+        self
+    }
+
+    /// Read the file the ids were loaded from again, replacing them. Fails
+    /// if the ids were not loaded from a file, and keeps the current ids if
+    /// the file cannot be read.
+    pub fn reload(&self) -> Result<(), Error> {
+        let path = self.shared.file.as_ref().ok_or_else(|| {
+            Error::InvalidConfig("the suppressed ids were not loaded from a file".to_owned())
+        })?;
+        let ids = read_ids(path)?;
+        *self.ids_mut() = ids;
+        Ok(())
+    }
+
+    /// Replace the suppressed ids.
+    pub fn set_ids<I: IntoIterator<Item = String>>(&self, ids: I) {
+        *self.ids_mut() = ids.into_iter().collect();
+    }
+
+    /// Suppress the messages of a user, by user id or anonymous id.
+    pub fn insert(&self, id: impl Into<String>) {
+        self.ids_mut().insert(id.into());
+    }
+
+    /// Stop suppressing the messages of a user.
+    pub fn remove(&self, id: &str) {
+        self.ids_mut().remove(id);
+    }
+
+    /// Whether the messages of a user are suppressed.
+    pub fn contains(&self, id: &str) -> bool {
+        self.shared
+            .ids
+            .read()
+            .unwrap_or_else(PoisonError::into_inner)
+            .contains(id)
+    }
+
+    /// Drop every message until [`opt_in`](Self::opt_in) is called.
+    pub fn opt_out(&self) {
+        self.shared.opted_out.store(true, Ordering::Relaxed);
+    }
+
+    /// Send messages again, other than the ones of suppressed users.
+    pub fn opt_in(&self) {
+        self.shared.opted_out.store(false, Ordering::Relaxed);
+    }
+
+    /// Whether every message is dropped.
+    pub fn is_opted_out(&self) -> bool {
+        self.shared.opted_out.load(Ordering::Relaxed)
+    }
+
+    /// The number of messages dropped so far.
+    pub fn suppressed(&self) -> u64 {
+        self.shared.suppressed.load(Ordering::Relaxed)
+    }
+
+    fn ids_mut(&self) -> RwLockWriteGuard<'_, HashSet<String>> {
+        self.shared
+            .ids
+            .write()
+            .unwrap_or_else(PoisonError::into_inner)
+    }
+
+    // Why the message is suppressed, if it is
+    fn reason(&self, msg: &BatchMessage) -> Option<&'static str> {
+        if self.is_opted_out() {
+            return Some("opted out");
+        }
+        let (user_id, other_id) = msg.identity();
+        let ids = self
+            .shared
+            .ids
+            .read()
+            .unwrap_or_else(PoisonError::into_inner);
+        [user_id, other_id]
+            .iter()
+            .flatten()
+            .any(|id| ids.contains(*id))
+            .then_some("suppressed user")
+    }
+}
+
+impl Middleware for Suppression {
+    fn process(&self, msg: &mut BatchMessage) -> Verdict {
+        match self.reason(msg) {
+            Some(reason) => {
+                self.shared.suppressed.fetch_add(1, Ordering::Relaxed);
+                if self.log {
+                    info!("message dropped: {}", reason);
+                }
+                Verdict::Drop
+            }
+            None => Verdict::Keep,
+        }
+    }
+}
+
+fn read_ids(path: &Path) -> Result<HashSet<String>, Error> {
+    Ok(fs::read_to_string(path)?
+        .lines()
+        .map(str::trim)
+        .filter(|line| !line.is_empty() && !line.starts_with('#'))
+        .map(str::to_owned)
+        .collect())
+}
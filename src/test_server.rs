@@ -0,0 +1,378 @@
+//! A local mock of the data plane, for integration tests.
+//!
+//! [`TestServer`] serves the `/v1/*` endpoints on a random local port,
+//! checks the write key sent as basic auth, and records every request it
+//! receives. Failures can be scripted to exercise retries and rate limiting.
+//!
+//! ```
+//! use rudderanalytics::client::RudderAnalytics;
+//! use rudderanalytics::message::{Message, Track};
+//! use rudderanalytics::test_server::TestServer;
+//!
+//! let server = TestServer::start("WRITE-KEY").unwrap();
+//! let rudder_analytics = RudderAnalytics::load("WRITE-KEY".to_string(), server.url());
+//!
+//! rudder_analytics
+//!     .send(&Message::Track(Track {
+//!         user_id: Some("user-1".to_owned()),
+//!         event: "Example".to_owned(),
+//!         ..Default::default()
+//!     }))
+//!     .unwrap();
+//!
+//! assert_eq!(server.events()[0]["event"], "Example");
+//! ```
+
+use crate::errors::Error;
+use base64::engine::general_purpose::STANDARD as BASE64;
+use base64::Engine;
+use flate2::read::GzDecoder;
+use serde_json::Value;
+use std::collections::VecDeque;
+use std::io::{BufRead, BufReader, Read, Write};
+use std::net::{Shutdown, TcpListener, TcpStream};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex, MutexGuard};
+use std::thread::{self, JoinHandle};
+use std::time::{Duration, Instant};
+
+const ENDPOINTS: [&str; 7] = [
+    "/v1/identify",
+    "/v1/track",
+    "/v1/page",
+    "/v1/screen",
+    "/v1/group",
+    "/v1/alias",
+    "/v1/batch",
+];
+
+/// A scripted failure, used for the next request instead of accepting it.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Failure {
+    /// Answer with the given status code and body.
+    Status { status: u16, body: String },
+    /// Answer with `429 Too Many Requests`, with a `Retry-After` header in
+    /// seconds if given.
+    RateLimited { retry_after: Option<u64> },
+    /// Wait for the given time before handling the request as usual.
+    Slow(Duration),
+    /// Close the connection without answering.
+    DropConnection,
+}
+
+impl Failure {
+    /// Answer with the given status code and its reason phrase as body.
+    pub fn status(status: u16) -> Self {
+        Failure::Status {
+            status,
+            body: reason(status).to_owned(),
+        }
+    }
+}
+
+/// A request received by a [`TestServer`].
+#[derive(Debug, Clone, PartialEq)]
+pub struct ReceivedRequest {
+    /// The request path, e.g. `/v1/track`.
+    pub path: String,
+
+    /// The request headers, in the order received.
+    pub headers: Vec<(String, String)>,
+
+    /// The request body, decompressed if it was gzipped.
+    pub body: Vec<u8>,
+
+    /// The status code the request was answered with, `None` if the
+    /// connection was dropped.
+    pub status: Option<u16>,
+}
+
+impl ReceivedRequest {
+    /// The value of the given header, ignoring case.
+    pub fn header(&self, name: &str) -> Option<&str> {
+        self.headers
+            .iter()
+            .find(|(key, _)| key.eq_ignore_ascii_case(name))
+            .map(|(_, value)| value.as_str())
+    }
+
+    /// The request body as JSON.
+    pub fn json(&self) -> Result<Value, Error> {
+        Ok(serde_json::from_slice(&self.body)?)
+    }
+}
+
+#[derive(Default)]
+struct State {
+    requests: Vec<ReceivedRequest>,
+    failures: VecDeque<Failure>,
+}
+
+/// A mock data plane listening on a random local port.
+///
+/// The server stops when it is dropped.
+pub struct TestServer {
+    url: String,
+    state: Arc<Mutex<State>>,
+    stopped: Arc<AtomicBool>,
+    listener: Option<JoinHandle<()>>,
+}
+
+impl TestServer {
+    /// Start a server accepting requests authenticated with the given write
+    /// key. Requests with any other key are answered with `401`.
+    pub fn start(write_key: impl Into<String>) -> Result<Self, Error> {
+        let listener = TcpListener::bind("127.0.0.1:0")?;
+        let url = format!("http://{}", listener.local_addr()?);
+        let authorization = format!("Basic {}", BASE64.encode(format!("{}:", write_key.into())));
+        let state = Arc::new(Mutex::new(State::default()));
+        let stopped = Arc::new(AtomicBool::new(false));
+
+        let handle = {
+            let state = state.clone();
+            let stopped = stopped.clone();
+            thread::Builder::new()
+                .name("rudderanalytics-test-server".to_owned())
+                .spawn(move || {
+                    for stream in listener.incoming() {
+                        if stopped.load(Ordering::SeqCst) {
+                            break;
+                        }
+                        if let Ok(stream) = stream {
+                            let connection = Connection {
+                                authorization: authorization.clone(),
+                                state: state.clone(),
+                            };
+                            thread::spawn(move || connection.serve(stream));
+                        }
+                    }
+                })?
+        };
+
+        Ok(Self {
+            url,
+            state,
+            stopped,
+            listener: Some(handle),
+        })
+    }
+
+    /// The url to use as data plane url.
+    pub fn url(&self) -> String {
+        self.url.clone()
+    }
+
+    /// Fail the next request not yet taken by another scripted failure.
+    pub fn fail_next(&self, failure: Failure) {
+        lock(&self.state).failures.push_back(failure);
+    }
+
+    /// All requests received so far, in order.
+    pub fn requests(&self) -> Vec<ReceivedRequest> {
+        lock(&self.state).requests.clone()
+    }
+
+    /// The payloads of all accepted requests, in order.
+    pub fn payloads(&self) -> Vec<Value> {
+        self.requests()
+            .iter()
+            .filter(|request| request.status == Some(200))
+            .filter_map(|request| request.json().ok())
+            .collect()
+    }
+
+    /// The events of all accepted requests, with batches split into their
+    /// events, in order.
+    pub fn events(&self) -> Vec<Value> {
+        let mut events = Vec::new();
+        for payload in self.payloads() {
+            match payload.get("batch").and_then(Value::as_array) {
+                Some(batch) => events.extend(batch.iter().cloned()),
+                None => events.push(payload),
+            }
+        }
+        events
+    }
+
+    /// Wait until at least `count` requests have been received, or the
+    /// timeout has expired. Returns whether they have been received.
+    pub fn wait_for_requests(&self, count: usize, timeout: Duration) -> bool {
+        let deadline = Instant::now() + timeout;
+        while lock(&self.state).requests.len() < count {
+            if Instant::now() >= deadline {
+                return false;
+            }
+            thread::sleep(Duration::from_millis(10));
+        }
+        true
+    }
+}
+
+impl Drop for TestServer {
+    fn drop(&mut self) {
+        self.stopped.store(true, Ordering::SeqCst);
+        // Wake up the listener, so that it sees the flag
+        let _ = TcpStream::connect(self.url.trim_start_matches("http://"));
+        if let Some(listener) = self.listener.take() {
+            let _ = listener.join();
+        }
+    }
+}
+
+fn lock(state: &Mutex<State>) -> MutexGuard<'_, State> {
+    // A panicking test must not hide the requests from other tests
+    state
+        .lock()
+        .unwrap_or_else(|poisoned| poisoned.into_inner())
+}
+
+struct Connection {
+    authorization: String,
+    state: Arc<Mutex<State>>,
+}
+
+struct Request {
+    path: String,
+    headers: Vec<(String, String)>,
+    body: Vec<u8>,
+}
+
+impl Connection {
+    // Serve requests until the client closes the connection
+    fn serve(self, stream: TcpStream) {
+        let mut reader = BufReader::new(stream);
+        while let Some(request) = read_request(&mut reader) {
+            let failure = lock(&self.state).failures.pop_front();
+            if let Some(Failure::Slow(delay)) = &failure {
+                thread::sleep(*delay);
+            }
+
+            let response = match failure {
+                Some(Failure::DropConnection) => None,
+                Some(Failure::Status { status, body }) => Some((status, Vec::new(), body)),
+                Some(Failure::RateLimited { retry_after }) => {
+                    let headers = retry_after
+                        .map(|seconds| vec![("Retry-After".to_owned(), seconds.to_string())])
+                        .unwrap_or_default();
+                    Some((429, headers, reason(429).to_owned()))
+                }
+                Some(Failure::Slow(_)) | None => Some(self.handle(&request)),
+            };
+
+            let mut body = request.body;
+            if header(&request.headers, "content-encoding") == Some("gzip") {
+                let mut decoded = Vec::new();
+                if GzDecoder::new(&body[..]).read_to_end(&mut decoded).is_ok() {
+                    body = decoded;
+                }
+            }
+            // Recorded before answering, so that the request is visible as
+            // soon as the client returns
+            lock(&self.state).requests.push(ReceivedRequest {
+                path: request.path,
+                headers: request.headers,
+                body,
+                status: response.as_ref().map(|(status, _, _)| *status),
+            });
+
+            let stream = reader.get_mut();
+            match response {
+                Some(response) => {
+                    if write_response(stream, response).is_err() {
+                        return;
+                    }
+                }
+                None => {
+                    let _ = stream.shutdown(Shutdown::Both);
+                    return;
+                }
+            }
+        }
+    }
+
+    fn handle(&self, request: &Request) -> (u16, Vec<(String, String)>, String) {
+        let status = if !ENDPOINTS.contains(&request.path.as_str()) {
+            404
+        } else if header(&request.headers, "authorization") != Some(self.authorization.as_str()) {
+            401
+        } else {
+            200
+        };
+        (status, Vec::new(), reason(status).to_owned())
+    }
+}
+
+// Reads a request, or returns None once the connection is closed or broken
+fn read_request(reader: &mut BufReader<TcpStream>) -> Option<Request> {
+    let mut request_line = String::new();
+    if reader.read_line(&mut request_line).ok()? == 0 {
+        return None;
+    }
+    let path = request_line.split_whitespace().nth(1)?.to_owned();
+
+    let mut headers = Vec::new();
+    loop {
+        let mut line = String::new();
+        if reader.read_line(&mut line).ok()? == 0 {
+            return None;
+        }
+        let line = line.trim_end();
+        if line.is_empty() {
+            break;
+        }
+        let (name, value) = line.split_once(':')?;
+        headers.push((name.trim().to_owned(), value.trim().to_owned()));
+    }
+
+    let content_length = header(&headers, "content-length")
+        .and_then(|value| value.parse().ok())
+        .unwrap_or(0);
+    let mut body = vec![0; content_length];
+    reader.read_exact(&mut body).ok()?;
+    Some(Request {
+        path,
+        headers,
+        body,
+    })
+}
+
+fn write_response(
+    stream: &mut TcpStream,
+    (status, headers, body): (u16, Vec<(String, String)>, String),
+) -> std::io::Result<()> {
+    let mut response = format!(
+        "HTTP/1.1 {} {}\r\nContent-Length: {}\r\n",
+        status,
+        reason(status),
+        body.len()
+    );
+    for (name, value) in headers {
+        response.push_str(&format!("{}: {}\r\n", name, value));
+    }
+    response.push_str("\r\n");
+    response.push_str(&body);
+    stream.write_all(response.as_bytes())
+}
+
+fn header<'a>(headers: &'a [(String, String)], name: &str) -> Option<&'a str> {
+    headers
+        .iter()
+        .find(|(key, _)| key.eq_ignore_ascii_case(name))
+        .map(|(_, value)| value.as_str())
+}
+
+fn reason(status: u16) -> &'static str {
+    match status {
+        200 => "OK",
+        400 => "Bad Request",
+        401 => "Unauthorized",
+        404 => "Not Found",
+        413 => "Payload Too Large",
+        429 => "Too Many Requests",
+        500 => "Internal Server Error",
+        502 => "Bad Gateway",
+        503 => "Service Unavailable",
+        504 => "Gateway Timeout",
+        _ => "Unknown",
+    }
+}
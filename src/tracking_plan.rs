@@ -0,0 +1,334 @@
+//! Tracking plans, checking events against JSON Schemas before they are sent.
+//!
+//! A [`TrackingPlan`] lists the track events which may be sent, with a JSON
+//! Schema for their properties and for the user traits in their context, and
+//! JSON Schemas for the traits of identify and group messages. Plans are
+//! loaded from a JSON or YAML file:
+//!
+//! ```yaml
+//! allowUnplannedEvents: false
+//! events:
+//!   Order Completed:
+//!     properties:
+//!       type: object
+//!       required: [revenue]
+//!       properties:
+//!         revenue: { type: number }
+//! identify:
+//!   traits:
+//!     type: object
+//!     properties:
+//!       email: { type: string }
+//! ```
+//!
+//! Messages breaking the plan are handled as set by [`ViolationAction`].
+//! Page, screen and alias messages are not covered by tracking plans.
+
+use crate::client::Enforced;
+use crate::context::Context;
+use crate::errors::Error;
+use crate::message::{BatchMessage, Message};
+use jsonschema::error::ValidationErrorKind;
+use jsonschema::JSONSchema;
+use serde::Deserialize;
+use serde_json::{json, Value};
+use std::borrow::Cow;
+use std::collections::HashMap;
+use std::fmt;
+use std::fs;
+use std::path::Path;
+
+/// What the client does with a message which breaks the tracking plan.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum ViolationAction {
+    /// Fail the message with [`Error::TrackingPlanViolation`]. Within a batch,
+    /// the message counts as invalid, see
+    /// [`BatchValidation`](crate::client::BatchValidation). This is the
+    /// default.
+    #[default]
+    Block,
+
+    /// Do not send the message. `send` succeeds without making a request,
+    /// and a dropped batch message is reported in
+    /// [`BatchOutcome::rejected`](crate::client::BatchOutcome::rejected).
+    Drop,
+
+    /// Send the message anyway, with the violations listed in its
+    /// `context.violationErrors`, like RudderStack's server-side tracking
+    /// plans do.
+    Forward,
+}
+
+/// A single way in which a message breaks the tracking plan.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Violation {
+    /// The type of violation, as named by RudderStack, e.g.
+    /// `Unplanned-Event` or `Datatype-Mismatch`.
+    pub kind: &'static str,
+
+    /// A description of the violation.
+    pub message: String,
+
+    /// A JSON pointer to the offending value within the message, e.g.
+    /// `/properties/revenue`.
+    pub instance_path: String,
+}
+
+impl fmt::Display for Violation {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        if self.instance_path.is_empty() {
+            write!(f, "{}", self.message)
+        } else {
+            write!(f, "{}: {}", self.instance_path, self.message)
+        }
+    }
+}
+
+/// The events which may be sent, and the schemas they must follow.
+pub struct TrackingPlan {
+    events: HashMap<String, Rules>,
+    identify: Rules,
+    group: Rules,
+    allow_unplanned_events: bool,
+    action: ViolationAction,
+}
+
+impl fmt::Debug for TrackingPlan {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let mut events: Vec<_> = self.events.keys().collect();
+        events.sort();
+        f.debug_struct("TrackingPlan")
+            .field("events", &events)
+            .field("allow_unplanned_events", &self.allow_unplanned_events)
+            .field("action", &self.action)
+            .finish()
+    }
+}
+
+// The schemas of an event, compiled
+#[derive(Default)]
+struct Rules {
+    properties: Option<JSONSchema>,
+    traits: Option<JSONSchema>,
+}
+
+// The plan as read from a file
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase", deny_unknown_fields)]
+struct PlanFile {
+    #[serde(default = "default_allow_unplanned_events")]
+    allow_unplanned_events: bool,
+    #[serde(default)]
+    events: HashMap<String, RulesFile>,
+    #[serde(default)]
+    identify: RulesFile,
+    #[serde(default)]
+    group: RulesFile,
+}
+
+#[derive(Deserialize, Default)]
+#[serde(deny_unknown_fields)]
+struct RulesFile {
+    properties: Option<Value>,
+    traits: Option<Value>,
+}
+
+fn default_allow_unplanned_events() -> bool {
+    true
+}
+
+impl TrackingPlan {
+    /// Load a plan from a file, read as YAML if its extension is `yaml` or
+    /// `yml`, and as JSON otherwise.
+    pub fn from_file(path: impl AsRef<Path>) -> Result<Self, Error> {
+        let path = path.as_ref();
+        let contents = fs::read_to_string(path)?;
+        match path.extension().and_then(|ext| ext.to_str()) {
+            Some("yaml") | Some("yml") => Self::from_yaml(&contents),
+            _ => Self::from_json(&contents),
+        }
+    }
+
+    /// Load a plan from a JSON document.
+    pub fn from_json(json: &str) -> Result<Self, Error> {
+        Self::from_value(serde_json::from_str(json)?)
+    }
+
+    /// Load a plan from a YAML document.
+    pub fn from_yaml(yaml: &str) -> Result<Self, Error> {
+        let value = serde_yaml::from_str(yaml)
+            .map_err(|err| Error::InvalidConfig(format!("invalid tracking plan: {}", err)))?;
+        Self::from_value(value)
+    }
+
+    /// Load a plan from a JSON value.
+    ///
+    /// Fails if the value is not a tracking plan, or one of its schemas is
+    /// not a valid JSON Schema.
+    pub fn from_value(value: Value) -> Result<Self, Error> {
+        let file: PlanFile = serde_json::from_value(value)
+            .map_err(|err| Error::InvalidConfig(format!("invalid tracking plan: {}", err)))?;
+        let mut events = HashMap::new();
+        for (name, rules) in file.events {
+            let rules = compile_rules(&name, rules)?;
+            events.insert(name, rules);
+        }
+        Ok(Self {
+            events,
+            identify: compile_rules("identify", file.identify)?,
+            group: compile_rules("group", file.group)?,
+            allow_unplanned_events: file.allow_unplanned_events,
+            action: ViolationAction::default(),
+        })
+    }
+
+    /// Handle violations with the given action instead of blocking the
+    /// message.
+    pub fn on_violation(mut self, action: ViolationAction) -> Self {
+        self.action = action;
+        self
+    }
+
+    /// Check a message against the plan, and return all violations.
+    ///
+    /// Batches are not checked as a whole, only their messages are.
+    pub fn check(&self, msg: &Message) -> Vec<Violation> {
+        match msg {
+            Message::Track(m) => self.check_track(&m.event, &m.properties, &m.context),
+            Message::Identify(m) => check_schema(&self.identify.traits, &m.traits, "/traits"),
+            Message::Group(m) => check_schema(&self.group.traits, &m.traits, "/traits"),
+            _ => Vec::new(),
+        }
+    }
+
+    /// Check a message of a batch against the plan, and return all
+    /// violations.
+    pub fn check_item(&self, msg: &BatchMessage) -> Vec<Violation> {
+        match msg {
+            BatchMessage::Track(m) => self.check_track(&m.event, &m.properties, &m.context),
+            BatchMessage::Identify(m) => check_schema(&self.identify.traits, &m.traits, "/traits"),
+            BatchMessage::Group(m) => check_schema(&self.group.traits, &m.traits, "/traits"),
+            _ => Vec::new(),
+        }
+    }
+
+    // Applies the plan's action to a message
+    pub(crate) fn enforce<'a>(&self, msg: &'a Message) -> Enforced<'a, Message> {
+        let violations = self.check(msg);
+        self.apply(msg, violations, |msg| msg.context_mut())
+    }
+
+    // Applies the plan's action to a message of a batch
+    pub(crate) fn enforce_item<'a>(&self, msg: &'a BatchMessage) -> Enforced<'a, BatchMessage> {
+        let violations = self.check_item(msg);
+        self.apply(msg, violations, |msg| Some(msg.context_mut()))
+    }
+
+    fn apply<'a, T: Clone>(
+        &self,
+        msg: &'a T,
+        violations: Vec<Violation>,
+        context_mut: impl FnOnce(&mut T) -> Option<&mut Option<Context>>,
+    ) -> Enforced<'a, T> {
+        if violations.is_empty() {
+            return Enforced::Keep(Cow::Borrowed(msg));
+        }
+        match self.action {
+            ViolationAction::Block => Enforced::Block(Error::TrackingPlanViolation(violations)),
+            ViolationAction::Drop => Enforced::Drop(Error::TrackingPlanViolation(violations)),
+            ViolationAction::Forward => {
+                let mut msg = msg.clone();
+                if let Some(context) = context_mut(&mut msg) {
+                    annotate(context.get_or_insert_with(Context::default), &violations);
+                }
+                Enforced::Keep(Cow::Owned(msg))
+            }
+        }
+    }
+
+    fn check_track(
+        &self,
+        event: &str,
+        properties: &Option<Value>,
+        context: &Option<Context>,
+    ) -> Vec<Violation> {
+        let rules = match self.events.get(event) {
+            Some(rules) => rules,
+            None if self.allow_unplanned_events => return Vec::new(),
+            None => {
+                return vec![Violation {
+                    kind: "Unplanned-Event",
+                    message: format!("event `{}` is not part of the tracking plan", event),
+                    instance_path: String::new(),
+                }]
+            }
+        };
+        let mut violations = check_schema(&rules.properties, properties, "/properties");
+        let traits = context.as_ref().and_then(|context| context.traits.clone());
+        violations.extend(check_schema(&rules.traits, &traits, "/context/traits"));
+        violations
+    }
+}
+
+fn compile_rules(name: &str, rules: RulesFile) -> Result<Rules, Error> {
+    let compile = |schema: Option<Value>| {
+        schema
+            .map(|schema| {
+                JSONSchema::compile(&schema).map_err(|err| {
+                    Error::InvalidConfig(format!("invalid schema for `{}`: {}", name, err))
+                })
+            })
+            .transpose()
+    };
+    Ok(Rules {
+        properties: compile(rules.properties)?,
+        traits: compile(rules.traits)?,
+    })
+}
+
+// Checks a value against a schema, treating a missing value as an empty object
+fn check_schema(schema: &Option<JSONSchema>, value: &Option<Value>, path: &str) -> Vec<Violation> {
+    let schema = match schema {
+        Some(schema) => schema,
+        None => return Vec::new(),
+    };
+    let empty = json!({});
+    let result = schema.validate(value.as_ref().unwrap_or(&empty));
+    match result {
+        Ok(()) => Vec::new(),
+        Err(errors) => errors
+            .map(|err| Violation {
+                kind: violation_kind(&err.kind),
+                message: err.to_string(),
+                instance_path: format!("{}{}", path, err.instance_path),
+            })
+            .collect(),
+    }
+}
+
+// RudderStack's name for a kind of violation
+fn violation_kind(kind: &ValidationErrorKind) -> &'static str {
+    match kind {
+        ValidationErrorKind::AdditionalProperties { .. } => "Additional-Properties",
+        ValidationErrorKind::Required { .. } => "Required-Missing",
+        ValidationErrorKind::Type { .. } => "Datatype-Mismatch",
+        _ => "Unknown-Violation",
+    }
+}
+
+// Lists the violations in the context, the way the data plane does
+fn annotate(context: &mut Context, violations: &[Violation]) {
+    let errors = violations
+        .iter()
+        .map(|violation| {
+            json!({
+                "type": violation.kind,
+                "message": violation.message,
+                "meta": { "instancePath": violation.instance_path },
+            })
+        })
+        .collect();
+    context
+        .extra
+        .insert("violationErrors".to_owned(), Value::Array(errors));
+}
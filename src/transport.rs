@@ -0,0 +1,237 @@
+//! The transports carrying requests from the blocking client to the data
+//! plane.
+//!
+//! [`RudderAnalytics`](crate::client::RudderAnalytics) prepares every request,
+//! including its authentication and encoding headers, and retries failed ones.
+//! A [`Transport`] only delivers a single request and reports the response.
+
+use crate::errors::Error;
+use flate2::read::GzDecoder;
+use reqwest::header::{HeaderMap, HeaderValue, CONTENT_ENCODING};
+use std::collections::VecDeque;
+use std::fs::OpenOptions;
+use std::io::{self, Read, Write};
+use std::path::Path;
+use std::sync::Mutex;
+
+/// Delivers requests to the data plane.
+pub trait Transport: Send + Sync {
+    /// Send a request to the given API path, e.g. `/v1/track`, and return the
+    /// response.
+    ///
+    /// Errors are retried if [`Error::is_retryable`] says so, like responses
+    /// with a `5xx` status.
+    fn send(&self, path: &str, headers: &HeaderMap, body: &[u8]) -> Result<Response, Error>;
+}
+
+/// A response from the data plane.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Response {
+    /// The status code.
+    pub status: u16,
+
+    /// The response headers. `Retry-After` is honored for rate limiting.
+    pub headers: HeaderMap,
+
+    /// The response body.
+    pub body: String,
+}
+
+impl Response {
+    /// A `200 OK` response, as sent by the data plane for accepted payloads.
+    pub fn ok() -> Self {
+        Self::with_status(200, "OK")
+    }
+
+    /// A response with the given status and body, and no headers.
+    pub fn with_status(status: u16, body: impl Into<String>) -> Self {
+        Self {
+            status,
+            headers: HeaderMap::new(),
+            body: body.into(),
+        }
+    }
+}
+
+/// Sends requests over HTTP with `reqwest`. This is the default transport.
+#[derive(Debug, Clone)]
+pub struct ReqwestTransport {
+    client: reqwest::blocking::Client,
+    data_plane_url: String,
+}
+
+impl ReqwestTransport {
+    /// Send requests with the given client to the data plane at the given
+    /// url.
+    pub fn new(client: reqwest::blocking::Client, data_plane_url: impl Into<String>) -> Self {
+        Self {
+            client,
+            data_plane_url: data_plane_url.into(),
+        }
+    }
+}
+
+impl Transport for ReqwestTransport {
+    fn send(&self, path: &str, headers: &HeaderMap, body: &[u8]) -> Result<Response, Error> {
+        let res = self
+            .client
+            .post(format!("{}{}", self.data_plane_url, path))
+            .headers(headers.clone())
+            .body(body.to_vec())
+            .send()?;
+        Ok(Response {
+            status: res.status().as_u16(),
+            headers: res.headers().clone(),
+            body: res.text()?,
+        })
+    }
+}
+
+/// A request as seen by a [`RecordingTransport`].
+#[derive(Debug, Clone, PartialEq)]
+pub struct RecordedRequest {
+    /// The API path, e.g. `/v1/track`.
+    pub path: String,
+
+    /// The request headers, including `Authorization`.
+    pub headers: HeaderMap,
+
+    /// The request body, compressed if the client uses gzip.
+    pub body: Vec<u8>,
+}
+
+impl RecordedRequest {
+    /// The request body as JSON, decompressed if needed.
+    pub fn json(&self) -> Result<serde_json::Value, Error> {
+        let body = decode_body(&self.headers, &self.body)?;
+        Ok(serde_json::from_slice(&body)?)
+    }
+}
+
+/// Records requests in memory instead of sending them, for tests.
+///
+/// Responses can be scripted with [`respond_with`](Self::respond_with); once
+/// they have been used up, every request is answered with `200 OK`.
+///
+/// ```
+/// use rudderanalytics::client::RudderAnalytics;
+/// use rudderanalytics::message::{Message, Track};
+/// use rudderanalytics::transport::RecordingTransport;
+/// use std::sync::Arc;
+///
+/// let transport = Arc::new(RecordingTransport::new());
+/// let rudder_analytics = RudderAnalytics::builder("WRITE-KEY", "")
+///     .transport(transport.clone())
+///     .build()
+///     .unwrap();
+///
+/// rudder_analytics
+///     .send(&Message::Track(Track {
+///         user_id: Some("user-1".to_owned()),
+///         event: "Example".to_owned(),
+///         ..Default::default()
+///     }))
+///     .unwrap();
+///
+/// let requests = transport.requests();
+/// assert_eq!(requests[0].path, "/v1/track");
+/// assert_eq!(requests[0].json().unwrap()["event"], "Example");
+/// ```
+#[derive(Debug, Default)]
+pub struct RecordingTransport {
+    requests: Mutex<Vec<RecordedRequest>>,
+    responses: Mutex<VecDeque<Result<Response, Error>>>,
+}
+
+impl RecordingTransport {
+    /// A transport answering every request with `200 OK`.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Answer the next request not yet answered by a scripted response with
+    /// the given response or error.
+    pub fn respond_with(&self, response: Result<Response, Error>) {
+        if let Ok(mut responses) = self.responses.lock() {
+            responses.push_back(response);
+        }
+    }
+
+    /// The requests received so far, in order.
+    pub fn requests(&self) -> Vec<RecordedRequest> {
+        match self.requests.lock() {
+            Ok(requests) => requests.clone(),
+            Err(_) => Vec::new(),
+        }
+    }
+}
+
+impl Transport for RecordingTransport {
+    fn send(&self, path: &str, headers: &HeaderMap, body: &[u8]) -> Result<Response, Error> {
+        if let Ok(mut requests) = self.requests.lock() {
+            requests.push(RecordedRequest {
+                path: path.to_owned(),
+                headers: headers.clone(),
+                body: body.to_vec(),
+            });
+        }
+        match self.responses.lock().ok().and_then(|mut r| r.pop_front()) {
+            Some(response) => response,
+            None => Ok(Response::ok()),
+        }
+    }
+}
+
+/// Writes requests to a file or stdout instead of sending them, for local
+/// development.
+///
+/// Every request is written as a single line holding the path and the
+/// decompressed body, and answered with `200 OK`.
+pub struct WriterTransport {
+    writer: Mutex<Box<dyn Write + Send>>,
+}
+
+impl WriterTransport {
+    /// Write requests to the given writer.
+    pub fn new(writer: impl Write + Send + 'static) -> Self {
+        Self {
+            writer: Mutex::new(Box::new(writer)),
+        }
+    }
+
+    /// Write requests to stdout.
+    pub fn stdout() -> Self {
+        Self::new(io::stdout())
+    }
+
+    /// Append requests to the given file, creating it if needed.
+    pub fn file(path: impl AsRef<Path>) -> Result<Self, Error> {
+        let file = OpenOptions::new().create(true).append(true).open(path)?;
+        Ok(Self::new(file))
+    }
+}
+
+impl Transport for WriterTransport {
+    fn send(&self, path: &str, headers: &HeaderMap, body: &[u8]) -> Result<Response, Error> {
+        let body = decode_body(headers, body)?;
+        let mut writer = self
+            .writer
+            .lock()
+            .map_err(|_| Error::InvalidConfig("transport poisoned by a panic".to_owned()))?;
+        writer.write_all(format!("POST {} ", path).as_bytes())?;
+        writer.write_all(&body)?;
+        writer.write_all(b"\n")?;
+        writer.flush()?;
+        Ok(Response::ok())
+    }
+}
+
+// Decompresses a gzipped body
+fn decode_body(headers: &HeaderMap, body: &[u8]) -> Result<Vec<u8>, Error> {
+    if headers.get(CONTENT_ENCODING) != Some(&HeaderValue::from_static("gzip")) {
+        return Ok(body.to_vec());
+    }
+    let mut decoded = Vec::new();
+    GzDecoder::new(body).read_to_end(&mut decoded)?;
+    Ok(decoded)
+}
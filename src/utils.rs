@@ -1,4 +1,7 @@
-use crate::message::{Identify, Track, Page, Screen, Group, Alias, Batch, BatchMessage};
+use crate::context::Context;
+use crate::message::{
+    generate_message_id, Identify, Track, Page, Screen, Group, Alias, Batch, BatchMessage,
+};
 use crate::ruddermessage::{
     Ruddermessage,
     Identify as Rudderidentify,
@@ -32,331 +35,315 @@ fn merge(a: &mut Value, b: Value) {
     }
 }
 
-// function to check if any reserve keyword is present in a given object or not
-// returns true/false
-pub fn check_reserved_keywords_conflict(context: Value)->bool{
-    let mut result = false;
-    for (k, _v) in context.as_object().unwrap().iter(){
-        let s: String = k.to_owned();
-        if RESERVED_KEYS.contains(&&s[..]) {
-            result = true;
-            break;
-        } 
-    }
-    result
+// function to check if any reserve keyword is present in a given context or not
+// returns the first conflicting keyword
+pub fn check_reserved_keywords_conflict(context: &Context) -> Option<&'static str> {
+    let context = context_value(Some(context));
+    let context = context.as_object()?;
+    RESERVED_KEYS.iter().copied().find(|k| context.contains_key(*k))
+}
+
+// The user supplied context as JSON object
+fn context_value(context: Option<&Context>) -> Value {
+    context
+        .and_then(|context| serde_json::to_value(context).ok())
+        .unwrap_or_else(|| json!({}))
 }
 
 // Build and return static context fields
-fn get_default_context()->Value{
-    let default_context = json!({
+fn get_default_context() -> Value {
+    json!({
         "library":{
             "name": NAME,
             "version": VERSION
         }
-    });
-    default_context
+    })
 }
 
-// modify identify payload to rudder format
-pub fn parse_identify(msg:&Identify)-> Ruddermessage{
+// Build the context sent to the data plane: the default context merged
+// with the context supplied by the user
+pub fn get_context(context: &Option<Context>) -> Value {
     let mut modified_context = get_default_context();
-    merge(&mut modified_context, msg.context.clone().unwrap_or(json!({})));
+    merge(&mut modified_context, context_value(context.as_ref()));
+    modified_context
+}
 
-    let original_timestamp;
+// modify identify payload to rudder format
+pub fn parse_identify(msg: &Identify) -> Ruddermessage {
     let sent_at = Utc::now();
-    if msg.original_timestamp==Option::None {
-        original_timestamp = Some(sent_at);
-    }else {
-        original_timestamp = msg.original_timestamp;
-    }
-    
-    let new_message = Ruddermessage::Identify(
+    let original_timestamp = msg.original_timestamp.or(Some(sent_at));
+
+    Ruddermessage::Identify(
         Rudderidentify {
             user_id: msg.user_id.clone(),
             anonymous_id: msg.anonymous_id.clone(),
             traits: msg.traits.clone(),
-            original_timestamp: original_timestamp,
+            original_timestamp,
             sent_at: Some(sent_at),
             integrations: msg.integrations.clone(),
-            context: Some(modified_context),
+            context: Some(get_context(&msg.context)),
             r#type: String::from("identify"),
-            channel: CHANNEL.to_string()
-        } 
-    );
-    new_message
+            channel: CHANNEL.to_string(),
+            message_id: msg.message_id.clone().unwrap_or_else(generate_message_id),
+        }
+    )
 }
 
 // modify track payload to rudder format
-pub fn parse_track(msg:&Track)-> Ruddermessage{
-    let mut modified_context = get_default_context();
-    merge(&mut modified_context, msg.context.clone().unwrap_or(json!({})));
-
-    let original_timestamp;
+pub fn parse_track(msg: &Track) -> Ruddermessage {
     let sent_at = Utc::now();
-    if msg.original_timestamp==Option::None {
-        original_timestamp = Some(sent_at);
-    }else {
-        original_timestamp = msg.original_timestamp;
-    }
+    let original_timestamp = msg.original_timestamp.or(Some(sent_at));
 
-    let new_message= Ruddermessage::Track(
+    Ruddermessage::Track(
         Ruddertrack {
             user_id: msg.user_id.clone(),
             anonymous_id: msg.anonymous_id.clone(),
             event: msg.event.clone(),
             properties: msg.properties.clone(),
-            original_timestamp: original_timestamp,
+            original_timestamp,
             sent_at: Some(sent_at),
             integrations: msg.integrations.clone(),
-            context: Some(modified_context),
+            context: Some(get_context(&msg.context)),
             r#type: String::from("track"),
-            channel: CHANNEL.to_string()
+            channel: CHANNEL.to_string(),
+            message_id: msg.message_id.clone().unwrap_or_else(generate_message_id),
         }
-    );
-    new_message
+    )
 }
 
 // modify page payload to rudder format
-pub fn parse_page(msg:&Page)-> Ruddermessage{
-    let mut modified_context = get_default_context();
-    merge(&mut modified_context, msg.context.clone().unwrap_or(json!({})));
-
-    let original_timestamp;
+pub fn parse_page(msg: &Page) -> Ruddermessage {
     let sent_at = Utc::now();
-    if msg.original_timestamp==Option::None {
-        original_timestamp = Some(sent_at);
-    }else {
-        original_timestamp = msg.original_timestamp;
-    }
+    let original_timestamp = msg.original_timestamp.or(Some(sent_at));
 
-    let new_message= Ruddermessage::Page(
+    Ruddermessage::Page(
         Rudderpage {
             user_id: msg.user_id.clone(),
             anonymous_id: msg.anonymous_id.clone(),
             name: msg.name.clone(),
             properties: msg.properties.clone(),
-            original_timestamp: original_timestamp,
+            original_timestamp,
             sent_at: Some(sent_at),
             integrations: msg.integrations.clone(),
-            context: Some(modified_context),
+            context: Some(get_context(&msg.context)),
             r#type: String::from("page"),
-            channel: CHANNEL.to_string()
+            channel: CHANNEL.to_string(),
+            message_id: msg.message_id.clone().unwrap_or_else(generate_message_id),
         }
-    );
-    new_message
+    )
 }
 
 // modify screen payload to rudder format
-pub fn parse_screen(msg:&Screen)-> Ruddermessage{
-    let mut modified_context = get_default_context();
-    merge(&mut modified_context, msg.context.clone().unwrap_or(json!({})));
-
-    let original_timestamp;
+pub fn parse_screen(msg: &Screen) -> Ruddermessage {
     let sent_at = Utc::now();
-    if msg.original_timestamp==Option::None {
-        original_timestamp = Some(sent_at);
-    }else {
-        original_timestamp = msg.original_timestamp;
-    }
+    let original_timestamp = msg.original_timestamp.or(Some(sent_at));
 
-    let new_message= Ruddermessage::Screen(
+    Ruddermessage::Screen(
         Rudderscreen {
             user_id: msg.user_id.clone(),
             anonymous_id: msg.anonymous_id.clone(),
             name: msg.name.clone(),
             properties: msg.properties.clone(),
-            original_timestamp: original_timestamp,
+            original_timestamp,
             sent_at: Some(sent_at),
             integrations: msg.integrations.clone(),
-            context: Some(modified_context),
+            context: Some(get_context(&msg.context)),
             r#type: String::from("screen"),
-            channel: CHANNEL.to_string()
+            channel: CHANNEL.to_string(),
+            message_id: msg.message_id.clone().unwrap_or_else(generate_message_id),
         }
-    );
-    new_message
+    )
 }
 
 // modify group payload to rudder format
-pub fn parse_group(msg:&Group)-> Ruddermessage{
-    let mut modified_context = get_default_context();
-    merge(&mut modified_context, msg.context.clone().unwrap_or(json!({})));
-
-    let original_timestamp;
+pub fn parse_group(msg: &Group) -> Ruddermessage {
     let sent_at = Utc::now();
-    if msg.original_timestamp==Option::None {
-        original_timestamp = Some(sent_at);
-    }else {
-        original_timestamp = msg.original_timestamp;
-    }
+    let original_timestamp = msg.original_timestamp.or(Some(sent_at));
 
-    let new_message= Ruddermessage::Group(
+    Ruddermessage::Group(
         Ruddergroup {
             user_id: msg.user_id.clone(),
             anonymous_id: msg.anonymous_id.clone(),
             group_id: msg.group_id.clone(),
             traits: msg.traits.clone(),
-            original_timestamp: original_timestamp,
+            original_timestamp,
             sent_at: Some(sent_at),
             integrations: msg.integrations.clone(),
-            context: Some(modified_context),
+            context: Some(get_context(&msg.context)),
             r#type: String::from("group"),
-            channel: CHANNEL.to_string()
+            channel: CHANNEL.to_string(),
+            message_id: msg.message_id.clone().unwrap_or_else(generate_message_id),
         }
-    );
-    new_message
+    )
 }
 
 // modify alias payload to rudder format
-pub fn parse_alias(msg:&Alias)-> Ruddermessage{
-    let mut modified_context = get_default_context();
-    merge(&mut modified_context, msg.context.clone().unwrap_or(json!({})));
-
-    let original_timestamp;
+pub fn parse_alias(msg: &Alias) -> Ruddermessage {
     let sent_at = Utc::now();
-    if msg.original_timestamp==Option::None {
-        original_timestamp = Some(sent_at);
-    }else {
-        original_timestamp = msg.original_timestamp;
-    }
+    let original_timestamp = msg.original_timestamp.or(Some(sent_at));
 
-    let new_message= Ruddermessage::Alias(
+    Ruddermessage::Alias(
         Rudderalias {
             user_id: msg.user_id.clone(),
             previous_id: msg.previous_id.clone(),
             traits: msg.traits.clone(),
-            original_timestamp: original_timestamp,
+            original_timestamp,
             sent_at: Some(sent_at),
             integrations: msg.integrations.clone(),
-            context: Some(modified_context),
+            context: Some(get_context(&msg.context)),
             r#type: String::from("alias"),
-            channel: CHANNEL.to_string()
+            channel: CHANNEL.to_string(),
+            message_id: msg.message_id.clone().unwrap_or_else(generate_message_id),
         }
-    );
-    new_message
+    )
 }
 
-// modify batch payload to rudder format
-pub fn parse_batch(msg:&Batch)-> Ruddermessage{
-    let mut modified_context = get_default_context();
-    merge(&mut modified_context, msg.context.clone().unwrap_or(json!({})));
+// modify a single message of a batch to rudder format
+// context is the already merged batch level context, the message's own
+// context is merged on top of it. The same goes for integrations, and the
+// batch's timestamp is only used if the message has none of its own.
+pub fn parse_batch_message(
+    msg: &BatchMessage,
+    context: &Value,
+    integrations: &Option<Value>,
+    original_timestamp: Option<DateTime<Utc>>,
+    sent_at: DateTime<Utc>,
+) -> Rudderbatchmessage {
+    let merge_context = |own: &Option<Context>| {
+        let mut context = context.clone();
+        merge(&mut context, context_value(own.as_ref()));
+        context
+    };
+    let merge_integrations = |own: &Option<Value>| match (integrations, own) {
+        (None, own) => own.clone(),
+        (Some(integrations), None) => Some(integrations.clone()),
+        (Some(integrations), Some(own)) => {
+            let mut integrations = integrations.clone();
+            merge(&mut integrations, own.clone());
+            Some(integrations)
+        }
+    };
 
-    let original_timestamp;
-    let sent_at = Utc::now();
-    if msg.original_timestamp==Option::None {
-        original_timestamp = Some(sent_at);
-    }else {
-        original_timestamp = msg.original_timestamp;
+    match msg {
+        BatchMessage::Identify(a_) => {
+            Rudderbatchmessage::Identify(Rudderidentify {
+                user_id: a_.user_id.clone(),
+                anonymous_id: a_.anonymous_id.clone(),
+                traits: a_.traits.clone(),
+                original_timestamp: a_.original_timestamp.or(original_timestamp),
+                sent_at: Some(sent_at),
+                integrations: merge_integrations(&a_.integrations),
+                context: Some(merge_context(&a_.context)),
+                r#type: String::from("identify"),
+                channel: CHANNEL.to_string(),
+                message_id: a_.message_id.clone().unwrap_or_else(generate_message_id),
+            })
+        },
+        BatchMessage::Track(a_) => {
+            Rudderbatchmessage::Track(Ruddertrack {
+                user_id: a_.user_id.clone(),
+                anonymous_id: a_.anonymous_id.clone(),
+                event: a_.event.clone(),
+                properties: a_.properties.clone(),
+                original_timestamp: a_.original_timestamp.or(original_timestamp),
+                sent_at: Some(sent_at),
+                integrations: merge_integrations(&a_.integrations),
+                context: Some(merge_context(&a_.context)),
+                r#type: String::from("track"),
+                channel: CHANNEL.to_string(),
+                message_id: a_.message_id.clone().unwrap_or_else(generate_message_id),
+            })
+        },
+        BatchMessage::Page(a_) => {
+            Rudderbatchmessage::Page(Rudderpage {
+                user_id: a_.user_id.clone(),
+                anonymous_id: a_.anonymous_id.clone(),
+                name: a_.name.clone(),
+                properties: a_.properties.clone(),
+                original_timestamp: a_.original_timestamp.or(original_timestamp),
+                sent_at: Some(sent_at),
+                integrations: merge_integrations(&a_.integrations),
+                context: Some(merge_context(&a_.context)),
+                r#type: String::from("page"),
+                channel: CHANNEL.to_string(),
+                message_id: a_.message_id.clone().unwrap_or_else(generate_message_id),
+            })
+        },
+        BatchMessage::Screen(a_) => {
+            Rudderbatchmessage::Screen(Rudderscreen {
+                user_id: a_.user_id.clone(),
+                anonymous_id: a_.anonymous_id.clone(),
+                name: a_.name.clone(),
+                properties: a_.properties.clone(),
+                original_timestamp: a_.original_timestamp.or(original_timestamp),
+                sent_at: Some(sent_at),
+                integrations: merge_integrations(&a_.integrations),
+                context: Some(merge_context(&a_.context)),
+                r#type: String::from("screen"),
+                channel: CHANNEL.to_string(),
+                message_id: a_.message_id.clone().unwrap_or_else(generate_message_id),
+            })
+        },
+        BatchMessage::Group(a_) => {
+            Rudderbatchmessage::Group(Ruddergroup {
+                user_id: a_.user_id.clone(),
+                anonymous_id: a_.anonymous_id.clone(),
+                group_id: a_.group_id.clone(),
+                traits: a_.traits.clone(),
+                original_timestamp: a_.original_timestamp.or(original_timestamp),
+                sent_at: Some(sent_at),
+                integrations: merge_integrations(&a_.integrations),
+                context: Some(merge_context(&a_.context)),
+                r#type: String::from("group"),
+                channel: CHANNEL.to_string(),
+                message_id: a_.message_id.clone().unwrap_or_else(generate_message_id),
+            })
+        },
+        BatchMessage::Alias(a_) => {
+            Rudderbatchmessage::Alias(Rudderalias {
+                user_id: a_.user_id.clone(),
+                previous_id: a_.previous_id.clone(),
+                traits: a_.traits.clone(),
+                original_timestamp: a_.original_timestamp.or(original_timestamp),
+                sent_at: Some(sent_at),
+                integrations: merge_integrations(&a_.integrations),
+                context: Some(merge_context(&a_.context)),
+                r#type: String::from("alias"),
+                channel: CHANNEL.to_string(),
+                message_id: a_.message_id.clone().unwrap_or_else(generate_message_id),
+            })
+        },
     }
+}
 
-    let mut batch:Vec<Rudderbatchmessage> = Vec::new();
+// modify batch payload to rudder format
+pub fn parse_batch(msg: &Batch) -> Ruddermessage {
+    let modified_context = get_context(&msg.context);
+    let sent_at = Utc::now();
+    let original_timestamp = msg.original_timestamp.or(Some(sent_at));
 
-    for i in &msg.batch {
-        match i {
-            BatchMessage::Identify(a_) =>{
-                batch.push(Rudderbatchmessage::Identify(Rudderidentify 
-                {
-                    user_id: a_.user_id.clone(),
-                    anonymous_id: a_.anonymous_id.clone(),
-                    traits: a_.traits.clone(),
-                    original_timestamp: original_timestamp,
-                    sent_at: Some(sent_at),
-                    integrations: a_.integrations.clone(),
-                    context: Some(modified_context.clone()),
-                    r#type: String::from("identify"),
-                    channel: CHANNEL.to_string()
-                }));
-            },           
-            BatchMessage::Track(a_) =>{
-                batch.push(Rudderbatchmessage::Track(
-                    Ruddertrack {
-                        user_id: a_.user_id.clone(),
-                        anonymous_id: a_.anonymous_id.clone(),
-                        event: a_.event.clone(),
-                        properties: a_.properties.clone(),
-                        original_timestamp: original_timestamp,
-                        sent_at: Some(sent_at),
-                        integrations: a_.integrations.clone(),
-                        context: Some(modified_context.clone()),
-                        r#type: String::from("track"),
-                        channel: CHANNEL.to_string()
-                    }
-                ));
-            },           
-            BatchMessage::Page(a_) =>{
-                batch.push(Rudderbatchmessage::Page(
-                    Rudderpage {
-                        user_id: a_.user_id.clone(),
-                        anonymous_id: a_.anonymous_id.clone(),
-                        name: a_.name.clone(),
-                        properties: a_.properties.clone(),
-                        original_timestamp: original_timestamp,
-                        sent_at: Some(sent_at),
-                        integrations: a_.integrations.clone(),
-                        context: Some(modified_context.clone()),
-                        r#type: String::from("page"),
-                        channel: CHANNEL.to_string()
-                    }
-                ));
-            },           
-            BatchMessage::Screen(a_) =>{
-                batch.push(Rudderbatchmessage::Screen(
-                    Rudderscreen {
-                        user_id: a_.user_id.clone(),
-                        anonymous_id: a_.anonymous_id.clone(),
-                        name: a_.name.clone(),
-                        properties: a_.properties.clone(),
-                        original_timestamp: original_timestamp,
-                        sent_at: Some(sent_at),
-                        integrations: a_.integrations.clone(),
-                        context: Some(modified_context.clone()),
-                        r#type: String::from("screen"),
-                        channel: CHANNEL.to_string()
-                    }
-                ));
-            },           
-            BatchMessage::Group(a_) =>{
-                batch.push(Rudderbatchmessage::Group(
-                    Ruddergroup {
-                        user_id: a_.user_id.clone(),
-                        anonymous_id: a_.anonymous_id.clone(),
-                        group_id: a_.group_id.clone(),
-                        traits: a_.traits.clone(),
-                        original_timestamp: original_timestamp,
-                        sent_at: Some(sent_at),
-                        integrations: a_.integrations.clone(),
-                        context: Some(modified_context.clone()),
-                        r#type: String::from("group"),
-                        channel: CHANNEL.to_string()
-                    }
-                ));
-            },           
-            BatchMessage::Alias(a_) =>{
-                batch.push(Rudderbatchmessage::Alias(
-                    Rudderalias {
-                        user_id: a_.user_id.clone(),
-                        previous_id: a_.previous_id.clone(),
-                        traits: a_.traits.clone(),
-                        original_timestamp: original_timestamp,
-                        sent_at: Some(sent_at),
-                        integrations: a_.integrations.clone(),
-                        context: Some(modified_context.clone()),
-                        r#type: String::from("alias"),
-                        channel: CHANNEL.to_string()
-                    }
-                ));
-            },           
-        }
-    }
+    let batch: Vec<Rudderbatchmessage> = msg
+        .batch
+        .iter()
+        .map(|i| {
+            parse_batch_message(
+                i,
+                &modified_context,
+                &msg.integrations,
+                original_timestamp,
+                sent_at,
+            )
+        })
+        .collect();
 
-    let new_message= Ruddermessage::Batch(
+    Ruddermessage::Batch(
         Rudderbatch {
-            batch: batch,
+            batch,
             integrations: msg.integrations.clone(),
             context: Some(modified_context),
             r#type: String::from("batch"),
-            original_timestamp: original_timestamp,
+            original_timestamp,
             sent_at: Some(sent_at),
         }
-    );
-    new_message
+    )
 }
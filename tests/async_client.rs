@@ -0,0 +1,153 @@
+use rudderanalytics::async_client::AsyncRudderAnalytics;
+use rudderanalytics::client::RudderAnalytics;
+use rudderanalytics::errors::Error as AnalyticsError;
+use rudderanalytics::message::{Batch, BatchMessage, Message, Track};
+use rudderanalytics::retry::RetryPolicy;
+use rudderanalytics::test_server::{Failure, TestServer};
+use serde_json::json;
+use std::time::Duration;
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Nothing listens on the discard port, so every send fails fast
+    fn unreachable_client() -> AsyncRudderAnalytics {
+        AsyncRudderAnalytics::load("WRITE-KEY".to_string(), "http://127.0.0.1:9".to_string())
+    }
+
+    fn track() -> Track {
+        Track {
+            user_id: Some("foo".to_string()),
+            event: "Foo".to_owned(),
+            properties: Some(json!({ "plan": "pro" })),
+            ..Default::default()
+        }
+    }
+
+    fn fast_retries() -> RetryPolicy {
+        RetryPolicy {
+            base_delay: Duration::from_millis(1),
+            ..Default::default()
+        }
+    }
+
+    #[tokio::test]
+    async fn test_send() {
+        let server = TestServer::start("WRITE-KEY").unwrap();
+        let rudder_analytics = AsyncRudderAnalytics::load("WRITE-KEY".to_string(), server.url());
+
+        let delivery = rudder_analytics
+            .send(&Message::Track(track()))
+            .await
+            .unwrap();
+        assert_eq!(delivery.attempts, 1);
+
+        let requests = server.requests();
+        assert_eq!(requests.len(), 1);
+        assert_eq!(requests[0].path, "/v1/track");
+        let payload = requests[0].json().unwrap();
+        assert_eq!(payload["userId"], "foo");
+        assert_eq!(payload["event"], "Foo");
+        assert_eq!(payload["properties"]["plan"], "pro");
+        assert_eq!(payload["context"]["library"]["name"], "RudderStack Rust SDK");
+    }
+
+    #[tokio::test]
+    async fn test_send_batch() {
+        let server = TestServer::start("WRITE-KEY").unwrap();
+        let rudder_analytics = AsyncRudderAnalytics::load("WRITE-KEY".to_string(), server.url());
+
+        let delivery = rudder_analytics
+            .send(&Message::Batch(Batch {
+                batch: vec![BatchMessage::Track(track()), BatchMessage::Track(track())],
+                ..Default::default()
+            }))
+            .await
+            .unwrap();
+        assert_eq!(delivery.batch.unwrap().accepted, 2);
+        assert_eq!(server.requests()[0].path, "/v1/batch");
+        assert_eq!(server.events().len(), 2);
+    }
+
+    #[tokio::test]
+    async fn test_server_errors_are_retried() {
+        let server = TestServer::start("WRITE-KEY").unwrap();
+        server.fail_next(Failure::status(500));
+        server.fail_next(Failure::status(503));
+        let rudder_analytics = RudderAnalytics::builder("WRITE-KEY", server.url())
+            .retry_policy(fast_retries())
+            .build_async()
+            .unwrap();
+
+        let delivery = rudder_analytics
+            .send(&Message::Track(track()))
+            .await
+            .unwrap();
+        assert_eq!(delivery.attempts, 3);
+        let statuses: Vec<Option<u16>> = server.requests().iter().map(|r| r.status).collect();
+        assert_eq!(statuses, vec![Some(500), Some(503), Some(200)]);
+    }
+
+    #[tokio::test]
+    async fn test_client_errors_are_not_retried() {
+        let server = TestServer::start("WRITE-KEY").unwrap();
+        server.fail_next(Failure::status(400));
+        let rudder_analytics = RudderAnalytics::builder("WRITE-KEY", server.url())
+            .retry_policy(fast_retries())
+            .build_async()
+            .unwrap();
+
+        let err = rudder_analytics
+            .send(&Message::Track(track()))
+            .await
+            .err()
+            .unwrap();
+        assert!(matches!(err, AnalyticsError::HttpStatus { status: 400, .. }));
+        assert_eq!(server.requests().len(), 1);
+    }
+
+    #[tokio::test]
+    async fn test_gzip() {
+        let server = TestServer::start("WRITE-KEY").unwrap();
+        let rudder_analytics = RudderAnalytics::builder("WRITE-KEY", server.url())
+            .gzip(true)
+            .build_async()
+            .unwrap();
+
+        rudder_analytics
+            .send(&Message::Track(track()))
+            .await
+            .unwrap();
+        let request = &server.requests()[0];
+        assert_eq!(request.header("content-encoding"), Some("gzip"));
+        // The server decompresses the body it records
+        assert_eq!(request.json().unwrap()["event"], "Foo");
+    }
+
+    #[tokio::test]
+    async fn test_send_validates_identity() {
+        let err = unreachable_client()
+            .send(&Message::Track(Track {
+                event: "Foo".to_owned(),
+                ..Default::default()
+            }))
+            .await
+            .err()
+            .unwrap();
+        assert!(matches!(err, AnalyticsError::MissingIdentity));
+    }
+
+    #[tokio::test]
+    async fn test_send_inside_runtime() {
+        let result = unreachable_client()
+            .send(&Message::Track(Track {
+                user_id: Some("foo".to_string()),
+                event: "Foo".to_owned(),
+                ..Default::default()
+            }))
+            .await;
+        // The connection is refused, but the runtime is not blocked or panicking
+        assert!(result.is_err());
+    }
+}
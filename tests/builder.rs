@@ -0,0 +1,123 @@
+use chrono::{TimeZone, Utc};
+use rudderanalytics::context::{Context, Library};
+use rudderanalytics::errors::Error as AnalyticsError;
+use rudderanalytics::message::{Alias, Group, Identify, Page, Screen, Track};
+use serde_json::json;
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_track_builder() {
+        let timestamp = Utc.with_ymd_and_hms(2022, 1, 1, 0, 0, 0).unwrap();
+        let track = Track::builder("Order Completed")
+            .user_id("user-1")
+            .property("revenue", 42.0)
+            .property("currency", "EUR")
+            .context_ip("10.0.0.1")
+            .context_locale("en-US")
+            .integration("All", true)
+            .integration("Amplitude", false)
+            .original_timestamp(timestamp)
+            .message_id("message-1")
+            .build()
+            .unwrap();
+
+        assert_eq!(
+            track,
+            Track {
+                user_id: Some("user-1".to_owned()),
+                event: "Order Completed".to_owned(),
+                properties: Some(json!({ "revenue": 42.0, "currency": "EUR" })),
+                original_timestamp: Some(timestamp),
+                context: Some(Context {
+                    ip: Some("10.0.0.1".to_owned()),
+                    locale: Some("en-US".to_owned()),
+                    ..Default::default()
+                }),
+                integrations: Some(json!({ "All": true, "Amplitude": false })),
+                message_id: Some("message-1".to_owned()),
+                ..Default::default()
+            }
+        );
+    }
+
+    #[test]
+    fn test_builders_set_required_fields() {
+        let page = Page::builder("Pricing")
+            .anonymous_id("anon-1")
+            .build()
+            .unwrap();
+        assert_eq!(page.name, "Pricing");
+
+        let screen = Screen::builder("Settings")
+            .user_id("user-1")
+            .build()
+            .unwrap();
+        assert_eq!(screen.name, "Settings");
+
+        let group = Group::builder("acme")
+            .user_id("user-1")
+            .set_trait("plan", "enterprise")
+            .build()
+            .unwrap();
+        assert_eq!(group.group_id, "acme");
+        assert_eq!(group.traits, Some(json!({ "plan": "enterprise" })));
+
+        // Alias has its identity passed to the constructor
+        let alias = Alias::builder("user-1", "anon-1").build().unwrap();
+        assert_eq!(alias.user_id, "user-1");
+        assert_eq!(alias.previous_id, "anon-1");
+    }
+
+    #[test]
+    fn test_single_values_replace_non_objects() {
+        let identify = Identify::builder()
+            .user_id("user-1")
+            .traits(json!("not an object"))
+            .set_trait("name", "Test User")
+            .build()
+            .unwrap();
+        assert_eq!(identify.traits, Some(json!({ "name": "Test User" })));
+    }
+
+    #[test]
+    fn test_missing_identity_is_rejected() {
+        let err = Track::builder("Foo")
+            .property("foo", "bar")
+            .build()
+            .err()
+            .unwrap();
+        assert!(matches!(err, AnalyticsError::MissingIdentity));
+
+        let err = Identify::builder().build().err().unwrap();
+        assert!(matches!(err, AnalyticsError::MissingIdentity));
+    }
+
+    #[test]
+    fn test_reserved_context_is_rejected() {
+        let context = Context {
+            library: Some(Library {
+                name: Some("other".to_owned()),
+                ..Default::default()
+            }),
+            ..Default::default()
+        };
+
+        let err = Track::builder("Foo")
+            .user_id("user-1")
+            .context(context.clone())
+            .build()
+            .err()
+            .unwrap();
+        assert!(matches!(err, AnalyticsError::ReservedKeyword(_)));
+
+        let err = Alias::builder("user-1", "anon-1")
+            .context(context)
+            .build()
+            .err()
+            .unwrap();
+        assert!(matches!(err, AnalyticsError::ReservedKeyword(_)));
+    }
+}
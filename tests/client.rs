@@ -0,0 +1,218 @@
+use rudderanalytics::client::RudderAnalytics;
+use rudderanalytics::context::{Campaign, Context};
+use rudderanalytics::errors::Error as AnalyticsError;
+use rudderanalytics::message::{Batch, BatchMessage, Message, Track};
+use rudderanalytics::retry::RetryPolicy;
+use rudderanalytics::test_server::{Failure, TestServer};
+use serde_json::json;
+use std::time::Duration;
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chrono::{TimeZone, Utc};
+
+    #[test]
+    fn test_size_limits_apply_before_compression() {
+        // Nothing listens on the discard port; the message must be rejected
+        // before any request is made.
+        let mut rudder_analytics =
+            RudderAnalytics::load("WRITE-KEY".to_string(), "http://127.0.0.1:9".to_string());
+        rudder_analytics.gzip = true;
+
+        // Compresses to a few hundred bytes, but is over 32 KiB uncompressed
+        let err = rudder_analytics
+            .send(&Message::Track(Track {
+                user_id: Some(String::from_utf8(vec![b'a'; 1024 * 33]).unwrap()),
+                event: "Foo".to_owned(),
+                ..Default::default()
+            }))
+            .err()
+            .unwrap();
+        assert!(matches!(err, AnalyticsError::MessageTooLarge { .. }));
+    }
+
+    #[test]
+    fn test_builder() {
+        let rudder_analytics = RudderAnalytics::builder("WRITE-KEY", "http://127.0.0.1:9")
+            .connect_timeout(Duration::from_secs(1))
+            .timeout(Duration::from_secs(5))
+            .https_proxy("http://127.0.0.1:3128")
+            .header("X-Gateway-Token", "secret")
+            .user_agent("my-service/1.0")
+            .pool_idle_timeout(Duration::from_secs(30))
+            .pool_max_idle_per_host(4)
+            .retry_policy(RetryPolicy::none())
+            .gzip(true)
+            .build()
+            .unwrap();
+
+        assert_eq!(rudder_analytics.write_key, "WRITE-KEY");
+        assert_eq!(rudder_analytics.retry_policy, RetryPolicy::none());
+        assert!(rudder_analytics.gzip);
+    }
+
+    #[test]
+    fn test_builder_rejects_invalid_settings() {
+        assert!(RudderAnalytics::builder("WRITE-KEY", "http://127.0.0.1:9")
+            .header("Invalid Header", "value")
+            .build()
+            .is_err());
+        assert!(RudderAnalytics::builder("WRITE-KEY", "http://127.0.0.1:9")
+            .https_proxy("not a url")
+            .build()
+            .is_err());
+    }
+
+    #[test]
+    fn test_batch_keeps_message_context_and_timestamps() {
+        let server = TestServer::start("WRITE-KEY").unwrap();
+        let rudder_analytics = RudderAnalytics::load("WRITE-KEY".to_string(), server.url());
+
+        let batch_timestamp = Utc.with_ymd_and_hms(2022, 1, 1, 0, 0, 0).unwrap();
+        let message_timestamp = Utc.with_ymd_and_hms(2021, 6, 1, 12, 0, 0).unwrap();
+        rudder_analytics
+            .send(&Message::Batch(Batch {
+                batch: vec![
+                    BatchMessage::Track(Track {
+                        user_id: Some("foo".to_string()),
+                        event: "Foo".to_owned(),
+                        original_timestamp: Some(message_timestamp),
+                        context: Some(Context {
+                            ip: Some("10.0.0.1".to_owned()),
+                            campaign: Some(Campaign {
+                                name: Some("spring".to_owned()),
+                                ..Default::default()
+                            }),
+                            ..Default::default()
+                        }),
+                        integrations: Some(json!({ "Amplitude": false })),
+                        ..Default::default()
+                    }),
+                    BatchMessage::Track(Track {
+                        user_id: Some("bar".to_string()),
+                        event: "Bar".to_owned(),
+                        ..Default::default()
+                    }),
+                ],
+                context: Some(Context {
+                    locale: Some("en-US".to_owned()),
+                    campaign: Some(Campaign {
+                        source: Some("newsletter".to_owned()),
+                        ..Default::default()
+                    }),
+                    ..Default::default()
+                }),
+                integrations: Some(json!({ "All": true })),
+                original_timestamp: Some(batch_timestamp),
+            }))
+            .unwrap();
+
+        let payload = &server.payloads()[0];
+        let first = &payload["batch"][0];
+        assert_eq!(first["context"]["ip"], "10.0.0.1");
+        assert_eq!(first["context"]["locale"], "en-US");
+        assert_eq!(
+            first["context"]["campaign"],
+            json!({ "name": "spring", "source": "newsletter" })
+        );
+        assert_eq!(first["context"]["library"]["name"], "RudderStack Rust SDK");
+        assert_eq!(
+            first["integrations"],
+            json!({ "All": true, "Amplitude": false })
+        );
+        assert_eq!(first["originalTimestamp"], json!(message_timestamp));
+
+        let second = &payload["batch"][1];
+        assert_eq!(second["context"]["locale"], "en-US");
+        assert!(second["context"].get("ip").is_none());
+        assert_eq!(second["integrations"], json!({ "All": true }));
+        assert_eq!(second["originalTimestamp"], json!(batch_timestamp));
+    }
+
+    #[test]
+    fn test_message_ids_are_generated() {
+        let server = TestServer::start("WRITE-KEY").unwrap();
+        let rudder_analytics = RudderAnalytics::load("WRITE-KEY".to_string(), server.url());
+
+        rudder_analytics
+            .send(&Message::Batch(Batch {
+                batch: vec![
+                    BatchMessage::Track(Track {
+                        user_id: Some("foo".to_string()),
+                        event: "Foo".to_owned(),
+                        ..Default::default()
+                    }),
+                    BatchMessage::Track(Track {
+                        user_id: Some("bar".to_string()),
+                        event: "Bar".to_owned(),
+                        message_id: Some("my-message-id".to_owned()),
+                        ..Default::default()
+                    }),
+                ],
+                ..Default::default()
+            }))
+            .unwrap();
+
+        let payload = &server.payloads()[0];
+        let generated = payload["batch"][0]["messageId"].as_str().unwrap();
+        assert_eq!(generated.len(), 36);
+        assert_eq!(&generated[14..15], "4");
+        assert_eq!(payload["batch"][1]["messageId"], "my-message-id");
+    }
+
+    #[test]
+    fn test_message_id_is_stable_across_retries() {
+        let server = TestServer::start("WRITE-KEY").unwrap();
+        server.fail_next(Failure::status(503));
+        let rudder_analytics = RudderAnalytics::load("WRITE-KEY".to_string(), server.url());
+
+        let delivery = rudder_analytics
+            .send(&Message::Track(Track {
+                user_id: Some("foo".to_string()),
+                event: "Foo".to_owned(),
+                ..Default::default()
+            }))
+            .unwrap();
+        assert_eq!(delivery.attempts, 2);
+
+        let requests = server.requests();
+        let first = requests[0].json().unwrap();
+        let second = requests[1].json().unwrap();
+        assert!(first["messageId"].is_string());
+        assert_eq!(first["messageId"], second["messageId"]);
+    }
+
+    #[test]
+    fn test_batch_outcome() {
+        let server = TestServer::start("WRITE-KEY").unwrap();
+        let rudder_analytics = RudderAnalytics::load("WRITE-KEY".to_string(), server.url());
+
+        let track = BatchMessage::Track(Track {
+            user_id: Some("foo".to_string()),
+            event: "Foo".to_owned(),
+            ..Default::default()
+        });
+        let delivery = rudder_analytics
+            .send(&Message::Batch(Batch {
+                batch: vec![track.clone(), track],
+                ..Default::default()
+            }))
+            .unwrap();
+
+        let outcome = delivery.batch.unwrap();
+        assert_eq!(outcome.accepted, 2);
+        assert!(outcome.rejected.is_empty());
+        assert_eq!(outcome.response_body, "OK");
+
+        // Only batches have an outcome
+        let delivery = rudder_analytics
+            .send(&Message::Track(Track {
+                user_id: Some("foo".to_string()),
+                event: "Foo".to_owned(),
+                ..Default::default()
+            }))
+            .unwrap();
+        assert!(delivery.batch.is_none());
+    }
+}
@@ -0,0 +1,149 @@
+use rudderanalytics::client::RudderAnalytics;
+use rudderanalytics::consent::Consent;
+use rudderanalytics::context::{ConsentManagement, Context};
+use rudderanalytics::message::{Alias, Batch, BatchMessage, Message, Track};
+use rudderanalytics::test_server::TestServer;
+use serde_json::json;
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn choices(allowed: &[&str], denied: &[&str]) -> ConsentManagement {
+        ConsentManagement {
+            provider: Some("custom".to_owned()),
+            allowed_consent_ids: allowed.iter().map(|id| id.to_string()).collect(),
+            denied_consent_ids: denied.iter().map(|id| id.to_string()).collect(),
+            ..Default::default()
+        }
+    }
+
+    fn consent_for_user_1() -> ConsentManagement {
+        choices(&["marketing"], &["analytics"])
+    }
+
+    fn track(user_id: Option<&str>, anonymous_id: Option<&str>) -> Track {
+        Track {
+            user_id: user_id.map(str::to_owned),
+            anonymous_id: anonymous_id.map(str::to_owned),
+            event: "Foo".to_owned(),
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn test_consent_is_injected() {
+        let server = TestServer::start("WRITE-KEY").unwrap();
+        let consent = Consent::new();
+        let rudder_analytics = RudderAnalytics::builder("WRITE-KEY", server.url())
+            .middleware(consent.clone())
+            .build()
+            .unwrap();
+
+        consent.set("user-1", consent_for_user_1());
+        consent.set("anon-2", choices(&["analytics"], &[]));
+        rudder_analytics
+            .send(&Message::Track(track(Some("user-1"), Some("anon-1"))))
+            .unwrap();
+        rudder_analytics
+            .send(&Message::Batch(Batch {
+                batch: vec![
+                    BatchMessage::Track(track(None, Some("anon-2"))),
+                    BatchMessage::Track(track(Some("user-3"), None)),
+                ],
+                ..Default::default()
+            }))
+            .unwrap();
+
+        let events = server.events();
+        assert_eq!(
+            events[0]["context"]["consentManagement"],
+            json!({
+                "provider": "custom",
+                "allowedConsentIds": ["marketing"],
+                "deniedConsentIds": ["analytics"],
+            })
+        );
+        assert_eq!(
+            events[1]["context"]["consentManagement"]["allowedConsentIds"],
+            json!(["analytics"])
+        );
+        assert!(events[2]["context"].get("consentManagement").is_none());
+
+        consent.remove("user-1");
+        assert_eq!(consent.get("user-1"), None);
+        rudder_analytics
+            .send(&Message::Track(track(Some("user-1"), None)))
+            .unwrap();
+        assert!(server.events()[3]["context"]
+            .get("consentManagement")
+            .is_none());
+    }
+
+    #[test]
+    fn test_consent_of_the_message_wins() {
+        let server = TestServer::start("WRITE-KEY").unwrap();
+        let consent = Consent::new();
+        consent.set("user-1", consent_for_user_1());
+        let rudder_analytics = RudderAnalytics::builder("WRITE-KEY", server.url())
+            .middleware(consent)
+            .build()
+            .unwrap();
+
+        rudder_analytics
+            .send(&Message::Track(Track {
+                context: Some(Context {
+                    consent_management: Some(choices(&["analytics"], &[])),
+                    ..Default::default()
+                }),
+                ..track(Some("user-1"), None)
+            }))
+            .unwrap();
+        assert_eq!(
+            server.events()[0]["context"]["consentManagement"]["allowedConsentIds"],
+            json!(["analytics"])
+        );
+    }
+
+    #[test]
+    fn test_denied_consent_suppresses_messages() {
+        let server = TestServer::start("WRITE-KEY").unwrap();
+        let consent = Consent::new().suppress_when_denied("analytics");
+        consent.set("user-1", consent_for_user_1());
+        consent.set("anon-1", consent_for_user_1());
+        let rudder_analytics = RudderAnalytics::builder("WRITE-KEY", server.url())
+            .middleware(consent)
+            .build()
+            .unwrap();
+
+        let delivery = rudder_analytics
+            .send(&Message::Track(track(Some("user-1"), None)))
+            .unwrap();
+        assert_eq!(delivery.attempts, 0);
+
+        // Alias messages are looked up by their previous id as well
+        let delivery = rudder_analytics
+            .send(&Message::Alias(Alias {
+                user_id: "user-2".to_owned(),
+                previous_id: "anon-1".to_owned(),
+                ..Default::default()
+            }))
+            .unwrap();
+        assert_eq!(delivery.attempts, 0);
+
+        let delivery = rudder_analytics
+            .send(&Message::Batch(Batch {
+                batch: vec![
+                    BatchMessage::Track(track(Some("user-1"), None)),
+                    BatchMessage::Track(track(Some("user-2"), None)),
+                ],
+                ..Default::default()
+            }))
+            .unwrap();
+        assert_eq!(delivery.batch.unwrap().dropped, vec![0]);
+
+        let events = server.events();
+        assert_eq!(events.len(), 1);
+        assert_eq!(events[0]["userId"], "user-2");
+    }
+}
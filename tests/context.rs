@@ -0,0 +1,71 @@
+use rudderanalytics::client::RudderAnalytics;
+use rudderanalytics::context::{Context, Device, Library};
+use rudderanalytics::errors::Error as AnalyticsError;
+use rudderanalytics::message::{Message, Track};
+use serde_json::json;
+use std::convert::TryFrom;
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_serialization_round_trip() {
+        let value = json!({
+            "device": { "id": "device-1", "adTrackingEnabled": false, "os": "custom" },
+            "userAgent": "Mozilla/5.0",
+            "tenant": "acme",
+        });
+
+        let context = Context::try_from(value.clone()).unwrap();
+        assert_eq!(context.user_agent.as_deref(), Some("Mozilla/5.0"));
+        assert_eq!(
+            context.device,
+            Some(Device {
+                id: Some("device-1".to_owned()),
+                ad_tracking_enabled: Some(false),
+                extra: json!({ "os": "custom" }).as_object().unwrap().clone(),
+                ..Default::default()
+            })
+        );
+        assert_eq!(context.extra["tenant"], "acme");
+
+        assert_eq!(serde_json::to_value(&context).unwrap(), value);
+    }
+
+    #[test]
+    fn test_invalid_values_are_rejected() {
+        let err = Context::try_from(json!(["not", "an", "object"]))
+            .err()
+            .unwrap();
+        assert!(matches!(err, AnalyticsError::Serialization(_)));
+
+        let err = Context::try_from(json!({ "ip": 42 })).err().unwrap();
+        assert!(matches!(err, AnalyticsError::Serialization(_)));
+    }
+
+    #[test]
+    fn test_library_is_reserved() {
+        // Nothing listens on the discard port; the message must be rejected
+        // before any request is made.
+        let rudder_analytics =
+            RudderAnalytics::load("WRITE-KEY".to_string(), "http://127.0.0.1:9".to_string());
+
+        let err = rudder_analytics
+            .send(&Message::Track(Track {
+                user_id: Some("foo".to_string()),
+                event: "Foo".to_owned(),
+                context: Some(Context {
+                    library: Some(Library {
+                        name: Some("other".to_owned()),
+                        ..Default::default()
+                    }),
+                    ..Default::default()
+                }),
+                ..Default::default()
+            }))
+            .err()
+            .unwrap();
+        assert!(matches!(err, AnalyticsError::ReservedKeyword(_)));
+    }
+}
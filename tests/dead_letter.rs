@@ -0,0 +1,281 @@
+use rudderanalytics::client::{BatchValidation, RudderAnalytics};
+use rudderanalytics::dead_letter::{CallbackSink, DeadLetterSink, JsonlSink, MemorySink};
+use rudderanalytics::errors::Error as AnalyticsError;
+use rudderanalytics::message::{Batch, BatchMessage, Identify, Message, Track};
+use rudderanalytics::queue::QueueConfig;
+use rudderanalytics::retry::RetryPolicy;
+use rudderanalytics::test_server::{Failure, TestServer};
+use serde_json::json;
+use std::fs;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
+use std::time::Duration;
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // A server rejecting the first request with a 400
+    fn rejecting_server() -> TestServer {
+        let server = TestServer::start("WRITE-KEY").unwrap();
+        server.fail_next(Failure::Status {
+            status: 400,
+            body: "Invalid payload".to_owned(),
+        });
+        server
+    }
+
+    fn track() -> Message {
+        Message::Track(Track {
+            user_id: Some("foo".to_string()),
+            event: "Foo".to_owned(),
+            ..Default::default()
+        })
+    }
+
+    #[test]
+    fn test_rejected_message_is_dead_lettered() {
+        let server = rejecting_server();
+        let sink = Arc::new(MemorySink::new(10));
+        let rudder_analytics = RudderAnalytics::builder("WRITE-KEY", server.url())
+            .dead_letter(sink.clone())
+            .build()
+            .unwrap();
+
+        assert!(rudder_analytics.send(&track()).is_err());
+
+        let letters = sink.letters();
+        assert_eq!(letters.len(), 1);
+        let letter = &letters[0];
+        assert_eq!(letter.status, Some(400));
+        assert_eq!(letter.response_body.as_deref(), Some("Invalid payload"));
+        assert_eq!(letter.payload["event"], "Foo");
+        assert_eq!(letter.payload["channel"], "server");
+
+        // The message keeps the id it was sent with
+        match &letter.message {
+            Message::Track(track) => {
+                assert_eq!(
+                    track.message_id.as_deref(),
+                    letter.payload["messageId"].as_str()
+                )
+            }
+            _ => panic!("invalid message type"),
+        }
+    }
+
+    #[test]
+    fn test_dead_letter_keeps_the_ids_of_a_filtered_batch() {
+        let server = rejecting_server();
+        let sink = Arc::new(MemorySink::new(10));
+        let rudder_analytics = RudderAnalytics::builder("WRITE-KEY", server.url())
+            .batch_validation(BatchValidation::DropInvalid)
+            .dead_letter(sink.clone())
+            .build()
+            .unwrap();
+        let batch = Message::Batch(Batch {
+            batch: vec![
+                // no identity
+                BatchMessage::Track(Track {
+                    event: "Invalid".to_owned(),
+                    ..Default::default()
+                }),
+                BatchMessage::Track(Track {
+                    user_id: Some("foo".to_string()),
+                    event: "A".to_owned(),
+                    ..Default::default()
+                }),
+                BatchMessage::Track(Track {
+                    user_id: Some("foo".to_string()),
+                    event: "B".to_owned(),
+                    ..Default::default()
+                }),
+            ],
+            ..Default::default()
+        });
+        assert!(rudder_analytics.send(&batch).is_err());
+
+        let letter = sink.letters().remove(0);
+        let sent = letter.payload["batch"].as_array().unwrap();
+        assert_eq!(sent.len(), 2);
+        let batch = match &letter.message {
+            Message::Batch(batch) => batch,
+            _ => panic!("invalid message type"),
+        };
+        for sent in sent {
+            let msg = batch
+                .batch
+                .iter()
+                .find_map(|msg| match msg {
+                    BatchMessage::Track(track) if track.event == sent["event"] => Some(track),
+                    _ => None,
+                })
+                .unwrap();
+            assert_eq!(msg.message_id.as_deref(), sent["messageId"].as_str());
+        }
+    }
+
+    #[test]
+    fn test_exhausted_retries_are_dead_lettered() {
+        let calls = Arc::new(AtomicUsize::new(0));
+        let counter = calls.clone();
+        let rudder_analytics = RudderAnalytics::builder("WRITE-KEY", "http://127.0.0.1:9")
+            .retry_policy(RetryPolicy {
+                max_attempts: 2,
+                base_delay: std::time::Duration::from_millis(1),
+                ..Default::default()
+            })
+            .dead_letter(Arc::new(CallbackSink::new(move |letter| {
+                assert_eq!(letter.status, None);
+                assert!(letter.error.starts_with("giving up after 2 attempts"));
+                counter.fetch_add(1, Ordering::SeqCst);
+            })))
+            .build()
+            .unwrap();
+
+        assert!(rudder_analytics.send(&track()).is_err());
+        assert_eq!(calls.load(Ordering::SeqCst), 1);
+    }
+
+    #[test]
+    fn test_oversized_message_is_dead_lettered() {
+        let server = TestServer::start("WRITE-KEY").unwrap();
+        let sink = Arc::new(MemorySink::new(10));
+        let rudder_analytics = RudderAnalytics::builder("WRITE-KEY", server.url())
+            .dead_letter(sink.clone())
+            .build()
+            .unwrap();
+
+        let err = rudder_analytics
+            .send(&Message::Track(Track {
+                user_id: Some("foo".to_string()),
+                event: "Foo".to_owned(),
+                properties: Some(json!({ "padding": "x".repeat(40 * 1024) })),
+                ..Default::default()
+            }))
+            .err()
+            .unwrap();
+        assert!(matches!(err, AnalyticsError::MessageTooLarge { .. }));
+        assert!(server.requests().is_empty());
+
+        let letters = sink.letters();
+        assert_eq!(letters.len(), 1);
+        assert_eq!(letters[0].status, None);
+        assert_eq!(letters[0].error, err.to_string());
+        assert_eq!(letters[0].payload["event"], "Foo");
+    }
+
+    #[test]
+    fn test_rate_limited_message_is_dead_lettered() {
+        let server = TestServer::start("WRITE-KEY").unwrap();
+        server.fail_next(Failure::RateLimited {
+            retry_after: Some(120),
+        });
+        let sink = Arc::new(MemorySink::new(10));
+        let rudder_analytics = RudderAnalytics::builder("WRITE-KEY", server.url())
+            .dead_letter(sink.clone())
+            .build()
+            .unwrap();
+
+        assert!(rudder_analytics.send(&track()).is_err());
+        let letters = sink.letters();
+        assert_eq!(letters.len(), 1);
+        assert_eq!(letters[0].error, "rate limited by the data plane");
+    }
+
+    #[test]
+    fn test_queued_rate_limited_message_is_not_dead_lettered() {
+        let server = TestServer::start("WRITE-KEY").unwrap();
+        server.fail_next(Failure::RateLimited {
+            retry_after: Some(1),
+        });
+        let sink = Arc::new(MemorySink::new(10));
+        let queue = RudderAnalytics::builder("WRITE-KEY", server.url())
+            .retry_policy(RetryPolicy::none())
+            .dead_letter(sink.clone())
+            .build()
+            .unwrap()
+            .queued(QueueConfig {
+                flush_interval: Duration::from_secs(3600),
+                ..Default::default()
+            })
+            .unwrap();
+
+        queue
+            .enqueue(BatchMessage::Track(Track {
+                user_id: Some("foo".to_string()),
+                event: "Foo".to_owned(),
+                ..Default::default()
+            }))
+            .unwrap();
+        assert!(queue.flush().is_err());
+        // The worker sends the batch again before stopping
+        queue.shutdown();
+        assert!(sink.letters().is_empty());
+        assert_eq!(server.events().len(), 1);
+    }
+
+    #[test]
+    fn test_memory_sink_keeps_the_latest_letters() {
+        let server = rejecting_server();
+        let sink = Arc::new(MemorySink::new(1));
+        let rudder_analytics = RudderAnalytics::builder("WRITE-KEY", server.url())
+            .retry_policy(RetryPolicy::none())
+            .dead_letter(sink.clone())
+            .build()
+            .unwrap();
+        assert!(rudder_analytics.send(&track()).is_err());
+        let letter = sink.letters().remove(0);
+
+        sink.store(&letter).unwrap();
+        assert_eq!(sink.drain(), vec![letter]);
+        assert!(sink.letters().is_empty());
+    }
+
+    #[test]
+    fn test_jsonl_sink_and_redrive() {
+        let path = std::env::temp_dir().join(format!(
+            "rudderanalytics-dead-letters-{}.jsonl",
+            std::process::id()
+        ));
+        let _ = fs::remove_file(&path);
+
+        let server = rejecting_server();
+        server.fail_next(Failure::status(400));
+        let sink = Arc::new(JsonlSink::open(&path).unwrap());
+        let rudder_analytics = RudderAnalytics::builder("WRITE-KEY", server.url())
+            .dead_letter(sink.clone())
+            .build()
+            .unwrap();
+        let batch = Message::Batch(Batch {
+            batch: vec![BatchMessage::Identify(Identify {
+                user_id: Some("foo".to_string()),
+                ..Default::default()
+            })],
+            ..Default::default()
+        });
+        assert!(rudder_analytics.send(&track()).is_err());
+        assert!(rudder_analytics.send(&batch).is_err());
+
+        // Messages are read back as the same kind of message
+        let letters = JsonlSink::read(&path).unwrap();
+        assert_eq!(letters.len(), 2);
+        assert!(matches!(letters[0].message, Message::Track(_)));
+        assert!(matches!(letters[1].message, Message::Batch(_)));
+
+        let failed = rudder_analytics.redrive(sink.drain().unwrap().into_iter().take(1));
+        assert!(failed.is_empty());
+        assert_eq!(
+            server.payloads()[0]["messageId"],
+            letters[0].payload["messageId"]
+        );
+        assert!(JsonlSink::read(&path).unwrap().is_empty());
+
+        // Letters which fail again are returned
+        server.fail_next(Failure::status(400));
+        let failed = rudder_analytics.redrive(letters.into_iter().skip(1));
+        assert_eq!(failed.len(), 1);
+        assert_eq!(failed[0].status, Some(400));
+        fs::remove_file(path).unwrap();
+    }
+}
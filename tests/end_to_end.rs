@@ -0,0 +1,180 @@
+use rudderanalytics::client::RudderAnalytics;
+use rudderanalytics::errors::Error as AnalyticsError;
+use rudderanalytics::message::{
+    Alias, Batch, BatchMessage, Group, Identify, Message, Page, Screen, Track,
+};
+use rudderanalytics::retry::RetryPolicy;
+use rudderanalytics::test_server::{Failure, TestServer};
+use serde_json::json;
+use std::time::{Duration, Instant};
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn track() -> Message {
+        Message::Track(Track {
+            user_id: Some("foo".to_string()),
+            event: "Foo".to_owned(),
+            ..Default::default()
+        })
+    }
+
+    fn fast_retries() -> RetryPolicy {
+        RetryPolicy {
+            base_delay: Duration::from_millis(1),
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn test_every_message_type() {
+        let server = TestServer::start("WRITE-KEY").unwrap();
+        let rudder_analytics = RudderAnalytics::load("WRITE-KEY".to_string(), server.url());
+        let user_id = Some("foo".to_string());
+
+        let messages = vec![
+            Message::Identify(Identify {
+                user_id: user_id.clone(),
+                traits: Some(json!({ "name": "Foo" })),
+                ..Default::default()
+            }),
+            track(),
+            Message::Page(Page {
+                user_id: user_id.clone(),
+                name: "Home".to_owned(),
+                ..Default::default()
+            }),
+            Message::Screen(Screen {
+                user_id: user_id.clone(),
+                name: "Settings".to_owned(),
+                ..Default::default()
+            }),
+            Message::Group(Group {
+                user_id: user_id.clone(),
+                group_id: "group-1".to_owned(),
+                ..Default::default()
+            }),
+            Message::Alias(Alias {
+                user_id: "foo".to_owned(),
+                previous_id: "bar".to_owned(),
+                ..Default::default()
+            }),
+            Message::Batch(Batch {
+                batch: vec![BatchMessage::Track(Track {
+                    user_id,
+                    event: "Bar".to_owned(),
+                    ..Default::default()
+                })],
+                ..Default::default()
+            }),
+        ];
+        for message in &messages {
+            rudder_analytics.send(message).unwrap();
+        }
+
+        let paths: Vec<String> = server.requests().into_iter().map(|r| r.path).collect();
+        assert_eq!(
+            paths,
+            vec![
+                "/v1/identify",
+                "/v1/track",
+                "/v1/page",
+                "/v1/screen",
+                "/v1/group",
+                "/v1/alias",
+                "/v1/batch"
+            ]
+        );
+
+        let events = server.events();
+        let types: Vec<&str> = events.iter().map(|e| e["type"].as_str().unwrap()).collect();
+        assert_eq!(
+            types,
+            vec!["identify", "track", "page", "screen", "group", "alias", "track"]
+        );
+        assert_eq!(events[0]["traits"]["name"], "Foo");
+        assert_eq!(events[4]["groupId"], "group-1");
+        assert_eq!(events[5]["previousId"], "bar");
+        for event in &events {
+            assert_eq!(event["context"]["library"]["name"], "RudderStack Rust SDK");
+        }
+    }
+
+    #[test]
+    fn test_wrong_write_key_is_rejected() {
+        let server = TestServer::start("WRITE-KEY").unwrap();
+        let rudder_analytics = RudderAnalytics::load("OTHER-KEY".to_string(), server.url());
+
+        let err = rudder_analytics.send(&track()).err().unwrap();
+        assert!(matches!(
+            err,
+            AnalyticsError::HttpStatus { status: 401, .. }
+        ));
+        assert_eq!(server.requests().len(), 1);
+        assert!(server.payloads().is_empty());
+    }
+
+    #[test]
+    fn test_dropped_connection_is_retried() {
+        let server = TestServer::start("WRITE-KEY").unwrap();
+        server.fail_next(Failure::DropConnection);
+        let rudder_analytics = RudderAnalytics::builder("WRITE-KEY", server.url())
+            .retry_policy(fast_retries())
+            .build()
+            .unwrap();
+
+        let delivery = rudder_analytics.send(&track()).unwrap();
+        assert_eq!(delivery.attempts, 2);
+        let statuses: Vec<Option<u16>> = server.requests().iter().map(|r| r.status).collect();
+        assert_eq!(statuses, vec![None, Some(200)]);
+    }
+
+    #[test]
+    fn test_slow_response_times_out() {
+        let server = TestServer::start("WRITE-KEY").unwrap();
+        server.fail_next(Failure::Slow(Duration::from_millis(500)));
+        let rudder_analytics = RudderAnalytics::builder("WRITE-KEY", server.url())
+            .timeout(Duration::from_millis(100))
+            .retry_policy(fast_retries())
+            .build()
+            .unwrap();
+
+        let delivery = rudder_analytics.send(&track()).unwrap();
+        assert_eq!(delivery.attempts, 2);
+        assert!(server.wait_for_requests(2, Duration::from_secs(5)));
+        assert_eq!(server.events().len(), 2);
+    }
+
+    #[test]
+    fn test_default_timeout_applies() {
+        let server = TestServer::start("WRITE-KEY").unwrap();
+        server.fail_next(Failure::Slow(Duration::from_secs(40)));
+        let rudder_analytics = RudderAnalytics::builder("WRITE-KEY", server.url())
+            .retry_policy(RetryPolicy::none())
+            .build()
+            .unwrap();
+
+        let started = Instant::now();
+        let err = rudder_analytics.send(&track()).err().unwrap();
+        assert!(matches!(err, AnalyticsError::Transport(_)));
+        assert!(started.elapsed() < Duration::from_secs(40));
+    }
+
+    #[test]
+    fn test_server_errors_are_retried() {
+        let server = TestServer::start("WRITE-KEY").unwrap();
+        server.fail_next(Failure::status(500));
+        server.fail_next(Failure::status(502));
+        let rudder_analytics = RudderAnalytics::builder("WRITE-KEY", server.url())
+            .retry_policy(fast_retries())
+            .gzip(true)
+            .build()
+            .unwrap();
+
+        let delivery = rudder_analytics.send(&track()).unwrap();
+        assert_eq!(delivery.attempts, 3);
+        assert_eq!(server.events().len(), 1);
+        assert_eq!(server.events()[0]["event"], "Foo");
+    }
+}
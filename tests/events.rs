@@ -1,5 +1,6 @@
-// use rudderanalytics::batcher::Batcher;
-// use rudderanalytics::errors::Error as AnalyticsError;
+use rudderanalytics::batcher::Batcher;
+use rudderanalytics::context::Context;
+use rudderanalytics::errors::Error as AnalyticsError;
 use rudderanalytics::message::{
     Alias, Batch, BatchMessage, Group, Identify, Message, Page, Screen, Track,
 };
@@ -8,6 +9,7 @@ use rudderanalytics::message::{
 mod tests {
     use super::*;
     use serde_json::json;
+    use std::convert::TryFrom;
 
     #[test]
     fn serialize() {
@@ -113,9 +115,12 @@ mod tests {
                         ..Default::default()
                     })
                 ],
-                context: Some(json!({
-                    "foo": "bar",
-                })),
+                context: Some(
+                    Context::try_from(json!({
+                        "foo": "bar",
+                    }))
+                    .unwrap()
+                ),
                 ..Default::default()
             }))
             .unwrap(),
@@ -124,68 +129,86 @@ mod tests {
         );
     }
 
-    // #[test]
-    // fn test_push_and_into() {
-    //     let batch_msg = BatchMessage::Track(Track {
-    //         ..Default::default()
-    //     });
-
-    //     let context = json!({
-    //         "foo": "bar",
-    //     });
-
-    //     let mut batcher = Batcher::new(Some(context.clone()));
-    //     let result = batcher.push(batch_msg.clone());
-    //     assert_eq!(None, result.ok().unwrap());
-
-    //     let batch = batcher.into_message();
-    //     let inner_batch = match batch {
-    //         Message::Batch(b) => b,
-    //         _ => panic!("invalid message type"),
-    //     };
-    //     assert_eq!(context, inner_batch.context.unwrap());
-    //     assert_eq!(1, inner_batch.batch.len());
-
-    //     assert_eq!(inner_batch.batch, vec![batch_msg]);
-    // }
-
-    // #[test]
-    // fn test_bad_message_size() {
-    //     let batch_msg = BatchMessage::Track(Track {
-    //         user_id: Some(String::from_utf8(vec![b'a'; 1024 * 33]).unwrap()),
-    //         ..Default::default()
-    //     });
-
-    //     let mut batcher = Batcher::new(None);
-    //     let result = batcher.push(batch_msg.into());
-
-    //     let err = result.err().unwrap();
-    //     let err: &AnalyticsError = err.as_fail().downcast_ref().unwrap();
-
-    //     match err {
-    //         AnalyticsError::MessageTooLarge(_str) => {}
-    //         AnalyticsError::InvalidRequest(_str) => {}
-    //     }
-    // }
-
-    // #[test]
-    // fn test_max_buffer() {
-    //     let batch_msg = BatchMessage::Track(Track {
-    //         user_id: Some(String::from_utf8(vec![b'a'; 1024 * 30]).unwrap()),
-    //         ..Default::default()
-    //     });
-
-    //     let mut batcher = Batcher::new(None);
-    //     let mut result = Ok(None);
-    //     for _i in 0..20 {
-    //         result = batcher.push(batch_msg.clone().into());
-    //         dbg!(&result);
-    //         if result.is_ok() && result.as_ref().ok().unwrap().is_some() {
-    //             break;
-    //         }
-    //     }
-
-    //     let msg = result.ok().unwrap();
-    //     assert_eq!(batch_msg, msg.unwrap());
-    // }
+    #[test]
+    fn test_push_and_into() {
+        let batch_msg = BatchMessage::Track(Track {
+            ..Default::default()
+        });
+
+        let context = Context::try_from(json!({
+            "foo": "bar",
+        }))
+        .unwrap();
+
+        let mut batcher = Batcher::new(Some(context.clone()));
+        let result = batcher.push(batch_msg.clone());
+        assert_eq!(None, result.ok().unwrap());
+
+        let batch = batcher.into_message();
+        let inner_batch = match batch {
+            Message::Batch(b) => b,
+            _ => panic!("invalid message type"),
+        };
+        assert_eq!(context, inner_batch.context.unwrap());
+        assert_eq!(1, inner_batch.batch.len());
+
+        // push assigns a message id, and keeps everything else as is
+        let mut pushed = inner_batch.batch[0].clone();
+        match &mut pushed {
+            BatchMessage::Track(m) => assert!(m.message_id.take().is_some()),
+            _ => panic!("invalid message type"),
+        }
+        assert_eq!(pushed, batch_msg);
+    }
+
+    #[test]
+    fn test_bad_message_size() {
+        let batch_msg = BatchMessage::Track(Track {
+            user_id: Some(String::from_utf8(vec![b'a'; 1024 * 33]).unwrap()),
+            ..Default::default()
+        });
+
+        let mut batcher = Batcher::new(None);
+        let result = batcher.push(batch_msg);
+
+        let err = result.err().unwrap();
+
+        assert!(matches!(err, AnalyticsError::MessageTooLarge { .. }));
+    }
+
+    #[test]
+    fn test_message_size_includes_rudder_fields() {
+        // Fits in 32 KiB as a BatchMessage, but not once sentAt, channel and
+        // the library context have been added.
+        let batch_msg = BatchMessage::Track(Track {
+            user_id: Some(String::from_utf8(vec![b'a'; 1024 * 32 - 100]).unwrap()),
+            ..Default::default()
+        });
+        assert!(serde_json::to_vec(&batch_msg).unwrap().len() < 1024 * 32);
+
+        let mut batcher = Batcher::new(None);
+        let err = batcher.push(batch_msg).err().unwrap();
+        assert!(matches!(err, AnalyticsError::MessageTooLarge { .. }));
+    }
+
+    #[test]
+    fn test_max_buffer() {
+        let batch_msg = BatchMessage::Track(Track {
+            user_id: Some(String::from_utf8(vec![b'a'; 1024 * 30]).unwrap()),
+            message_id: Some("message-1".to_owned()),
+            ..Default::default()
+        });
+
+        let mut batcher = Batcher::new(None);
+        let mut result = Ok(None);
+        for _i in 0..20 {
+            result = batcher.push(batch_msg.clone());
+            if result.is_ok() && result.as_ref().ok().unwrap().is_some() {
+                break;
+            }
+        }
+
+        let msg = result.ok().unwrap();
+        assert_eq!(batch_msg, msg.unwrap());
+    }
 }
@@ -0,0 +1,219 @@
+#[cfg(feature = "async")]
+use rudderanalytics::async_client::AsyncRudderAnalytics;
+use rudderanalytics::client::RudderAnalytics;
+use rudderanalytics::context::Context;
+use rudderanalytics::errors::Error as AnalyticsError;
+use rudderanalytics::message::{Batch, BatchMessage, Message, Track};
+use rudderanalytics::middleware::{Middleware, Verdict};
+use rudderanalytics::queue::QueueConfig;
+use rudderanalytics::test_server::TestServer;
+use serde_json::json;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Adds the deployment to the context of every message
+    struct Deployment(&'static str);
+
+    impl Middleware for Deployment {
+        fn process(&self, msg: &mut BatchMessage) -> Verdict {
+            let context = match msg {
+                BatchMessage::Track(m) => &mut m.context,
+                BatchMessage::Identify(m) => &mut m.context,
+                _ => return Verdict::Keep,
+            };
+            context
+                .get_or_insert_with(Context::default)
+                .extra
+                .insert("deployment".to_owned(), json!(self.0));
+            Verdict::Keep
+        }
+    }
+
+    fn drop_test_users(msg: &mut BatchMessage) -> Verdict {
+        match msg {
+            BatchMessage::Track(m) if m.user_id.as_deref() == Some("test-user") => Verdict::Drop,
+            _ => Verdict::Keep,
+        }
+    }
+
+    fn track(user_id: &str, event: &str) -> Track {
+        Track {
+            user_id: Some(user_id.to_owned()),
+            event: event.to_owned(),
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn test_single_messages() {
+        let server = TestServer::start("WRITE-KEY").unwrap();
+        let rudder_analytics = RudderAnalytics::builder("WRITE-KEY", server.url())
+            .middleware(Deployment("canary"))
+            .middleware(drop_test_users)
+            .build()
+            .unwrap();
+
+        rudder_analytics
+            .send(&Message::Track(track("user-1", "Foo")))
+            .unwrap();
+        let delivery = rudder_analytics
+            .send(&Message::Track(track("test-user", "Foo")))
+            .unwrap();
+        assert_eq!(delivery.attempts, 0);
+
+        let events = server.events();
+        assert_eq!(events.len(), 1);
+        assert_eq!(events[0]["context"]["deployment"], "canary");
+        assert_eq!(
+            events[0]["context"]["library"]["name"],
+            "RudderStack Rust SDK"
+        );
+    }
+
+    #[test]
+    fn test_middlewares_run_in_order() {
+        let server = TestServer::start("WRITE-KEY").unwrap();
+        let rudder_analytics = RudderAnalytics::builder("WRITE-KEY", server.url())
+            .middleware(|msg: &mut BatchMessage| {
+                if let BatchMessage::Track(m) = msg {
+                    m.event = format!("{} 1", m.event);
+                }
+                Verdict::Keep
+            })
+            .middleware(|msg: &mut BatchMessage| {
+                if let BatchMessage::Track(m) = msg {
+                    m.event = format!("{} 2", m.event);
+                }
+                Verdict::Keep
+            })
+            .build()
+            .unwrap();
+
+        rudder_analytics
+            .send(&Message::Track(track("user-1", "Foo")))
+            .unwrap();
+        assert_eq!(server.events()[0]["event"], "Foo 1 2");
+    }
+
+    #[test]
+    fn test_batch_messages() {
+        let server = TestServer::start("WRITE-KEY").unwrap();
+        let rudder_analytics = RudderAnalytics::builder("WRITE-KEY", server.url())
+            .middleware(drop_test_users)
+            .middleware(Deployment("canary"))
+            .build()
+            .unwrap();
+
+        let delivery = rudder_analytics
+            .send(&Message::Batch(Batch {
+                batch: vec![
+                    BatchMessage::Track(track("test-user", "Foo")),
+                    BatchMessage::Track(track("user-1", "Bar")),
+                    BatchMessage::Track(track("test-user", "Baz")),
+                ],
+                ..Default::default()
+            }))
+            .unwrap();
+        let outcome = delivery.batch.unwrap();
+        assert_eq!(outcome.accepted, 1);
+        assert_eq!(outcome.dropped, vec![0, 2]);
+        assert!(outcome.rejected.is_empty());
+
+        let events = server.events();
+        assert_eq!(events.len(), 1);
+        assert_eq!(events[0]["event"], "Bar");
+        assert_eq!(events[0]["context"]["deployment"], "canary");
+
+        // Nothing is sent if every message is dropped
+        let delivery = rudder_analytics
+            .send(&Message::Batch(Batch {
+                batch: vec![BatchMessage::Track(track("test-user", "Foo"))],
+                ..Default::default()
+            }))
+            .unwrap();
+        assert_eq!(delivery.attempts, 0);
+        assert_eq!(server.requests().len(), 1);
+    }
+
+    #[test]
+    fn test_queued_client_runs_middlewares_once() {
+        let server = TestServer::start("WRITE-KEY").unwrap();
+        let calls = Arc::new(AtomicUsize::new(0));
+        let counter = calls.clone();
+        let queue = RudderAnalytics::builder("WRITE-KEY", server.url())
+            .middleware(move |_: &mut BatchMessage| {
+                counter.fetch_add(1, Ordering::SeqCst);
+                Verdict::Keep
+            })
+            .middleware(drop_test_users)
+            .middleware(Deployment("staging"))
+            .build()
+            .unwrap()
+            .queued(QueueConfig::default())
+            .unwrap();
+
+        queue
+            .enqueue(BatchMessage::Track(track("test-user", "Foo")))
+            .unwrap();
+        queue
+            .enqueue(BatchMessage::Track(track("user-1", "Bar")))
+            .unwrap();
+        queue.flush().unwrap();
+
+        assert_eq!(calls.load(Ordering::SeqCst), 2);
+        let events = server.events();
+        assert_eq!(events.len(), 1);
+        assert_eq!(events[0]["event"], "Bar");
+        assert_eq!(events[0]["context"]["deployment"], "staging");
+    }
+
+    #[test]
+    fn test_rewritten_messages_are_validated() {
+        let server = TestServer::start("WRITE-KEY").unwrap();
+        let rudder_analytics = RudderAnalytics::builder("WRITE-KEY", server.url())
+            .middleware(|msg: &mut BatchMessage| {
+                if let BatchMessage::Track(m) = msg {
+                    m.user_id = None;
+                }
+                Verdict::Keep
+            })
+            .build()
+            .unwrap();
+
+        let err = rudder_analytics
+            .send(&Message::Track(track("user-1", "Foo")))
+            .err()
+            .unwrap();
+        assert!(matches!(err, AnalyticsError::MissingIdentity));
+        assert!(server.requests().is_empty());
+    }
+
+    #[cfg(feature = "async")]
+    #[tokio::test]
+    async fn test_async_client() {
+        let server = TestServer::start("WRITE-KEY").unwrap();
+        let rudder_analytics: AsyncRudderAnalytics =
+            RudderAnalytics::builder("WRITE-KEY", server.url())
+                .middleware(Deployment("canary"))
+                .middleware(drop_test_users)
+                .build_async()
+                .unwrap();
+
+        rudder_analytics
+            .send(&Message::Track(track("test-user", "Foo")))
+            .await
+            .unwrap();
+        rudder_analytics
+            .send(&Message::Track(track("user-1", "Foo")))
+            .await
+            .unwrap();
+
+        let events = server.events();
+        assert_eq!(events.len(), 1);
+        assert_eq!(events[0]["context"]["deployment"], "canary");
+    }
+}
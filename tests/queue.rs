@@ -0,0 +1,282 @@
+use chrono::{DateTime, Utc};
+use rudderanalytics::client::RudderAnalytics;
+use rudderanalytics::disk_queue::{FsyncPolicy, PersistenceConfig};
+use rudderanalytics::errors::Error as AnalyticsError;
+use rudderanalytics::message::{BatchMessage, Track};
+use rudderanalytics::queue::{QueueConfig, QueuedRudderAnalytics};
+use rudderanalytics::retry::RetryPolicy;
+use rudderanalytics::test_server::{Failure, TestServer};
+use serde_json::json;
+use std::fs;
+use std::path::PathBuf;
+use std::thread;
+use std::time::Duration;
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn track() -> BatchMessage {
+        BatchMessage::Track(Track {
+            user_id: Some("foo".to_string()),
+            event: "Foo".to_owned(),
+            ..Default::default()
+        })
+    }
+
+    // Nothing listens on the discard port, so every send fails fast
+    fn unreachable_client() -> RudderAnalytics {
+        RudderAnalytics::load("WRITE-KEY".to_string(), "http://127.0.0.1:9".to_string())
+    }
+
+    // An empty directory for a test's persisted queue
+    fn queue_dir(name: &str) -> PathBuf {
+        let dir =
+            std::env::temp_dir().join(format!("rudderanalytics-{}-{}", name, std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        dir
+    }
+
+    #[test]
+    fn test_flush_empty_queue() {
+        let queue = unreachable_client().queued(QueueConfig::default()).unwrap();
+        assert!(queue.flush().is_ok());
+        queue.shutdown();
+    }
+
+    #[test]
+    fn test_flush_reports_send_errors() {
+        let queue = unreachable_client()
+            .queued(QueueConfig {
+                flush_interval: Duration::from_secs(3600),
+                ..Default::default()
+            })
+            .unwrap();
+        queue.enqueue(track()).unwrap();
+        assert!(queue.flush().is_err());
+    }
+
+    fn queued(server: &TestServer, config: QueueConfig) -> QueuedRudderAnalytics {
+        RudderAnalytics::load("WRITE-KEY".to_string(), server.url())
+            .queued(config)
+            .unwrap()
+    }
+
+    #[test]
+    fn test_full_batch_is_sent() {
+        let server = TestServer::start("WRITE-KEY").unwrap();
+        let queue = queued(
+            &server,
+            QueueConfig {
+                batch_size: 3,
+                flush_interval: Duration::from_secs(3600),
+                ..Default::default()
+            },
+        );
+
+        for _ in 0..4 {
+            queue.enqueue(track()).unwrap();
+        }
+        assert!(server.wait_for_requests(1, Duration::from_secs(5)));
+        let batch_len = |payload: &serde_json::Value| payload["batch"].as_array().unwrap().len();
+        let requests = server.requests();
+        assert_eq!(requests.len(), 1);
+        assert_eq!(requests[0].path, "/v1/batch");
+        assert_eq!(batch_len(&requests[0].json().unwrap()), 3);
+
+        // The rest is sent on shutdown
+        queue.shutdown();
+        let payloads = server.payloads();
+        assert_eq!(payloads.len(), 2);
+        assert_eq!(batch_len(&payloads[1]), 1);
+    }
+
+    #[test]
+    fn test_batch_is_sent_after_flush_interval() {
+        let server = TestServer::start("WRITE-KEY").unwrap();
+        let queue = queued(
+            &server,
+            QueueConfig {
+                flush_interval: Duration::from_millis(200),
+                ..Default::default()
+            },
+        );
+
+        queue.enqueue(track()).unwrap();
+        queue.enqueue(track()).unwrap();
+        assert!(server.wait_for_requests(1, Duration::from_secs(5)));
+        assert_eq!(server.requests()[0].path, "/v1/batch");
+        assert_eq!(server.events().len(), 2);
+        queue.shutdown();
+    }
+
+    #[test]
+    fn test_shutdown_and_drop_send_buffered_messages() {
+        let config = QueueConfig {
+            flush_interval: Duration::from_secs(3600),
+            ..Default::default()
+        };
+
+        let server = TestServer::start("WRITE-KEY").unwrap();
+        let queue = queued(&server, config.clone());
+        queue.enqueue(track()).unwrap();
+        queue.shutdown();
+        assert_eq!(server.events().len(), 1);
+
+        let server = TestServer::start("WRITE-KEY").unwrap();
+        let queue = queued(&server, config);
+        queue.enqueue(track()).unwrap();
+        queue.enqueue(track()).unwrap();
+        drop(queue);
+        assert_eq!(server.events().len(), 2);
+    }
+
+    #[test]
+    fn test_full_batch_waits_for_failed_batch() {
+        let server = TestServer::start("WRITE-KEY").unwrap();
+        server.fail_next(Failure::status(500));
+        server.fail_next(Failure::status(500));
+        let queue = RudderAnalytics::builder("WRITE-KEY", server.url())
+            .retry_policy(RetryPolicy {
+                max_attempts: 1,
+                max_delay: Duration::from_millis(10),
+                ..Default::default()
+            })
+            .build()
+            .unwrap()
+            .queued(QueueConfig {
+                flush_interval: Duration::from_secs(3600),
+                ..Default::default()
+            })
+            .unwrap();
+
+        // About 16 of them fill a batch
+        let padding = "x".repeat(30 * 1024);
+        for _ in 0..40 {
+            queue
+                .enqueue(BatchMessage::Track(Track {
+                    user_id: Some("foo".to_string()),
+                    event: "Foo".to_owned(),
+                    properties: Some(json!({ "padding": padding })),
+                    ..Default::default()
+                }))
+                .unwrap();
+        }
+        // The first batch fails twice, before it and the second batch are
+        // sent, and the last one is sent on shutdown
+        assert!(server.wait_for_requests(4, Duration::from_secs(10)));
+        queue.shutdown();
+        let statuses: Vec<Option<u16>> = server.requests().iter().map(|r| r.status).collect();
+        assert_eq!(
+            statuses,
+            vec![Some(500), Some(500), Some(200), Some(200), Some(200)]
+        );
+        assert_eq!(server.events().len(), 40);
+    }
+
+    #[test]
+    fn test_events_are_timed_when_enqueued() {
+        let server = TestServer::start("WRITE-KEY").unwrap();
+        let queue = RudderAnalytics::load("WRITE-KEY".to_string(), server.url())
+            .queued(QueueConfig {
+                flush_interval: Duration::from_secs(3600),
+                ..Default::default()
+            })
+            .unwrap();
+
+        let enqueued_at = Utc::now();
+        queue.enqueue(track()).unwrap();
+        thread::sleep(Duration::from_secs(1));
+        queue.flush().unwrap();
+
+        let event = &server.events()[0];
+        let timestamp: DateTime<Utc> = event["originalTimestamp"]
+            .as_str()
+            .unwrap()
+            .parse()
+            .unwrap();
+        assert!(timestamp - enqueued_at < chrono::Duration::milliseconds(500));
+    }
+
+    #[test]
+    fn test_persisted_messages_are_replayed() {
+        let dir = queue_dir("replay");
+        let config = QueueConfig {
+            flush_interval: Duration::from_secs(3600),
+            persistence: Some(PersistenceConfig::new(&dir)),
+            ..Default::default()
+        };
+
+        // Leaking the queue stands in for the process being killed before
+        // the message was sent.
+        let queue = unreachable_client().queued(config.clone()).unwrap();
+        queue.enqueue(track()).unwrap();
+        std::mem::forget(queue);
+
+        let server = TestServer::start("WRITE-KEY").unwrap();
+        let queue = RudderAnalytics::load("WRITE-KEY".to_string(), server.url())
+            .queued(config.clone())
+            .unwrap();
+        queue.flush().unwrap();
+        let payload = &server.payloads()[0];
+        assert_eq!(payload["batch"].as_array().unwrap().len(), 1);
+        assert_eq!(payload["batch"][0]["event"], "Foo");
+        queue.shutdown();
+
+        // Delivered messages are not replayed again
+        let queue = unreachable_client().queued(config).unwrap();
+        assert!(queue.flush().is_ok());
+        queue.shutdown();
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn test_persistence_size_cap() {
+        let dir = queue_dir("size-cap");
+        let config = QueueConfig {
+            flush_interval: Duration::from_secs(3600),
+            persistence: Some(PersistenceConfig {
+                max_bytes: 1024,
+                segment_bytes: 256,
+                fsync: FsyncPolicy::Never,
+                ..PersistenceConfig::new(&dir)
+            }),
+            ..Default::default()
+        };
+        let queue = unreachable_client().queued(config.clone()).unwrap();
+
+        let mut accepted = 0;
+        let err = loop {
+            match queue.enqueue(track()) {
+                Ok(()) => accepted += 1,
+                Err(err) => break err,
+            }
+        };
+        assert!(matches!(err, AnalyticsError::QueueFull));
+        assert!(accepted > 1);
+
+        // Records are spread over several segments
+        let segments = fs::read_dir(&dir)
+            .unwrap()
+            .filter(|entry| entry.as_ref().unwrap().path().extension() == Some("seg".as_ref()))
+            .count();
+        assert!(segments > 1);
+
+        // Failing to deliver the messages keeps them on disk
+        assert!(queue.flush().is_err());
+        let err = queue.enqueue(track()).err().unwrap();
+        assert!(matches!(err, AnalyticsError::QueueFull));
+        drop(queue);
+
+        // and they are delivered once the data plane is back
+        let server = TestServer::start("WRITE-KEY").unwrap();
+        let queue = RudderAnalytics::load("WRITE-KEY".to_string(), server.url())
+            .queued(config)
+            .unwrap();
+        queue.flush().unwrap();
+        assert_eq!(server.events().len(), accepted);
+        queue.enqueue(track()).unwrap();
+        drop(queue);
+        fs::remove_dir_all(dir).unwrap();
+    }
+}
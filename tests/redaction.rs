@@ -0,0 +1,288 @@
+use rudderanalytics::client::RudderAnalytics;
+use rudderanalytics::context::{Context, Page, Screen};
+use rudderanalytics::dead_letter::MemorySink;
+use rudderanalytics::errors::Error as AnalyticsError;
+use rudderanalytics::message::{Batch, BatchMessage, Identify, Message, Track};
+use rudderanalytics::redaction::{Detector, Redaction, Redactor};
+use rudderanalytics::test_server::{Failure, TestServer};
+use serde_json::json;
+use std::sync::Arc;
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const EMAIL_HASH: &str = "71564287a1554642879c05772e96d1f4200af4c6660a765e107cfa4dcf7060d4";
+
+    fn salted() -> Redaction {
+        Redaction::Hash {
+            salt: "SALT".to_owned(),
+        }
+    }
+
+    fn track(properties: serde_json::Value) -> BatchMessage {
+        BatchMessage::Track(Track {
+            user_id: Some("user-1".to_owned()),
+            event: "Foo".to_owned(),
+            properties: Some(properties),
+            ..Default::default()
+        })
+    }
+
+    fn properties(msg: &BatchMessage) -> &serde_json::Value {
+        match msg {
+            BatchMessage::Track(m) => m.properties.as_ref().unwrap(),
+            _ => panic!("not a track message"),
+        }
+    }
+
+    #[test]
+    fn test_path_rules() {
+        let redactor = Redactor::builder()
+            .rule("/traits/email", salted())
+            .rule("traits.*_phone", Redaction::Mask)
+            .rule("context.ip", Redaction::Drop)
+            .build()
+            .unwrap();
+
+        let mut msg = BatchMessage::Identify(Identify {
+            user_id: Some("user-1".to_owned()),
+            traits: Some(json!({
+                "email": "a@example.com",
+                "home_phone": "555 1234",
+                "work_phone": 5551234,
+                "phone": null,
+                "name": "Ada",
+            })),
+            context: Some(Context {
+                ip: Some("10.0.0.1".to_owned()),
+                locale: Some("en-US".to_owned()),
+                ..Default::default()
+            }),
+            ..Default::default()
+        });
+        redactor.redact(&mut msg);
+
+        let identify = match msg {
+            BatchMessage::Identify(m) => m,
+            _ => unreachable!(),
+        };
+        assert_eq!(
+            identify.traits.unwrap(),
+            json!({
+                "email": EMAIL_HASH,
+                "home_phone": "***",
+                "work_phone": "***",
+                "phone": null,
+                "name": "Ada",
+            })
+        );
+        let context = identify.context.unwrap();
+        assert_eq!(context.ip, None);
+        assert_eq!(context.locale.as_deref(), Some("en-US"));
+    }
+
+    #[test]
+    fn test_wildcards() {
+        let redactor = Redactor::builder()
+            .rule("properties.**.email", Redaction::Drop)
+            .rule("/properties/cards/*/number", Redaction::Mask)
+            .build()
+            .unwrap();
+
+        let mut msg = track(json!({
+            "email": "a@example.com",
+            "buyer": { "email": "b@example.com", "address": { "email": "c@example.com" } },
+            "cards": [{ "number": "4111", "brand": "visa" }, { "number": "5500" }],
+        }));
+        redactor.redact(&mut msg);
+        assert_eq!(
+            properties(&msg),
+            &json!({
+                "buyer": { "address": {} },
+                "cards": [{ "number": "***", "brand": "visa" }, { "number": "***" }],
+            })
+        );
+    }
+
+    #[test]
+    fn test_detectors() {
+        let redactor = Redactor::builder()
+            .detect(Detector::Email, Redaction::Mask)
+            .detect(Detector::Phone, Redaction::Mask)
+            .detect(Detector::CardNumber, salted())
+            .build()
+            .unwrap();
+
+        let mut msg = track(json!({
+            "comment": "mail a@example.com or call +49 30 1234567",
+            "notes": ["paid with 4111 1111 1111 1111", "order 1234567890123"],
+            "us": "(555) 123-4567",
+            "date": "2024-01-15",
+            "quantity": 3,
+        }));
+        redactor.redact(&mut msg);
+        assert_eq!(
+            properties(&msg),
+            &json!({
+                "comment": "mail *** or call ***",
+                "notes": [
+                    "paid with 3cd64ae718f45041c3f9490670b4cf9bcdc6ed6144f44bce1450861539f9ad44",
+                    "order 1234567890123",
+                ],
+                "us": "***",
+                "date": "2024-01-15",
+                "quantity": 3,
+            })
+        );
+
+        // Dropping removes the whole string
+        let redactor = Redactor::builder()
+            .detect(Detector::Email, Redaction::Drop)
+            .build()
+            .unwrap();
+        let mut msg = track(json!({ "comment": "mail a@example.com", "plan": "pro" }));
+        redactor.redact(&mut msg);
+        assert_eq!(properties(&msg), &json!({ "plan": "pro" }));
+    }
+
+    #[test]
+    fn test_invalid_paths() {
+        for path in ["userId", "/properties//email", "properties."] {
+            let err = Redactor::builder()
+                .rule(path, Redaction::Drop)
+                .build()
+                .err()
+                .unwrap();
+            assert!(matches!(err, AnalyticsError::InvalidConfig(_)), "{}", path);
+        }
+    }
+
+    #[test]
+    fn test_keys_of_the_wrong_type_are_removed() {
+        let redactor = Redactor::builder()
+            .rule("context.page", Redaction::Mask)
+            .rule("context.screen.width", salted())
+            .build()
+            .unwrap();
+
+        let mut context = Some(Context {
+            locale: Some("en-US".to_owned()),
+            page: Some(Page {
+                url: Some("https://example.com/?email=a@example.com".to_owned()),
+                ..Default::default()
+            }),
+            screen: Some(Screen {
+                width: Some(1920),
+                height: Some(1080),
+                ..Default::default()
+            }),
+            ..Default::default()
+        });
+        redactor.redact_context(&mut context);
+        assert_eq!(
+            context,
+            Some(Context {
+                locale: Some("en-US".to_owned()),
+                screen: Some(Screen {
+                    height: Some(1080),
+                    ..Default::default()
+                }),
+                ..Default::default()
+            })
+        );
+
+        // Only a context which is no longer an object is dropped
+        let redactor = Redactor::builder()
+            .rule("context", Redaction::Mask)
+            .build()
+            .unwrap();
+        let mut context = Some(Context {
+            locale: Some("en-US".to_owned()),
+            ..Default::default()
+        });
+        redactor.redact_context(&mut context);
+        assert_eq!(context, None);
+    }
+
+    #[test]
+    fn test_send() {
+        let server = TestServer::start("WRITE-KEY").unwrap();
+        let redactor = Redactor::builder()
+            .rule("context.ip", Redaction::Drop)
+            .rule("properties.email", salted())
+            .build()
+            .unwrap();
+        let rudder_analytics = RudderAnalytics::builder("WRITE-KEY", server.url())
+            .middleware(redactor)
+            .build()
+            .unwrap();
+
+        let context = Some(Context {
+            ip: Some("10.0.0.1".to_owned()),
+            ..Default::default()
+        });
+        rudder_analytics
+            .send(&Message::Track(Track {
+                user_id: Some("user-1".to_owned()),
+                event: "Foo".to_owned(),
+                properties: Some(json!({ "email": "a@example.com" })),
+                context: context.clone(),
+                ..Default::default()
+            }))
+            .unwrap();
+        rudder_analytics
+            .send(&Message::Batch(Batch {
+                batch: vec![track(json!({ "email": "a@example.com" }))],
+                context,
+                ..Default::default()
+            }))
+            .unwrap();
+
+        let events = server.events();
+        assert_eq!(events.len(), 2);
+        for event in &events {
+            assert_eq!(event["properties"]["email"], EMAIL_HASH);
+            assert!(event["context"].get("ip").is_none());
+        }
+        let batch = &server.payloads()[1];
+        assert!(batch["context"].get("ip").is_none());
+    }
+
+    #[test]
+    fn test_dead_letters_are_redacted() {
+        let server = TestServer::start("WRITE-KEY").unwrap();
+        server.fail_next(Failure::status(400));
+        let sink = Arc::new(MemorySink::new(10));
+        let rudder_analytics = RudderAnalytics::builder("WRITE-KEY", server.url())
+            .middleware(
+                Redactor::builder()
+                    .rule("properties.email", salted())
+                    .build()
+                    .unwrap(),
+            )
+            .dead_letter(sink.clone())
+            .build()
+            .unwrap();
+
+        assert!(rudder_analytics
+            .send(&Message::Track(Track {
+                user_id: Some("user-1".to_owned()),
+                event: "Foo".to_owned(),
+                properties: Some(json!({ "email": "a@example.com" })),
+                ..Default::default()
+            }))
+            .is_err());
+        let letters = sink.drain();
+        match &letters[0].message {
+            Message::Track(m) => assert_eq!(m.properties, Some(json!({ "email": EMAIL_HASH }))),
+            _ => panic!("not a track message"),
+        }
+
+        // The redacted values are not hashed again
+        assert!(rudder_analytics.redrive(letters).is_empty());
+        let events = server.events();
+        assert_eq!(events.len(), 1);
+        assert_eq!(events[0]["properties"]["email"], EMAIL_HASH);
+    }
+}
@@ -0,0 +1,145 @@
+use rudderanalytics::client::RudderAnalytics;
+use rudderanalytics::errors::Error as AnalyticsError;
+use rudderanalytics::message::{Message, Track};
+use rudderanalytics::retry::{parse_retry_after, RetryPolicy};
+use rudderanalytics::test_server::{Failure, TestServer};
+use std::time::Duration;
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_delay_backs_off_exponentially() {
+        let policy = RetryPolicy {
+            max_attempts: 10,
+            base_delay: Duration::from_millis(100),
+            max_delay: Duration::from_secs(1),
+            jitter: 0.0,
+        };
+        assert_eq!(policy.delay(1), Duration::from_millis(100));
+        assert_eq!(policy.delay(2), Duration::from_millis(200));
+        assert_eq!(policy.delay(4), Duration::from_millis(800));
+        assert_eq!(policy.delay(5), Duration::from_secs(1));
+        assert_eq!(policy.delay(100), Duration::from_secs(1));
+    }
+
+    #[test]
+    fn test_delay_jitter_stays_in_bounds() {
+        let policy = RetryPolicy {
+            jitter: 0.5,
+            ..Default::default()
+        };
+        for _ in 0..100 {
+            let delay = policy.delay(2);
+            assert!(delay <= Duration::from_millis(200));
+            assert!(delay >= Duration::from_millis(100));
+        }
+    }
+
+    #[test]
+    fn test_network_errors_are_retried() {
+        // Nothing listens on the discard port, so every attempt fails fast
+        let mut rudder_analytics =
+            RudderAnalytics::load("WRITE-KEY".to_string(), "http://127.0.0.1:9".to_string());
+        rudder_analytics.retry_policy = RetryPolicy {
+            max_attempts: 3,
+            base_delay: Duration::from_millis(1),
+            ..Default::default()
+        };
+
+        let err = rudder_analytics
+            .send(&Message::Track(Track {
+                user_id: Some("foo".to_string()),
+                event: "Foo".to_owned(),
+                ..Default::default()
+            }))
+            .err()
+            .unwrap();
+        assert!(matches!(
+            err,
+            AnalyticsError::RetriesExhausted { attempts: 3, .. }
+        ));
+    }
+
+    #[test]
+    fn test_parse_retry_after() {
+        assert_eq!(parse_retry_after("120"), Some(Duration::from_secs(120)));
+        assert_eq!(
+            parse_retry_after("Wed, 21 Oct 2015 07:28:00 GMT"),
+            Some(Duration::ZERO)
+        );
+        let future = (chrono::Utc::now() + chrono::Duration::seconds(60)).to_rfc2822();
+        let delay = parse_retry_after(&future).unwrap();
+        assert!(delay > Duration::from_secs(50) && delay <= Duration::from_secs(60));
+        assert_eq!(parse_retry_after("soon"), None);
+    }
+
+    #[test]
+    fn test_rate_limited() {
+        let server = TestServer::start("WRITE-KEY").unwrap();
+        server.fail_next(Failure::RateLimited {
+            retry_after: Some(120),
+        });
+        let rudder_analytics = RudderAnalytics::load("WRITE-KEY".to_string(), server.url());
+
+        let err = rudder_analytics
+            .send(&Message::Track(Track {
+                user_id: Some("foo".to_string()),
+                event: "Foo".to_owned(),
+                ..Default::default()
+            }))
+            .err()
+            .unwrap();
+        // 120 seconds is beyond the policy's max_delay, so it gives up at once
+        assert!(matches!(
+            err,
+            AnalyticsError::RateLimited {
+                retry_after: Some(retry_after)
+            } if retry_after == Duration::from_secs(120)
+        ));
+    }
+
+    #[test]
+    fn test_client_errors_are_not_retried() {
+        let server = TestServer::start("WRITE-KEY").unwrap();
+        server.fail_next(Failure::Status {
+            status: 400,
+            body: "Invalid payload".to_owned(),
+        });
+        let rudder_analytics = RudderAnalytics::load("WRITE-KEY".to_string(), server.url());
+
+        let err = rudder_analytics
+            .send(&Message::Track(Track {
+                user_id: Some("foo".to_string()),
+                event: "Foo".to_owned(),
+                ..Default::default()
+            }))
+            .err()
+            .unwrap();
+        assert!(!err.is_retryable());
+        assert!(matches!(
+            err,
+            AnalyticsError::HttpStatus { status: 400, ref body } if body == "Invalid payload"
+        ));
+    }
+
+    #[test]
+    fn test_is_retryable() {
+        let server_error = AnalyticsError::HttpStatus {
+            status: 502,
+            body: String::new(),
+        };
+        assert!(server_error.is_retryable());
+        assert!(AnalyticsError::RateLimited { retry_after: None }.is_retryable());
+        assert!(AnalyticsError::RetriesExhausted {
+            attempts: 3,
+            last_error: Box::new(server_error),
+        }
+        .is_retryable());
+        assert!(!AnalyticsError::MissingIdentity.is_retryable());
+        assert!(!AnalyticsError::ReservedKeyword("library".to_owned()).is_retryable());
+        assert!(!AnalyticsError::EmptyField("event").is_retryable());
+        assert!(!AnalyticsError::AliasToSelf.is_retryable());
+    }
+}
@@ -0,0 +1,108 @@
+use rudderanalytics::client::RudderAnalytics;
+use rudderanalytics::message::{Batch, BatchMessage, Identify, Message, Track};
+use rudderanalytics::sampling::Sampler;
+use rudderanalytics::test_server::TestServer;
+use serde_json::json;
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn track(event: &str, user_id: &str) -> BatchMessage {
+        BatchMessage::Track(Track {
+            user_id: Some(user_id.to_owned()),
+            event: event.to_owned(),
+            ..Default::default()
+        })
+    }
+
+    fn send_batch(rudder_analytics: &RudderAnalytics, batch: Vec<BatchMessage>) -> Vec<usize> {
+        rudder_analytics
+            .send(&Message::Batch(Batch {
+                batch,
+                ..Default::default()
+            }))
+            .unwrap()
+            .batch
+            .unwrap()
+            .dropped
+    }
+
+    #[test]
+    fn test_sampling_is_deterministic_per_user() {
+        let server = TestServer::start("WRITE-KEY").unwrap();
+        let rudder_analytics = RudderAnalytics::builder("WRITE-KEY", server.url())
+            .middleware(Sampler::new().sample("Heartbeat", 0.5).sample("Beat", 0.5))
+            .build()
+            .unwrap();
+
+        let users: Vec<String> = (0..200).map(|i| format!("user-{}", i)).collect();
+        let heartbeats = users.iter().map(|user| track("Heartbeat", user)).collect();
+        let dropped = send_batch(&rudder_analytics, heartbeats);
+        assert!(dropped.len() > 50 && dropped.len() < 150);
+
+        // The same users are kept for every event sampled at the same rate
+        let beats = users.iter().map(|user| track("Beat", user)).collect();
+        assert_eq!(send_batch(&rudder_analytics, beats), dropped);
+
+        let events = server.events();
+        assert_eq!(events.len(), 2 * (200 - dropped.len()));
+        assert!(events
+            .iter()
+            .all(|event| event["context"]["sampleRate"] == json!(0.5)));
+    }
+
+    #[test]
+    fn test_other_events_are_not_sampled() {
+        let server = TestServer::start("WRITE-KEY").unwrap();
+        let rudder_analytics = RudderAnalytics::builder("WRITE-KEY", server.url())
+            .middleware(Sampler::new().sample("Heartbeat", 0.0))
+            .build()
+            .unwrap();
+
+        let delivery = rudder_analytics
+            .send(&Message::Batch(Batch {
+                batch: vec![track("Heartbeat", "user-1"), track("Signed Up", "user-1")],
+                ..Default::default()
+            }))
+            .unwrap();
+        assert_eq!(delivery.batch.unwrap().dropped, vec![0]);
+        rudder_analytics
+            .send(&Message::Identify(Identify {
+                user_id: Some("user-1".to_owned()),
+                ..Default::default()
+            }))
+            .unwrap();
+
+        let events = server.events();
+        assert_eq!(events.len(), 2);
+        assert_eq!(events[0]["event"], "Signed Up");
+        assert!(events[0]["context"].get("sampleRate").is_none());
+        assert_eq!(events[1]["type"], "identify");
+    }
+
+    #[test]
+    fn test_rate_limit() {
+        let server = TestServer::start("WRITE-KEY").unwrap();
+        let rudder_analytics = RudderAnalytics::builder("WRITE-KEY", server.url())
+            .middleware(Sampler::new().rate_limit("Cache Hit", 0.001, 3))
+            .build()
+            .unwrap();
+
+        let batch = (0..5)
+            .map(|i| track("Cache Hit", &format!("user-{}", i)))
+            .chain(std::iter::once(track("Cache Miss", "user-1")))
+            .collect();
+        assert_eq!(send_batch(&rudder_analytics, batch), vec![3, 4]);
+
+        let delivery = rudder_analytics
+            .send(&Message::Track(Track {
+                user_id: Some("user-1".to_owned()),
+                event: "Cache Hit".to_owned(),
+                ..Default::default()
+            }))
+            .unwrap();
+        assert_eq!(delivery.attempts, 0);
+        assert_eq!(server.events().len(), 4);
+    }
+}
@@ -0,0 +1,121 @@
+use rudderanalytics::client::RudderAnalytics;
+use rudderanalytics::errors::Error as AnalyticsError;
+use rudderanalytics::message::{Alias, Batch, BatchMessage, Identify, Message, Track};
+use rudderanalytics::suppression::Suppression;
+use rudderanalytics::test_server::TestServer;
+use std::fs;
+use std::process;
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn track(user_id: Option<&str>, anonymous_id: Option<&str>) -> Track {
+        Track {
+            user_id: user_id.map(str::to_owned),
+            anonymous_id: anonymous_id.map(str::to_owned),
+            event: "Foo".to_owned(),
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn test_suppressed_users() {
+        let server = TestServer::start("WRITE-KEY").unwrap();
+        let suppression = Suppression::new().log_suppressed(true);
+        suppression.insert("user-1");
+        suppression.insert("anon-1");
+        let rudder_analytics = RudderAnalytics::builder("WRITE-KEY", server.url())
+            .middleware(suppression.clone())
+            .build()
+            .unwrap();
+
+        let messages = vec![
+            Message::Track(track(Some("user-1"), None)),
+            Message::Identify(Identify {
+                anonymous_id: Some("anon-1".to_owned()),
+                ..Default::default()
+            }),
+            Message::Alias(Alias {
+                user_id: "user-2".to_owned(),
+                previous_id: "anon-1".to_owned(),
+                ..Default::default()
+            }),
+        ];
+        for msg in &messages {
+            let delivery = rudder_analytics.send(msg).unwrap();
+            assert_eq!(delivery.attempts, 0);
+        }
+
+        let delivery = rudder_analytics
+            .send(&Message::Batch(Batch {
+                batch: vec![
+                    BatchMessage::Track(track(Some("user-2"), Some("anon-1"))),
+                    BatchMessage::Track(track(Some("user-2"), Some("anon-2"))),
+                ],
+                ..Default::default()
+            }))
+            .unwrap();
+        assert_eq!(delivery.batch.unwrap().dropped, vec![0]);
+        assert_eq!(suppression.suppressed(), 4);
+
+        suppression.remove("anon-1");
+        assert!(!suppression.contains("anon-1"));
+        rudder_analytics
+            .send(&Message::Track(track(None, Some("anon-1"))))
+            .unwrap();
+
+        let events = server.events();
+        assert_eq!(events.len(), 2);
+        assert_eq!(events[0]["anonymousId"], "anon-2");
+        assert_eq!(events[1]["anonymousId"], "anon-1");
+    }
+
+    #[test]
+    fn test_opt_out() {
+        let server = TestServer::start("WRITE-KEY").unwrap();
+        let suppression = Suppression::new();
+        let rudder_analytics = RudderAnalytics::builder("WRITE-KEY", server.url())
+            .middleware(suppression.clone())
+            .build()
+            .unwrap();
+
+        suppression.opt_out();
+        assert!(suppression.is_opted_out());
+        rudder_analytics
+            .send(&Message::Track(track(Some("user-1"), None)))
+            .unwrap();
+        assert!(server.requests().is_empty());
+
+        suppression.opt_in();
+        rudder_analytics
+            .send(&Message::Track(track(Some("user-1"), None)))
+            .unwrap();
+        assert_eq!(server.events().len(), 1);
+        assert_eq!(suppression.suppressed(), 1);
+    }
+
+    #[test]
+    fn test_load_and_reload_from_file() {
+        let path =
+            std::env::temp_dir().join(format!("rudderanalytics-suppression-{}.txt", process::id()));
+        fs::write(&path, "# erased on 2024-01-15\nuser-1\n\n  anon-1  \n").unwrap();
+        let suppression = Suppression::from_file(&path).unwrap();
+        assert!(suppression.contains("user-1"));
+        assert!(suppression.contains("anon-1"));
+        assert!(!suppression.contains("# erased on 2024-01-15"));
+
+        fs::write(&path, "user-2\n").unwrap();
+        suppression.reload().unwrap();
+        assert!(!suppression.contains("user-1"));
+        assert!(suppression.contains("user-2"));
+
+        // The ids are kept if the file is gone
+        fs::remove_file(&path).unwrap();
+        assert!(suppression.reload().is_err());
+        assert!(suppression.contains("user-2"));
+
+        let err = Suppression::new().reload().err().unwrap();
+        assert!(matches!(err, AnalyticsError::InvalidConfig(_)));
+    }
+}
@@ -0,0 +1,243 @@
+use rudderanalytics::client::{BatchValidation, RudderAnalytics};
+use rudderanalytics::context::Context;
+use rudderanalytics::errors::Error as AnalyticsError;
+use rudderanalytics::message::{Batch, BatchMessage, Identify, Message, Track};
+use rudderanalytics::test_server::TestServer;
+use rudderanalytics::tracking_plan::{TrackingPlan, ViolationAction};
+use serde_json::json;
+use std::fs;
+use std::process;
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const PLAN: &str = r#"
+allowUnplannedEvents: false
+events:
+  Order Completed:
+    properties:
+      type: object
+      required: [revenue]
+      properties:
+        revenue: { type: number }
+    traits:
+      type: object
+      properties:
+        plan: { type: string }
+identify:
+  traits:
+    type: object
+    additionalProperties: false
+    properties:
+      email: { type: string }
+"#;
+
+    fn plan() -> TrackingPlan {
+        TrackingPlan::from_yaml(PLAN).unwrap()
+    }
+
+    fn order(revenue: serde_json::Value) -> Track {
+        Track {
+            user_id: Some("user-1".to_owned()),
+            event: "Order Completed".to_owned(),
+            properties: Some(json!({ "revenue": revenue })),
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn test_check() {
+        let plan = plan();
+        assert!(plan.check(&Message::Track(order(json!(42.0)))).is_empty());
+
+        let violations = plan.check(&Message::Track(order(json!("42"))));
+        assert_eq!(violations.len(), 1);
+        assert_eq!(violations[0].kind, "Datatype-Mismatch");
+        assert_eq!(violations[0].instance_path, "/properties/revenue");
+
+        let violations = plan.check(&Message::Track(Track {
+            user_id: Some("user-1".to_owned()),
+            event: "Order Completed".to_owned(),
+            context: Some(Context {
+                traits: Some(json!({ "plan": 1 })),
+                ..Default::default()
+            }),
+            ..Default::default()
+        }));
+        let kinds: Vec<_> = violations.iter().map(|v| v.kind).collect();
+        assert_eq!(kinds, vec!["Required-Missing", "Datatype-Mismatch"]);
+        assert_eq!(violations[1].instance_path, "/context/traits/plan");
+
+        let violations = plan.check(&Message::Track(Track {
+            user_id: Some("user-1".to_owned()),
+            event: "Unknown".to_owned(),
+            ..Default::default()
+        }));
+        assert_eq!(violations[0].kind, "Unplanned-Event");
+
+        let violations = plan.check(&Message::Identify(Identify {
+            user_id: Some("user-1".to_owned()),
+            traits: Some(json!({ "email": "a@example.com", "age": 42 })),
+            ..Default::default()
+        }));
+        assert_eq!(violations[0].kind, "Additional-Properties");
+    }
+
+    #[test]
+    fn test_load_from_file() {
+        let path = std::env::temp_dir().join(format!(
+            "rudderanalytics-tracking-plan-{}.json",
+            process::id()
+        ));
+        fs::write(
+            &path,
+            json!({ "events": { "Signed Up": { "properties": { "type": "object" } } } })
+                .to_string(),
+        )
+        .unwrap();
+        let plan = TrackingPlan::from_file(&path).unwrap();
+        fs::remove_file(&path).unwrap();
+
+        // Unplanned events are allowed by default
+        assert!(plan
+            .check(&Message::Track(Track {
+                user_id: Some("user-1".to_owned()),
+                event: "Unknown".to_owned(),
+                ..Default::default()
+            }))
+            .is_empty());
+    }
+
+    #[test]
+    fn test_invalid_plans_are_rejected() {
+        let err = TrackingPlan::from_value(json!({ "evnts": {} }))
+            .err()
+            .unwrap();
+        assert!(matches!(err, AnalyticsError::InvalidConfig(_)));
+
+        let err = TrackingPlan::from_value(json!({
+            "events": { "Foo": { "properties": { "type": "no such type" } } }
+        }))
+        .err()
+        .unwrap();
+        assert!(matches!(err, AnalyticsError::InvalidConfig(_)));
+    }
+
+    #[test]
+    fn test_block() {
+        let server = TestServer::start("WRITE-KEY").unwrap();
+        let rudder_analytics = RudderAnalytics::builder("WRITE-KEY", server.url())
+            .tracking_plan(plan())
+            .build()
+            .unwrap();
+
+        rudder_analytics
+            .send(&Message::Track(order(json!(42.0))))
+            .unwrap();
+        let err = rudder_analytics
+            .send(&Message::Track(order(json!("42"))))
+            .err()
+            .unwrap();
+        assert!(matches!(err, AnalyticsError::TrackingPlanViolation(_)));
+
+        // Within a batch, blocked messages are invalid
+        let err = rudder_analytics
+            .send(&Message::Batch(Batch {
+                batch: vec![
+                    BatchMessage::Track(order(json!(42.0))),
+                    BatchMessage::Track(order(json!("42"))),
+                ],
+                ..Default::default()
+            }))
+            .err()
+            .unwrap();
+        match err {
+            AnalyticsError::InvalidBatchItems(items) => assert_eq!(items[0].index, 1),
+            err => panic!("unexpected error: {}", err),
+        }
+        assert_eq!(server.requests().len(), 1);
+    }
+
+    #[test]
+    fn test_block_with_dropped_invalid_batch_messages() {
+        let server = TestServer::start("WRITE-KEY").unwrap();
+        let rudder_analytics = RudderAnalytics::builder("WRITE-KEY", server.url())
+            .tracking_plan(plan())
+            .batch_validation(BatchValidation::DropInvalid)
+            .build()
+            .unwrap();
+
+        let delivery = rudder_analytics
+            .send(&Message::Batch(Batch {
+                batch: vec![
+                    BatchMessage::Track(order(json!("42"))),
+                    BatchMessage::Track(order(json!(42.0))),
+                ],
+                ..Default::default()
+            }))
+            .unwrap();
+        let outcome = delivery.batch.unwrap();
+        assert_eq!(outcome.accepted, 1);
+        assert_eq!(outcome.rejected[0].index, 0);
+        assert_eq!(server.events().len(), 1);
+    }
+
+    #[test]
+    fn test_drop() {
+        let server = TestServer::start("WRITE-KEY").unwrap();
+        let rudder_analytics = RudderAnalytics::builder("WRITE-KEY", server.url())
+            .tracking_plan(plan().on_violation(ViolationAction::Drop))
+            .build()
+            .unwrap();
+
+        let delivery = rudder_analytics
+            .send(&Message::Track(order(json!("42"))))
+            .unwrap();
+        assert_eq!(delivery.attempts, 0);
+        assert!(server.requests().is_empty());
+
+        let delivery = rudder_analytics
+            .send(&Message::Batch(Batch {
+                batch: vec![
+                    BatchMessage::Track(order(json!(42.0))),
+                    BatchMessage::Track(order(json!("42"))),
+                ],
+                ..Default::default()
+            }))
+            .unwrap();
+        let outcome = delivery.batch.unwrap();
+        assert_eq!(outcome.accepted, 1);
+        assert_eq!(outcome.rejected[0].index, 1);
+        assert!(matches!(
+            outcome.rejected[0].errors[..],
+            [AnalyticsError::TrackingPlanViolation(_)]
+        ));
+        assert_eq!(server.events().len(), 1);
+    }
+
+    #[test]
+    fn test_forward() {
+        let server = TestServer::start("WRITE-KEY").unwrap();
+        let rudder_analytics = RudderAnalytics::builder("WRITE-KEY", server.url())
+            .tracking_plan(plan().on_violation(ViolationAction::Forward))
+            .build()
+            .unwrap();
+
+        rudder_analytics
+            .send(&Message::Batch(Batch {
+                batch: vec![
+                    BatchMessage::Track(order(json!(42.0))),
+                    BatchMessage::Track(order(json!("42"))),
+                ],
+                ..Default::default()
+            }))
+            .unwrap();
+
+        let events = server.events();
+        assert!(events[0]["context"].get("violationErrors").is_none());
+        let errors = &events[1]["context"]["violationErrors"];
+        assert_eq!(errors[0]["type"], "Datatype-Mismatch");
+        assert_eq!(errors[0]["meta"]["instancePath"], "/properties/revenue");
+    }
+}
@@ -0,0 +1,111 @@
+use rudderanalytics::client::RudderAnalytics;
+use rudderanalytics::errors::Error as AnalyticsError;
+use rudderanalytics::message::{Message, Track};
+use rudderanalytics::retry::RetryPolicy;
+use rudderanalytics::transport::{RecordingTransport, Response, WriterTransport};
+use std::fs;
+use std::sync::Arc;
+use std::time::Duration;
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn track() -> Message {
+        Message::Track(Track {
+            user_id: Some("foo".to_string()),
+            event: "Foo".to_owned(),
+            ..Default::default()
+        })
+    }
+
+    #[test]
+    fn test_recording_transport() {
+        let transport = Arc::new(RecordingTransport::new());
+        let rudder_analytics = RudderAnalytics::builder("WRITE-KEY", "")
+            .transport(transport.clone())
+            .gzip(true)
+            .build()
+            .unwrap();
+
+        let delivery = rudder_analytics.send(&track()).unwrap();
+        assert_eq!(delivery.attempts, 1);
+
+        let requests = transport.requests();
+        assert_eq!(requests.len(), 1);
+        assert_eq!(requests[0].path, "/v1/track");
+        // base64 of "WRITE-KEY:"
+        assert_eq!(
+            requests[0].headers["authorization"],
+            "Basic V1JJVEUtS0VZOg=="
+        );
+        assert_eq!(requests[0].headers["content-type"], "application/json");
+        assert_eq!(requests[0].headers["content-encoding"], "gzip");
+
+        let payload = requests[0].json().unwrap();
+        assert_eq!(payload["userId"], "foo");
+        assert_eq!(payload["event"], "Foo");
+        assert_eq!(payload["type"], "track");
+    }
+
+    #[test]
+    fn test_recording_transport_scripted_responses() {
+        let transport = Arc::new(RecordingTransport::new());
+        transport.respond_with(Ok(Response::with_status(503, "Unavailable")));
+        transport.respond_with(Ok(Response::with_status(400, "Invalid payload")));
+        let rudder_analytics = RudderAnalytics::builder("WRITE-KEY", "")
+            .transport(transport.clone())
+            .retry_policy(RetryPolicy {
+                base_delay: Duration::from_millis(1),
+                ..Default::default()
+            })
+            .build()
+            .unwrap();
+
+        let err = rudder_analytics.send(&track()).err().unwrap();
+        assert!(matches!(
+            err,
+            AnalyticsError::HttpStatus { status: 400, ref body } if body == "Invalid payload"
+        ));
+        assert_eq!(transport.requests().len(), 2);
+
+        // Scripted responses are used up, requests succeed again
+        assert!(rudder_analytics.send(&track()).is_ok());
+    }
+
+    #[test]
+    fn test_writer_transport() {
+        let path = std::env::temp_dir().join(format!(
+            "rudderanalytics-requests-{}.log",
+            std::process::id()
+        ));
+        let _ = fs::remove_file(&path);
+
+        let rudder_analytics = RudderAnalytics::builder("WRITE-KEY", "")
+            .transport(Arc::new(WriterTransport::file(&path).unwrap()))
+            .gzip(true)
+            .build()
+            .unwrap();
+        rudder_analytics.send(&track()).unwrap();
+        rudder_analytics.send(&track()).unwrap();
+
+        let content = fs::read_to_string(&path).unwrap();
+        let lines: Vec<&str> = content.lines().collect();
+        assert_eq!(lines.len(), 2);
+        let body = lines[0].strip_prefix("POST /v1/track ").unwrap();
+        let payload: serde_json::Value = serde_json::from_str(body).unwrap();
+        assert_eq!(payload["event"], "Foo");
+        fs::remove_file(path).unwrap();
+    }
+
+    #[cfg(feature = "async")]
+    #[test]
+    fn test_async_client_rejects_transport() {
+        let err = RudderAnalytics::builder("WRITE-KEY", "")
+            .transport(Arc::new(RecordingTransport::new()))
+            .build_async()
+            .err()
+            .unwrap();
+        assert!(matches!(err, AnalyticsError::InvalidConfig(_)));
+    }
+}
@@ -0,0 +1,231 @@
+use rudderanalytics::client::{BatchValidation, RudderAnalytics};
+use rudderanalytics::context::{Context, Library};
+use rudderanalytics::errors::Error as AnalyticsError;
+use rudderanalytics::message::{Alias, Batch, BatchMessage, Group, Message, Page, Track};
+use rudderanalytics::queue::QueueConfig;
+use rudderanalytics::test_server::TestServer;
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn reserved_context() -> Option<Context> {
+        Some(Context {
+            library: Some(Library::default()),
+            ..Default::default()
+        })
+    }
+
+    #[test]
+    fn test_valid_messages() {
+        let track = Track {
+            user_id: Some("user-1".to_owned()),
+            event: "Foo".to_owned(),
+            ..Default::default()
+        };
+        assert!(Message::Track(track.clone()).validate().is_ok());
+        assert!(BatchMessage::Track(track).validate().is_ok());
+
+        let alias = Alias {
+            user_id: "user-1".to_owned(),
+            previous_id: "anon-1".to_owned(),
+            ..Default::default()
+        };
+        assert!(Message::Alias(alias).validate().is_ok());
+    }
+
+    #[test]
+    fn test_all_violations_are_returned() {
+        let errors = Message::Track(Track {
+            context: reserved_context(),
+            ..Default::default()
+        })
+        .validate()
+        .err()
+        .unwrap();
+
+        assert_eq!(errors.len(), 3);
+        assert!(matches!(errors[0], AnalyticsError::MissingIdentity));
+        assert!(matches!(errors[1], AnalyticsError::EmptyField("event")));
+        assert!(matches!(errors[2], AnalyticsError::ReservedKeyword(_)));
+    }
+
+    #[test]
+    fn test_empty_names_and_ids() {
+        let errors = BatchMessage::Page(Page {
+            anonymous_id: Some("anon-1".to_owned()),
+            ..Default::default()
+        })
+        .validate()
+        .err()
+        .unwrap();
+        assert!(matches!(errors[..], [AnalyticsError::EmptyField("name")]));
+
+        let errors = Message::Group(Group {
+            user_id: Some("user-1".to_owned()),
+            ..Default::default()
+        })
+        .validate()
+        .err()
+        .unwrap();
+        assert!(matches!(
+            errors[..],
+            [AnalyticsError::EmptyField("group_id")]
+        ));
+    }
+
+    #[test]
+    fn test_alias_to_self() {
+        let errors = Message::Alias(Alias {
+            user_id: "user-1".to_owned(),
+            previous_id: "user-1".to_owned(),
+            ..Default::default()
+        })
+        .validate()
+        .err()
+        .unwrap();
+        assert!(matches!(errors[..], [AnalyticsError::AliasToSelf]));
+    }
+
+    #[test]
+    fn test_batch_checks_its_own_context() {
+        let batch = Message::Batch(Batch {
+            context: reserved_context(),
+            ..Default::default()
+        });
+        let errors = batch.validate().err().unwrap();
+        assert!(matches!(errors[..], [AnalyticsError::ReservedKeyword(_)]));
+    }
+
+    #[test]
+    fn test_empty_ids_count_as_missing() {
+        let errors = Message::Track(Track {
+            user_id: Some(String::new()),
+            anonymous_id: Some(String::new()),
+            event: "Foo".to_owned(),
+            ..Default::default()
+        })
+        .validate()
+        .err()
+        .unwrap();
+        assert!(matches!(errors[..], [AnalyticsError::MissingIdentity]));
+    }
+
+    #[test]
+    fn test_batch_checks_its_messages() {
+        let errors = mixed_batch().validate().err().unwrap();
+        let items = match &errors[..] {
+            [AnalyticsError::InvalidBatchItems(items)] => items,
+            errors => panic!("unexpected errors: {:?}", errors),
+        };
+        let indices: Vec<usize> = items.iter().map(|item| item.index).collect();
+        assert_eq!(indices, vec![1, 3]);
+    }
+
+    fn mixed_batch() -> Message {
+        let track = |user_id: Option<&str>, event: &str| {
+            BatchMessage::Track(Track {
+                user_id: user_id.map(str::to_owned),
+                event: event.to_owned(),
+                ..Default::default()
+            })
+        };
+        Message::Batch(Batch {
+            batch: vec![
+                track(Some("user-1"), "Foo"),
+                track(None, "Bar"),
+                track(Some("user-1"), "Baz"),
+                track(Some("user-1"), ""),
+            ],
+            ..Default::default()
+        })
+    }
+
+    #[test]
+    fn test_invalid_batch_items_fail_the_batch() {
+        let server = TestServer::start("WRITE-KEY").unwrap();
+        let rudder_analytics = RudderAnalytics::load("WRITE-KEY".to_string(), server.url());
+
+        let err = rudder_analytics.send(&mixed_batch()).err().unwrap();
+        let items = match err {
+            AnalyticsError::InvalidBatchItems(items) => items,
+            err => panic!("unexpected error: {}", err),
+        };
+        assert_eq!(items.len(), 2);
+        assert_eq!(items[0].index, 1);
+        assert!(matches!(
+            items[0].errors[..],
+            [AnalyticsError::MissingIdentity]
+        ));
+        assert_eq!(items[1].index, 3);
+        assert!(matches!(
+            items[1].errors[..],
+            [AnalyticsError::EmptyField("event")]
+        ));
+        assert!(server.requests().is_empty());
+    }
+
+    #[test]
+    fn test_invalid_batch_items_can_be_dropped() {
+        let server = TestServer::start("WRITE-KEY").unwrap();
+        let rudder_analytics = RudderAnalytics::builder("WRITE-KEY", server.url())
+            .batch_validation(BatchValidation::DropInvalid)
+            .build()
+            .unwrap();
+
+        let delivery = rudder_analytics.send(&mixed_batch()).unwrap();
+        let outcome = delivery.batch.unwrap();
+        assert_eq!(outcome.accepted, 2);
+        let rejected: Vec<_> = outcome.rejected.iter().map(|item| item.index).collect();
+        assert_eq!(rejected, vec![1, 3]);
+
+        let events: Vec<_> = server.events().iter().map(|e| e["event"].clone()).collect();
+        assert_eq!(events, vec!["Foo", "Baz"]);
+
+        // Nothing is sent if every message is invalid
+        let err = rudder_analytics
+            .send(&Message::Batch(Batch {
+                batch: vec![BatchMessage::Track(Track::default())],
+                ..Default::default()
+            }))
+            .err()
+            .unwrap();
+        assert!(matches!(err, AnalyticsError::InvalidBatchItems(_)));
+        assert_eq!(server.requests().len(), 1);
+    }
+
+    #[test]
+    fn test_queue_rejects_invalid_messages() {
+        let rudder_analytics =
+            RudderAnalytics::load("WRITE-KEY".to_string(), "http://127.0.0.1:9".to_string())
+                .queued(QueueConfig::default())
+                .unwrap();
+
+        let err = rudder_analytics
+            .enqueue(BatchMessage::Track(Track {
+                event: "Foo".to_owned(),
+                ..Default::default()
+            }))
+            .err()
+            .unwrap();
+        assert!(matches!(err, AnalyticsError::MissingIdentity));
+    }
+
+    #[test]
+    fn test_send_rejects_the_first_violation() {
+        // Nothing listens on the discard port; the message must be rejected
+        // before any request is made.
+        let rudder_analytics =
+            RudderAnalytics::load("WRITE-KEY".to_string(), "http://127.0.0.1:9".to_string());
+
+        let err = rudder_analytics
+            .send(&Message::Track(Track {
+                user_id: Some("user-1".to_owned()),
+                context: reserved_context(),
+                ..Default::default()
+            }))
+            .err()
+            .unwrap();
+        assert!(matches!(err, AnalyticsError::EmptyField("event")));
+    }
+}